@@ -1,21 +1,85 @@
+pub mod admin;
+pub mod auth;
+pub mod error;
+pub mod extract;
+pub mod health;
 pub mod openapi;
 pub mod process;
+pub mod request_id;
 pub mod search;
 pub mod types;
+pub mod webhooks;
 
 use std::sync::Arc;
 
-use axum::{routing::get, Router};
+use axum::{
+    middleware,
+    routing::{delete, get, post},
+    Router,
+};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-use crate::api::{openapi::ApiDoc, process::process_user, search::search, types::AppState};
+use crate::api::{
+    admin::{
+        create_api_key, delete_older_than, delete_repository, delete_user, export_corpus,
+        github_cache_stats, github_token_stats, import_corpus, list_api_keys, revoke_api_key,
+    },
+    health::{healthz, metrics, readyz, track_requests},
+    openapi::ApiDoc,
+    process::{process_org, process_repository, process_user},
+    search::search,
+    types::AppState,
+    webhooks::github_webhook,
+};
+use crate::database::ApiScope;
 
 pub fn create_router(state: Arc<AppState>) -> Router {
     let api_doc = ApiDoc::openapi();
+    let search_routes = Router::new().route("/search", get(search));
+    let process_routes = Router::new()
+        .route("/process", get(process_user))
+        .route("/process/org", get(process_org))
+        .route("/process/repo", get(process_repository));
+    let admin_routes = Router::new()
+        .route("/admin/export", get(export_corpus))
+        // Spooled to disk up to `import_max_mb`, rather than buffered in memory
+        .route("/admin/import", post(import_corpus))
+        .route("/admin/users/{login}", delete(delete_user))
+        .route("/admin/repos/{org}/{repo}", delete(delete_repository))
+        .route("/admin/commits", delete(delete_older_than))
+        .route("/admin/github-cache", get(github_cache_stats))
+        .route("/admin/github-tokens", get(github_token_stats))
+        .route("/admin/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/admin/api-keys/{id}", delete(revoke_api_key));
+
     Router::new()
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", api_doc))
-        .route("/search", get(search))
-        .route("/process", get(process_user))
+        .merge(scoped(&state, ApiScope::Search, search_routes))
+        .merge(scoped(&state, ApiScope::Process, process_routes))
+        .merge(scoped(&state, ApiScope::Admin, admin_routes))
+        // Authenticated by its signature instead of an API key
+        .route("/webhooks/github", post(github_webhook))
+        // Probes and scrapers don't have API keys
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            track_requests,
+        ))
+        .layer(middleware::from_fn(request_id::middleware))
         .with_state(state)
 }
+
+/// Requires an API key with `scope` for every route in `routes`
+fn scoped(
+    state: &Arc<AppState>,
+    scope: ApiScope,
+    routes: Router<Arc<AppState>>,
+) -> Router<Arc<AppState>> {
+    routes.route_layer(middleware::from_fn_with_state(
+        (state.clone(), scope),
+        auth::authorize,
+    ))
+}
@@ -0,0 +1,386 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use chrono::Utc;
+use eyre::{eyre, WrapErr};
+use futures::stream;
+use std::{io, sync::Arc};
+use tokio::sync::mpsc;
+use tracing::{error, info, instrument};
+
+use crate::{
+    api::{
+        auth,
+        error::{AppError, AppResult, ProblemDetails},
+        extract::{Json, Path, Query},
+        types::{
+            ApiKeyInfo, AppState, CreateApiKeyRequest, CreatedApiKey, DeleteOlderThanQuery,
+            DeleteRepositoryQuery, ExportQuery, ImportQuery, ImportResponse,
+        },
+    },
+    corpus::{self, CorpusFormat, ExportOptions, ImportTooLarge},
+    database::{ApiKey, CommitFilter, DeletionReport},
+    forge::ForgeKind,
+    github::{CacheStats, TokenStats},
+    retention::{self, RetentionPolicy},
+};
+
+/// Export processed commits as JSONL or Parquet
+#[utoipa::path(
+    get,
+    path = "/admin/export",
+    params(
+        ("format" = Option<CorpusFormat>, Query, description = "Output format (defaults to jsonl)"),
+        ("org" = Option<String>, Query, description = "Only export commits from this organization or user"),
+        ("repo" = Option<String>, Query, description = "Only export commits from repositories with this name"),
+        ("include_patches" = Option<bool>, Query, description = "Include commit patches (defaults to true)"),
+        ("include_embeddings" = Option<bool>, Query, description = "Include embeddings (defaults to true)")
+    ),
+    responses(
+        (status = 200, description = "Exported commits as application/x-ndjson or application/vnd.apache.parquet", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 400, description = "Missing or malformed query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn export_corpus(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ExportQuery>,
+) -> AppResult<impl IntoResponse> {
+    let options = ExportOptions {
+        filter: CommitFilter {
+            org: query.org,
+            repo: query.repo,
+            ..CommitFilter::default()
+        },
+        include_patches: query.include_patches,
+        include_embeddings: query.include_embeddings,
+    };
+
+    // Encoded on a task of its own and sent as it's produced, so the corpus is never
+    // held in memory whole. Failures after the first chunk can only abort the body.
+    let (chunks, receiver) = mpsc::channel::<io::Result<Bytes>>(4);
+    let format = query.format;
+    tokio::spawn(async move {
+        let export = corpus::export_commits(&state.db, format, &options, |chunk| {
+            let chunks = chunks.clone();
+            async move {
+                chunks
+                    .send(Ok(Bytes::from(chunk)))
+                    .await
+                    .map_err(|_| eyre!("The client went away"))
+            }
+        })
+        .await;
+        match export {
+            Ok(count) => info!("Streamed {count} commits"),
+            Err(e) => {
+                error!("Failed to export commits: {e:?}");
+                let _ = chunks.send(Err(io::Error::other(format!("{e:#}")))).await;
+            }
+        }
+    });
+    let body = Body::from_stream(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    }));
+
+    let disposition = format!(
+        "attachment; filename=\"commits.{}\"",
+        query.format.extension()
+    );
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                query.format.content_type().to_string(),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    ))
+}
+
+/// Import commits from a JSONL or Parquet export
+#[utoipa::path(
+    post,
+    path = "/admin/import",
+    params(
+        ("format" = Option<CorpusFormat>, Query, description = "Input format (defaults to jsonl)")
+    ),
+    request_body(content = Vec<u8>, description = "Exported commits, at most `import_max_mb`", content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Successfully imported commits", body = ImportResponse),
+        (status = 400, description = "Missing or malformed query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 413, description = "The export is larger than `import_max_mb`", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+#[instrument(skip(state, body))]
+pub async fn import_corpus(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ImportQuery>,
+    body: Body,
+) -> AppResult<Json<ImportResponse>> {
+    info!("Importing {:?}", query.format);
+    let report = corpus::import_stream(
+        &state.db,
+        Some(&state.machine_learning),
+        query.format,
+        body.into_data_stream(),
+        state.config.import_max_bytes,
+    )
+    .await
+    .map_err(|report| match report.downcast_ref::<ImportTooLarge>() {
+        Some(_) => AppError::PayloadTooLarge(report),
+        None => report.wrap_err("Failed to import commits").into(),
+    })?;
+
+    Ok(Json(ImportResponse {
+        imported: report.imported,
+        skipped: report.skipped,
+    }))
+}
+
+/// Delete all data ingested for a GitHub user
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{login}",
+    params(
+        ("login" = String, Path, description = "GitHub login whose data should be removed")
+    ),
+    responses(
+        (status = 200, description = "Data deleted", body = DeletionReport),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    Path(login): Path<String>,
+) -> AppResult<Json<DeletionReport>> {
+    let report = retention::delete_user(&state.db, &login, "api")
+        .await
+        .wrap_err_with(|| format!("Failed to delete data for user {login}"))?;
+    Ok(Json(report))
+}
+
+/// Delete all data ingested from a repository
+#[utoipa::path(
+    delete,
+    path = "/admin/repos/{org}/{repo}",
+    params(
+        ("org" = String, Path, description = "Repository owner"),
+        ("repo" = String, Path, description = "Repository name"),
+        ("forge" = Option<ForgeKind>, Query, description = "Forge the repository is on; defaults to GitHub")
+    ),
+    responses(
+        (status = 200, description = "Data deleted", body = DeletionReport),
+        (status = 400, description = "Missing or malformed query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn delete_repository(
+    State(state): State<Arc<AppState>>,
+    Path((org, repo)): Path<(String, String)>,
+    Query(query): Query<DeleteRepositoryQuery>,
+) -> AppResult<Json<DeletionReport>> {
+    let report = retention::delete_repository(&state.db, query.forge, &org, &repo, "api")
+        .await
+        .wrap_err_with(|| format!("Failed to delete data for {org}/{repo}"))?;
+    Ok(Json(report))
+}
+
+/// Delete commits older than a number of days
+#[utoipa::path(
+    delete,
+    path = "/admin/commits",
+    params(
+        ("older_than_days" = u32, Query, description = "Delete commits committed more than this many days ago"),
+        ("org" = Option<String>, Query, description = "Only delete commits from this organization or user"),
+        ("repo" = Option<String>, Query, description = "Only delete commits from repositories with this name")
+    ),
+    responses(
+        (status = 200, description = "Data deleted", body = DeletionReport),
+        (status = 400, description = "Missing or malformed query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn delete_older_than(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DeleteOlderThanQuery>,
+) -> AppResult<Json<DeletionReport>> {
+    let policy = RetentionPolicy {
+        org: query.org,
+        repo: query.repo,
+        max_age_days: query.older_than_days,
+    };
+    let report = retention::delete_older_than(&state.db, &policy, "api")
+        .await
+        .wrap_err("Failed to delete old commits")?;
+    Ok(Json(report))
+}
+
+/// GitHub response cache statistics
+#[utoipa::path(
+    get,
+    path = "/admin/github-cache",
+    responses(
+        (status = 200, description = "Cache statistics since the server started", body = CacheStats),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+pub async fn github_cache_stats(State(state): State<Arc<AppState>>) -> Json<CacheStats> {
+    Json(state.github_client.cache_stats())
+}
+
+/// Usage of each pooled GitHub token
+#[utoipa::path(
+    get,
+    path = "/admin/github-tokens",
+    responses(
+        (status = 200, description = "Requests and last reported rate limits per token, identified by position rather than value", body = Vec<TokenStats>),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+pub async fn github_token_stats(State(state): State<Arc<AppState>>) -> Json<Vec<TokenStats>> {
+    Json(state.github_client.token_stats())
+}
+
+/// Create an API key
+#[utoipa::path(
+    post,
+    path = "/admin/api-keys",
+    request_body = CreateApiKeyRequest,
+    responses(
+        (status = 201, description = "The key, which is only ever shown here", body = CreatedApiKey),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 400, description = "Malformed request body, no scopes or a zero rate limit", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> AppResult<(StatusCode, Json<CreatedApiKey>)> {
+    if request.scopes.is_empty() {
+        return Err(AppError::BadRequest(eyre!(
+            "An API key needs at least one scope"
+        )));
+    }
+    let requests_per_minute = request.requests_per_minute.unwrap_or(60);
+    if requests_per_minute == 0 {
+        return Err(AppError::BadRequest(eyre!(
+            "requests_per_minute must be greater than zero"
+        )));
+    }
+
+    let (key, hash) = auth::generate_key();
+    let api_key = ApiKey {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: request.name,
+        prefix: key.chars().take(12).collect(),
+        hash,
+        scopes: request.scopes,
+        requests_per_minute,
+        daily_quota: request.daily_quota,
+        created_at: Utc::now(),
+        revoked_at: None,
+    };
+    state.db.insert_api_key(api_key.clone()).await?;
+    info!("Created API key {} ({})", api_key.name, api_key.id);
+
+    let info = ApiKeyInfo::from(api_key);
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { key, info })))
+}
+
+/// List API keys, including revoked ones
+#[utoipa::path(
+    get,
+    path = "/admin/api-keys",
+    responses(
+        (status = 200, description = "Every key, oldest first", body = Vec<ApiKeyInfo>),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+pub async fn list_api_keys(State(state): State<Arc<AppState>>) -> AppResult<Json<Vec<ApiKeyInfo>>> {
+    let keys = state.db.list_api_keys().await?;
+    Ok(Json(keys.into_iter().map(ApiKeyInfo::from).collect()))
+}
+
+/// Revoke an API key
+#[utoipa::path(
+    delete,
+    path = "/admin/api-keys/{id}",
+    params(
+        ("id" = String, Path, description = "ID of the key to revoke")
+    ),
+    responses(
+        (status = 200, description = "The revoked key", body = ApiKeyInfo),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the admin scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "No key with this ID", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["admin"])),
+    tag = "admin"
+)]
+#[instrument(skip(state))]
+pub async fn revoke_api_key(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> AppResult<Json<ApiKeyInfo>> {
+    let Some(key) = state.db.revoke_api_key(&id).await? else {
+        return Err(AppError::NotFound(eyre!("API key {id} not found")));
+    };
+    info!("Revoked API key {} ({id})", key.name);
+    Ok(Json(ApiKeyInfo::from(key)))
+}
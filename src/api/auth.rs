@@ -0,0 +1,195 @@
+//! API key authentication. Keys are sent as `Authorization: Bearer <key>` and looked
+//! up by their SHA-256, so the database never holds a usable key. Each route group
+//! requires a scope, and each key has a per-minute rate limit, enforced in memory,
+//! and an optional daily quota, counted in the database so it survives restarts.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Days, Timelike, Utc};
+use eyre::eyre;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        error::{AppError, AppResult},
+        types::AppState,
+    },
+    database::ApiScope,
+};
+
+/// Start of every generated key, so leaked keys are easy to scan for
+const KEY_PREFIX: &str = "grk_";
+
+/// Requests each key made in the current minute
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    windows: Mutex<HashMap<String, (i64, u32)>>,
+}
+
+impl RateLimiter {
+    /// Counts a request by `key_id`, returning the seconds until it may retry if it
+    /// has already made `limit` requests this minute
+    fn check(&self, key_id: &str, limit: u32, now: DateTime<Utc>) -> Result<(), u64> {
+        let minute = now.timestamp().div_euclid(60);
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget the windows of keys that have gone quiet
+        windows.retain(|_, (window, _)| *window == minute);
+        let (_, requests) = windows.entry(key_id.to_string()).or_insert((minute, 0));
+        if *requests >= limit {
+            return Err(60 - u64::from(now.second()));
+        }
+        *requests += 1;
+        Ok(())
+    }
+}
+
+/// Generates a new key, returning it and the hash it's stored by
+pub fn generate_key() -> (String, String) {
+    let key = format!(
+        "{KEY_PREFIX}{}{}",
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let hash = hash_key(&key);
+    (key, hash)
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Rejects requests without a key that grants `scope` or that are over its limits
+pub async fn authorize(
+    State((state, scope)): State<(Arc<AppState>, ApiScope)>,
+    request: Request,
+    next: Next,
+) -> AppResult<Response> {
+    let Some(presented) = bearer_token(request.headers()) else {
+        return Err(AppError::Unauthorized(eyre!(
+            "Missing API key; send it as Authorization: Bearer <key>"
+        )));
+    };
+    let hash = hash_key(presented);
+
+    // The configured admin key isn't stored, so it works before any key exists
+    if let Some(admin_key) = &state.config.api_admin_key {
+        if hash_key(admin_key) == hash {
+            debug!("Authorized with the configured admin key");
+            return Ok(next.run(request).await);
+        }
+    }
+
+    let Some(key) = state
+        .db
+        .find_api_key(&hash)
+        .await?
+        .filter(|key| key.revoked_at.is_none())
+    else {
+        return Err(AppError::Unauthorized(eyre!("Invalid or revoked API key")));
+    };
+    if !key.allows(scope) {
+        return Err(AppError::Forbidden(eyre!(
+            "API key {} doesn't have the {} scope",
+            key.name,
+            scope.as_str()
+        )));
+    }
+
+    let now = Utc::now();
+    if let Err(retry_after) = state
+        .rate_limiter
+        .check(&key.id, key.requests_per_minute, now)
+    {
+        return Err(AppError::RateLimited {
+            report: eyre!(
+                "API key {} is limited to {} requests per minute",
+                key.name,
+                key.requests_per_minute
+            ),
+            retry_after: Some(retry_after),
+        });
+    }
+    if let Some(quota) = key.daily_quota {
+        let day = now.format("%Y-%m-%d").to_string();
+        let used = state.db.count_api_key_request(&key.id, &day).await?;
+        if used > quota {
+            return Err(AppError::RateLimited {
+                report: eyre!("API key {} has used its daily quota of {quota}", key.name),
+                retry_after: Some(seconds_until_tomorrow(now)),
+            });
+        }
+    }
+
+    debug!("Authorized with API key {}", key.name);
+    Ok(next.run(request).await)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Seconds until the next UTC midnight, when daily quotas reset
+fn seconds_until_tomorrow(now: DateTime<Utc>) -> u64 {
+    let tomorrow = now
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|midnight| midnight.and_utc());
+    tomorrow.map_or(0, |midnight| (midnight - now).num_seconds().max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_rate_limiter_windows() {
+        let limiter = RateLimiter::default();
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 45).unwrap();
+        assert_eq!(limiter.check("a", 2, start), Ok(()));
+        assert_eq!(limiter.check("a", 2, start), Ok(()));
+        assert_eq!(limiter.check("a", 2, start), Err(15));
+        // Keys are limited separately
+        assert_eq!(limiter.check("b", 2, start), Ok(()));
+        // and the count starts over each minute
+        let next_minute = start + chrono::TimeDelta::seconds(20);
+        assert_eq!(limiter.check("a", 2, next_minute), Ok(()));
+    }
+
+    #[test]
+    fn test_keys() {
+        let (key, hash) = generate_key();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(hash, hash_key(&key));
+        assert_ne!(generate_key().0, key);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {key}").parse().unwrap(),
+        );
+        assert_eq!(bearer_token(&headers), Some(key.as_str()));
+        headers.insert(header::AUTHORIZATION, "Basic abc".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 23, 59, 0).unwrap();
+        assert_eq!(seconds_until_tomorrow(now), 60);
+    }
+}
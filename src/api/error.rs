@@ -0,0 +1,261 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use eyre::Report;
+use serde::Serialize;
+use tracing::{error, warn};
+use utoipa::ToSchema;
+
+use crate::{api::request_id, forge::ForgeError, ml::ModelError};
+
+/// Error type for API operations, each variant answered with its own status
+#[derive(Debug)]
+pub enum AppError {
+    /// Invalid input, reported back to the caller as-is
+    BadRequest(Report),
+    /// Credentials or a signature that couldn't be verified
+    Unauthorized(Report),
+    /// Valid credentials that don't allow the request
+    Forbidden(Report),
+    /// An unknown user, organization, repository or commit
+    NotFound(Report),
+    /// A request body larger than the endpoint accepts
+    PayloadTooLarge(Report),
+    /// Every credential for an upstream API is rate limited; retry after the
+    /// given number of seconds, if known
+    RateLimited {
+        report: Report,
+        retry_after: Option<u64>,
+    },
+    /// GitHub, another forge or a model API failed
+    Upstream(Report),
+    /// Too busy to take the request now; it can be retried later
+    Unavailable(Report),
+    Internal(Report),
+}
+
+/// An [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807) problem, the body of every
+/// error response
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ProblemDetails {
+    /// Always `about:blank`: the status code says what went wrong
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// Reason phrase of the status code
+    pub title: String,
+    pub status: u16,
+    /// What went wrong with this request; omitted for internal errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// ID of the request, also sent in the `X-Request-Id` header and logged with
+    /// the error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn report(&self) -> &Report {
+        match self {
+            Self::BadRequest(report)
+            | Self::Unauthorized(report)
+            | Self::Forbidden(report)
+            | Self::NotFound(report)
+            | Self::PayloadTooLarge(report)
+            | Self::RateLimited { report, .. }
+            | Self::Upstream(report)
+            | Self::Unavailable(report)
+            | Self::Internal(report) => report,
+        }
+    }
+
+    fn problem(&self) -> ProblemDetails {
+        let status = self.status();
+        let detail = match self {
+            Self::Internal(_) => None,
+            _ => Some(self.report().to_string()),
+        };
+        ProblemDetails {
+            problem_type: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail,
+            request_id: request_id::current(),
+        }
+    }
+}
+
+/// Classifies errors by the [`ForgeError`] or [`ModelError`] they were caused by,
+/// anything else being internal
+impl From<Report> for AppError {
+    fn from(report: Report) -> Self {
+        match report.downcast_ref::<ModelError>() {
+            Some(&ModelError::RateLimited { retry_after, .. }) => {
+                return Self::RateLimited {
+                    report,
+                    retry_after,
+                }
+            }
+            Some(ModelError::Upstream { .. }) => return Self::Upstream(report),
+            None => {}
+        }
+        match report.downcast_ref::<ForgeError>() {
+            Some(ForgeError::NotFound(_)) => Self::NotFound(report),
+            Some(ForgeError::RateLimited { reset_at, .. }) => {
+                let retry_after = reset_at
+                    .map(|reset_at| (reset_at - Utc::now()).num_seconds().max(0) as u64 + 1);
+                Self::RateLimited {
+                    report,
+                    retry_after,
+                }
+            }
+            Some(ForgeError::Upstream { .. }) => Self::Upstream(report),
+            None => Self::Internal(report),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let problem = self.problem();
+        match &self {
+            Self::Internal(report) => error!("Internal error: {report:?}"),
+            Self::Upstream(report) => error!("Upstream error: {report:?}"),
+            report => warn!("Rejected request: {}", report.report()),
+        }
+
+        let mut response = (self.status(), axum::Json(problem)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let Self::RateLimited {
+            retry_after: Some(seconds),
+            ..
+        } = self
+        {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+pub type AppResult<T> = Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use eyre::{eyre, WrapErr};
+
+    async fn problem(error: AppError) -> (StatusCode, Response) {
+        let response =
+            request_id::scope("test-request".to_string(), async { error.into_response() }).await;
+        (response.status(), response)
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forge_errors_are_classified() {
+        let not_found: Report = ForgeError::NotFound("GitHub user ghost".to_string()).into();
+        let (status, response) =
+            problem(not_found.wrap_err("Failed to process ghost").into()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(
+            body(response).await,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Failed to process ghost",
+                "request_id": "test-request",
+            })
+        );
+
+        let rate_limited: Report = ForgeError::RateLimited {
+            resource: "graphql".to_string(),
+            reset_at: Some(Utc::now() + TimeDelta::seconds(30)),
+        }
+        .into();
+        let (status, response) = problem(rate_limited.into()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((30..=31).contains(&retry_after), "{retry_after}");
+
+        let upstream: Report = ForgeError::Upstream {
+            status: 502,
+            message: "GitHub API error".to_string(),
+        }
+        .into();
+        let (status, _) = problem(upstream.into()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_model_errors_are_classified() {
+        let rate_limited: Report = ModelError::RateLimited {
+            service: "Gemini API".to_string(),
+            retry_after: Some(20),
+        }
+        .into();
+        let (status, response) =
+            problem(rate_limited.wrap_err("Failed to generate summary").into()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "20");
+
+        let upstream: Report = ModelError::Upstream {
+            service: "OpenAI".to_string(),
+            status: 500,
+            message: "server error".to_string(),
+        }
+        .into();
+        let (status, response) = problem(upstream.into()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body(response).await["detail"],
+            "OpenAI returned 500: server error"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_internal_errors_hide_details() {
+        let report = Err::<(), _>(eyre!("connection refused"))
+            .wrap_err("Failed to query MongoDB")
+            .unwrap_err();
+        let (status, response) = problem(report.into()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body(response).await;
+        assert_eq!(body["title"], "Internal Server Error");
+        assert!(body.get("detail").is_none(), "{body}");
+    }
+}
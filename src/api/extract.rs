@@ -0,0 +1,132 @@
+//! Axum's `Query`, `Path` and `Json` extractors, with their rejections answered as
+//! [`AppError::BadRequest`] problems instead of axum's plain-text responses.
+
+use axum::{
+    extract::{FromRequest, FromRequestParts, Request},
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use eyre::eyre;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::api::error::AppError;
+
+/// Deserializes the query string
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Query<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) = axum::extract::Query::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| AppError::BadRequest(eyre!(rejection.body_text())))?;
+        Ok(Self(value))
+    }
+}
+
+/// Deserializes the path parameters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Path<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) = axum::extract::Path::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| AppError::BadRequest(eyre!(rejection.body_text())))?;
+        Ok(Self(value))
+    }
+}
+
+/// Deserializes a JSON request body, and serializes a JSON response
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json<T>(pub T);
+
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::from_request(request, state)
+            .await
+            .map_err(|rejection| AppError::BadRequest(eyre!(rejection.body_text())))?;
+        Ok(Self(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{header, StatusCode},
+    };
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct Page {
+        page: u32,
+    }
+
+    async fn problem(rejection: AppError) -> serde_json::Value {
+        let response = rejection.into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_problems() {
+        let request = Request::get("/search?page=first")
+            .body(Body::empty())
+            .unwrap();
+        let (mut parts, _) = request.into_parts();
+        let rejection = Query::<Page>::from_request_parts(&mut parts, &())
+            .await
+            .unwrap_err();
+        let body = problem(rejection).await;
+        assert!(body["detail"]
+            .as_str()
+            .is_some_and(|detail| detail.contains("page")));
+
+        let request = Request::post("/admin/api-keys")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"page\":"))
+            .unwrap();
+        let rejection = Json::<Page>::from_request(request, &()).await.unwrap_err();
+        assert_eq!(problem(rejection).await["status"], 400);
+
+        let request = Request::get("/search?page=2").body(Body::empty()).unwrap();
+        let (mut parts, _) = request.into_parts();
+        let Query(page) = Query::<Page>::from_request_parts(&mut parts, &())
+            .await
+            .unwrap();
+        assert_eq!(page.page, 2);
+    }
+}
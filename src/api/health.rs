@@ -0,0 +1,225 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::future::{join_all, BoxFuture};
+use opentelemetry::KeyValue;
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    api::{
+        error::{AppResult, ProblemDetails},
+        extract::{Json, Query},
+        types::AppState,
+    },
+    telemetry::PrometheusReader,
+};
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthResponse {
+    pub status: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ReadinessQuery {
+    /// Also check that GitHub and the model APIs accept our credentials. The result
+    /// is reused for 30 seconds, so frequent probes don't use up API quota.
+    #[serde(default)]
+    pub providers: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ReadinessCheck {
+    /// `mongodb`, `github` or `models`
+    pub name: String,
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How long the result of the provider checks is reused
+const PROVIDER_CHECK_TTL: Duration = Duration::from_secs(30);
+
+/// The last result of the `/readyz` provider checks, which call paid APIs
+#[derive(Debug, Default)]
+pub struct ProviderChecks {
+    last: Mutex<Option<(Instant, Vec<ReadinessCheck>)>>,
+}
+
+impl ProviderChecks {
+    /// The last result while it's fresh, otherwise the result of `run`. Concurrent
+    /// probes wait for the same run instead of each calling the providers.
+    async fn get<F>(&self, run: impl FnOnce() -> F) -> Vec<ReadinessCheck>
+    where
+        F: Future<Output = Vec<ReadinessCheck>>,
+    {
+        let mut last = self.last.lock().await;
+        if let Some((_, checks)) = last
+            .as_ref()
+            .filter(|(checked_at, _)| checked_at.elapsed() < PROVIDER_CHECK_TTL)
+        {
+            return checks.clone();
+        }
+        let checks = run().await;
+        *last = Some((Instant::now(), checks.clone()));
+        checks
+    }
+}
+
+/// Liveness: the server is up and answering requests
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "The server is running", body = HealthResponse)
+    ),
+    tag = "health"
+)]
+pub async fn healthz() -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok".to_string(),
+    })
+}
+
+/// Readiness: MongoDB, and optionally GitHub and the model APIs, are reachable
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    params(ReadinessQuery),
+    responses(
+        (status = 200, description = "Every check passed", body = ReadinessResponse),
+        (status = 400, description = "Malformed query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "A check failed", body = ReadinessResponse)
+    ),
+    tag = "health"
+)]
+pub async fn readyz(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ReadinessQuery>,
+) -> (StatusCode, Json<ReadinessResponse>) {
+    let providers = async {
+        if !query.providers {
+            return Vec::new();
+        }
+        state
+            .provider_checks
+            .get(|| {
+                join_all([
+                    run_check("github", Box::pin(state.github_client.check())),
+                    run_check("models", Box::pin(state.machine_learning.check())),
+                ])
+            })
+            .await
+    };
+    let (mongodb, providers) =
+        tokio::join!(run_check("mongodb", Box::pin(state.db.ping())), providers);
+    let checks: Vec<ReadinessCheck> = std::iter::once(mongodb).chain(providers).collect();
+
+    let ready = checks.iter().all(|check| check.ok);
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(ReadinessResponse { ready, checks }))
+}
+
+async fn run_check(name: &str, check: BoxFuture<'_, eyre::Result<()>>) -> ReadinessCheck {
+    let start = Instant::now();
+    let result = check.await;
+    if let Err(e) = &result {
+        warn!("Readiness check {name} failed: {e:?}");
+    }
+    ReadinessCheck {
+        name: name.to_string(),
+        ok: result.is_ok(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+/// Metrics in the Prometheus text format
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Request latencies, commits processed, model tokens and cost, embedding cache lookups, GitHub rate limits and webhook queue depth", body = String, content_type = "text/plain; version=0.0.4"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    tag = "health"
+)]
+pub async fn metrics() -> AppResult<impl IntoResponse> {
+    let text = PrometheusReader::render_global()?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text))
+}
+
+/// Records how long each request took, by route and status
+pub async fn track_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(String::new, |path| path.as_str().to_string());
+
+    let response = next.run(request).await;
+    let attributes = [
+        KeyValue::new("http.request.method", method),
+        KeyValue::new("http.route", route),
+        KeyValue::new(
+            "http.response.status_code",
+            i64::from(response.status().as_u16()),
+        ),
+    ];
+    state
+        .metrics
+        .request_duration
+        .record(start.elapsed().as_secs_f64(), &attributes);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_provider_checks_are_reused() {
+        let checks = ProviderChecks::default();
+        let runs = AtomicUsize::new(0);
+        let run = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            vec![run_check("github", Box::pin(async { Ok(()) })).await]
+        };
+
+        assert!(checks.get(run).await[0].ok);
+        assert!(checks.get(run).await[0].ok);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Checked again once the result is stale
+        let stale = Instant::now() - PROVIDER_CHECK_TTL;
+        checks.last.lock().await.as_mut().unwrap().0 = stale;
+        checks.get(run).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,25 +1,91 @@
-use utoipa::OpenApi;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
 
-use crate::api::types::{ProcessUserQuery, ProcessUserResponse, SearchQuery, SearchResult};
+use crate::{
+    api::{
+        error::ProblemDetails,
+        health::{HealthResponse, ReadinessCheck, ReadinessResponse},
+        types::{
+            ApiKeyInfo, CreateApiKeyRequest, CreatedApiKey, DeleteOlderThanQuery,
+            DeleteRepositoryQuery, ExportQuery, ImportQuery, ImportResponse, ProcessOrgQuery,
+            ProcessRepositoriesResponse, ProcessRepositoryQuery, ProcessUserQuery,
+            ProcessUserResponse, SearchQuery, SearchResult,
+        },
+        webhooks::{WebhookResponse, WebhookStatus},
+    },
+    corpus::CorpusFormat,
+    database::{ActivityDocument, ApiScope, DeletionReport, DiffStats, DocumentKind},
+    forge::ForgeKind,
+    github::{CacheStats, RateLimitStats, TokenStats},
+};
 
 /// API Documentation
 #[derive(OpenApi)]
 #[openapi(
     paths(
         crate::api::search::search,
-        crate::api::process::process_user
+        crate::api::process::process_user,
+        crate::api::process::process_org,
+        crate::api::process::process_repository,
+        crate::api::admin::export_corpus,
+        crate::api::admin::import_corpus,
+        crate::api::admin::delete_user,
+        crate::api::admin::delete_repository,
+        crate::api::admin::delete_older_than,
+        crate::api::admin::github_cache_stats,
+        crate::api::admin::github_token_stats,
+        crate::api::admin::create_api_key,
+        crate::api::admin::list_api_keys,
+        crate::api::admin::revoke_api_key,
+        crate::api::webhooks::github_webhook,
+        crate::api::health::healthz,
+        crate::api::health::readyz,
+        crate::api::health::metrics
     ),
     components(
         schemas(
             SearchQuery,
             SearchResult,
+            DocumentKind,
+            ActivityDocument,
+            DiffStats,
             ProcessUserQuery,
-            ProcessUserResponse
+            ProcessUserResponse,
+            ForgeKind,
+            ProcessOrgQuery,
+            ProcessRepositoryQuery,
+            ProcessRepositoriesResponse,
+            ExportQuery,
+            ImportQuery,
+            ImportResponse,
+            CorpusFormat,
+            DeleteOlderThanQuery,
+            DeleteRepositoryQuery,
+            DeletionReport,
+            CacheStats,
+            TokenStats,
+            RateLimitStats,
+            WebhookResponse,
+            WebhookStatus,
+            ProblemDetails,
+            ApiScope,
+            CreateApiKeyRequest,
+            ApiKeyInfo,
+            CreatedApiKey,
+            HealthResponse,
+            ReadinessResponse,
+            ReadinessCheck
         )
     ),
+    modifiers(&SecurityAddon),
     tags(
         (name = "search", description = "Search API endpoints"),
-        (name = "process", description = "Process GitHub user repositories")
+        (name = "process", description = "Process users, organizations and repositories"),
+        (name = "admin", description = "Corpus administration and data deletion"),
+        (name = "webhooks", description = "Ingestion triggered by GitHub webhooks"),
+        (name = "health", description = "Liveness, readiness and metrics for orchestrators and scrapers")
     ),
     info(
         title = "GitHub Research API",
@@ -28,3 +94,24 @@ use crate::api::types::{ProcessUserQuery, ProcessUserResponse, SearchQuery, Sear
     )
 )]
 pub struct ApiDoc;
+
+/// Describes API key authentication, which utoipa can't derive
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "An API key created with POST /admin/api-keys, or API_ADMIN_KEY. \
+                        Each operation lists the scope it needs.",
+                    ))
+                    .build(),
+            ),
+        );
+    }
+}
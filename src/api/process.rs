@@ -1,53 +1,100 @@
-use axum::{
-    extract::Query,
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    Json,
-};
-use eyre::{Report, WrapErr};
+use axum::extract::State;
+use chrono::{DateTime, Utc};
+use eyre::WrapErr;
+use opentelemetry::KeyValue;
 use serde_json;
-use std::sync::Arc;
-use tracing::{debug, error, info, instrument, warn};
+use std::{collections::BTreeSet, path::Path, sync::Arc};
+use tokio::sync::OnceCell;
+use tracing::{debug, info, instrument, warn};
 
 use crate::{
-    api::types::{AppState, ProcessUserQuery, ProcessUserResponse},
-    database::CommitDocument,
+    api::{
+        error::{AppError, AppResult, ProblemDetails},
+        extract::{Json, Query},
+        types::{
+            AppState, ProcessOrgQuery, ProcessRepositoriesResponse, ProcessRepositoryQuery,
+            ProcessUserQuery, ProcessUserResponse,
+        },
+    },
+    database::{
+        ActivityDocument, CommitContentDocument, CommitDocument, CommitSummary, DiffStats,
+        DocumentKind, ReadmeDocument, ReadmeSummary,
+    },
+    forge::{Forge, ForgeError, ForgeKind},
+    git::LocalRepository,
+    github::{
+        CommitInfo, ContributionPeriod, IssueActivity, PullRequestInfo, Repository,
+        RepositoryFilter, ReviewCommentInfo, ReviewInfo,
+    },
+    ml::{EMBEDDING_MODEL, SUMMARY_MODEL},
 };
 
 /// Maximum size of a patch in bytes that we'll process
 const MAX_PATCH_SIZE_BYTES: usize = 50_000;
 
-/// Error type for API operations
-#[derive(Debug)]
-pub struct AppError(Report);
+/// Whether `error` says something doesn't exist on the forge. Only the subject of a
+/// request answers it with a 404; a repository or commit that has gone missing since
+/// it was listed is skipped instead.
+fn is_not_found(error: &eyre::Report) -> bool {
+    matches!(
+        error.downcast_ref::<ForgeError>(),
+        Some(ForgeError::NotFound(_))
+    )
+}
 
-impl From<Report> for AppError {
-    fn from(err: Report) -> Self {
-        Self(err)
-    }
+/// Told how far [`ingest_user`] has got, e.g. to draw a progress bar
+pub trait IngestProgress: Sync {
+    /// `expected` commits were found across `repositories` repositories
+    fn started(&self, _repositories: usize, _expected: u64) {}
+    /// Moved on to the commits in `repository`
+    fn repository(&self, _repository: &str) {}
+    /// Stored another commit
+    fn commit_stored(&self) {}
+    /// Moved on to pull requests, reviews, issues and discussions
+    fn activities(&self) {}
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        error!("Internal error: {:?}", self.0);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
-    }
+/// Reports nothing, for API requests
+impl IngestProgress for () {}
+
+/// Where commit patches and the README of the repository being ingested come from
+#[derive(Clone, Copy)]
+enum CommitSource<'a> {
+    Forge(&'a dyn Forge),
+    Local(&'a Arc<LocalRepository>),
 }
 
-type AppResult<T> = Result<T, AppError>;
+impl CommitSource<'_> {
+    fn kind(self) -> ForgeKind {
+        match self {
+            Self::Forge(forge) => forge.kind(),
+            Self::Local(_) => ForgeKind::Local,
+        }
+    }
+}
 
-/// Process a GitHub user's repositories and commits
+/// Process a user's repositories and commits
 #[utoipa::path(
     get,
     path = "/process",
     params(
-        ("user" = String, Query, description = "GitHub username to process")
+        ("user" = String, Query, description = "Username to process"),
+        ("forge" = Option<ForgeKind>, Query, description = "Forge the user is on; defaults to GitHub"),
+        ("from" = Option<String>, Query, description = "Start of the contribution range (RFC 3339); defaults to a year before `to`"),
+        ("to" = Option<String>, Query, description = "End of the contribution range (RFC 3339); defaults to now"),
+        ("full_history" = Option<bool>, Query, description = "Walk every year the user has contributions in")
     ),
     responses(
         (status = 200, description = "Successfully processed user's repositories", body = ProcessUserResponse),
-        (status = 500, description = "Internal server error")
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the process scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 400, description = "Missing or malformed query parameters, or an invalid contribution range", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown user on the forge", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up, or every forge credential or a model API is rate limited; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 502, description = "The forge or a model API failed", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
     ),
+    security(("api_key" = ["process"])),
     tag = "process"
 )]
 #[instrument(skip(state))]
@@ -55,12 +102,29 @@ pub async fn process_user(
     State(state): State<Arc<AppState>>,
     Query(query): Query<ProcessUserQuery>,
 ) -> AppResult<Json<ProcessUserResponse>> {
-    info!("Processing user: {}", query.user);
-    let repos = state
-        .github_client
-        .get_user_contributed_repos(&query.user)
+    let period = ContributionPeriod::new(query.from, query.to, query.full_history)
+        .map_err(AppError::BadRequest)?;
+    let forge = state.forge(query.forge).map_err(AppError::BadRequest)?;
+    let response = ingest_user(&state, forge, &query.user, &period, &()).await?;
+    Ok(Json(response))
+}
+
+/// Ingests the commits `user` made during `period` in every repository they
+/// contributed to on `forge`. Pull requests, reviews, issues and discussions are
+/// only ingested from GitHub.
+#[instrument(skip(state, forge, progress), fields(forge = forge.kind().as_str()))]
+pub async fn ingest_user(
+    state: &AppState,
+    forge: &dyn Forge,
+    user: &str,
+    period: &ContributionPeriod,
+    progress: &dyn IngestProgress,
+) -> eyre::Result<ProcessUserResponse> {
+    info!("Processing user: {user}");
+    let repos = forge
+        .contributed_repos(user, period)
         .await
-        .wrap_err_with(|| format!("Failed to get contributed repos for user {}", query.user))?;
+        .wrap_err_with(|| format!("Failed to get contributed repos for user {user}"))?;
 
     let total_expected: i32 = repos.iter().map(|r| r.commit_count).sum();
     info!(
@@ -68,36 +132,30 @@ pub async fn process_user(
         repos.len(),
         total_expected
     );
+    progress.started(repos.len(), total_expected.max(0) as u64);
     let mut total_processed = 0;
     let mut repositories = Vec::new();
 
     // Process each repository
     for repo in repos {
         debug!("Processing repository: {}/{}", repo.owner, repo.name);
-        repositories.push(format!("{}/{}", repo.owner, repo.name));
-
-        let author_id = state
-            .github_client
-            .get_user_id(&query.user)
-            .await
-            .wrap_err_with(|| format!("Failed to get GitHub user ID for {}", query.user))?
-            .ok_or_else(|| eyre::eyre!("No GitHub ID found for user {}", query.user))?;
+        let name = format!("{}/{}", repo.owner, repo.name);
+        progress.repository(&name);
+        repositories.push(name);
 
-        let commits = state
-            .github_client
-            .get_commits(
-                &repo.owner,
-                &repo.name,
-                Some(&repo.default_branch),
-                Some(&author_id),
-            )
-            .await
-            .wrap_err_with(|| {
-                format!(
+        let commits = match forge.user_commits(&repo, user, period).await {
+            Ok(commits) => commits,
+            Err(e) if is_not_found(&e) => {
+                warn!("Skipping {}/{}: {e}", repo.owner, repo.name);
+                continue;
+            }
+            Err(e) => {
+                return Err(e.wrap_err(format!(
                     "Failed to get commits for repository {}/{}",
                     repo.owner, repo.name
-                )
-            })?;
+                )))
+            }
+        };
 
         if commits.is_empty() {
             debug!("No commits found for {}/{}", repo.owner, repo.name);
@@ -112,143 +170,1033 @@ pub async fn process_user(
         );
         total_processed += commits.len() as i32;
 
-        // Process each commit
+        // Filled in the first time a commit in this repository needs summarizing
+        let readme_summary = OnceCell::new();
+
         for commit in commits {
-            debug!("Processing commit: {}", commit.oid);
-            // Skip if already processed
-            let exists = state
-                .db
-                .commit_exists(&commit.oid)
-                .await
-                .wrap_err_with(|| {
-                    format!("Failed to check if commit {} exists in DB", commit.oid)
-                })?;
-
-            if exists {
-                debug!("Commit already processed: {}", commit.oid);
-                continue;
-            }
+            store_commit(
+                state,
+                CommitSource::Forge(forge),
+                &repo,
+                &readme_summary,
+                commit,
+                Some(user.to_string()),
+            )
+            .await?;
+            progress.commit_stored();
+        }
+    }
 
-            // Get commit patch
-            let patch = state
-                .github_client
-                .get_commit_patch(&repo.owner, &repo.name, &commit.oid)
-                .await
-                .wrap_err_with(|| {
-                    format!(
-                        "Failed to get patch for commit {} in {}/{}",
-                        commit.oid, repo.owner, repo.name
-                    )
-                })?;
-
-            // Skip if patch is too large (50KB)
-            if patch.len() > MAX_PATCH_SIZE_BYTES {
-                warn!(
-                    "Skipping large patch for commit {}: {} bytes",
-                    commit.oid,
-                    patch.len()
-                );
-                continue;
-            }
+    let activities_processed = if forge.kind() == ForgeKind::GitHub {
+        progress.activities();
+        ingest_activity(state, user, period).await?
+    } else {
+        0
+    };
 
-            // Skip if patch is empty
-            if patch.is_empty() {
-                warn!("Skipping empty patch for commit {}", commit.oid);
-                continue;
-            }
+    info!(
+        "Completed processing user {user}. Processed {total_processed}/{total_expected} commits \
+        and {activities_processed} other activities"
+    );
+    Ok(ProcessUserResponse {
+        total_expected,
+        total_processed,
+        repositories,
+        activities_processed,
+    })
+}
 
-            // Get README for additional context if available
-            let readme_content = state
-                .github_client
-                .get_readme(&repo.owner, &repo.name, &state.db)
-                .await
-                .wrap_err_with(|| {
-                    format!(
-                        "Failed to get README for repository {}/{}",
-                        repo.owner, repo.name
-                    )
-                })?;
-
-            // Generate README summary if available
-            let readme_summary = if let Some(readme) = &readme_content {
-                Some(
-                    state
-                        .machine_learning
-                        .summarize_readme(readme)
-                        .await
-                        .wrap_err_with(|| {
-                            format!(
-                                "Failed to generate README summary for repository {}/{}",
-                                repo.owner, repo.name
-                            )
-                        })?,
+/// Process every matching repository in a GitHub organization
+#[utoipa::path(
+    get,
+    path = "/process/org",
+    params(
+        ("org" = String, Query, description = "GitHub organization to process"),
+        ("include_archived" = Option<bool>, Query, description = "Also process archived repositories"),
+        ("include_forks" = Option<bool>, Query, description = "Also process forks"),
+        ("language" = Option<String>, Query, description = "Only process repositories with this primary language"),
+        ("topic" = Option<String>, Query, description = "Only process repositories tagged with this topic"),
+        ("from" = Option<String>, Query, description = "Only process commits made at or after this time (RFC 3339)"),
+        ("to" = Option<String>, Query, description = "Only process commits made at or before this time (RFC 3339)")
+    ),
+    responses(
+        (status = 200, description = "Successfully processed the organization's repositories", body = ProcessRepositoriesResponse),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the process scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 400, description = "Missing or malformed query parameters, or an invalid date range", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown organization on the forge", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up, or every forge credential or a model API is rate limited; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 502, description = "The forge or a model API failed", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["process"])),
+    tag = "process"
+)]
+#[instrument(skip(state))]
+pub async fn process_org(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ProcessOrgQuery>,
+) -> AppResult<Json<ProcessRepositoriesResponse>> {
+    check_range(query.from, query.to).map_err(AppError::BadRequest)?;
+    let filter = RepositoryFilter {
+        include_archived: query.include_archived,
+        include_forks: query.include_forks,
+        language: query.language,
+        topic: query.topic,
+    };
+    let response = ingest_org(&state, &query.org, &filter, query.from, query.to).await?;
+    Ok(Json(response))
+}
+
+/// Process a single repository's branch history
+#[utoipa::path(
+    get,
+    path = "/process/repo",
+    params(
+        ("owner" = String, Query, description = "Owner of the repository"),
+        ("repo" = String, Query, description = "Name of the repository"),
+        ("branch" = Option<String>, Query, description = "Branch to walk; defaults to the repository's default branch"),
+        ("from" = Option<String>, Query, description = "Only process commits made at or after this time (RFC 3339)"),
+        ("to" = Option<String>, Query, description = "Only process commits made at or before this time (RFC 3339)")
+    ),
+    responses(
+        (status = 200, description = "Successfully processed the repository", body = ProcessRepositoriesResponse),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the process scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 400, description = "Missing or malformed query parameters, or an invalid date range", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown repository on the forge", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up, or every forge credential or a model API is rate limited; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 502, description = "The forge or a model API failed", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    security(("api_key" = ["process"])),
+    tag = "process"
+)]
+#[instrument(skip(state))]
+pub async fn process_repository(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ProcessRepositoryQuery>,
+) -> AppResult<Json<ProcessRepositoriesResponse>> {
+    check_range(query.from, query.to).map_err(AppError::BadRequest)?;
+    let response = ingest_repository(
+        &state,
+        &query.owner,
+        &query.repo,
+        query.branch.as_deref(),
+        query.from,
+        query.to,
+    )
+    .await?;
+    Ok(Json(response))
+}
+
+fn check_range(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> eyre::Result<()> {
+    if let (Some(from), Some(to)) = (from, to) {
+        if from >= to {
+            eyre::bail!("Range start {from} must be before its end {to}");
+        }
+    }
+    Ok(())
+}
+
+/// Ingests every repository in `org` that matches `filter`
+#[instrument(skip(state))]
+pub async fn ingest_org(
+    state: &AppState,
+    org: &str,
+    filter: &RepositoryFilter,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> eyre::Result<ProcessRepositoriesResponse> {
+    info!("Processing organization: {org}");
+    let repos = state
+        .github_client
+        .get_org_repositories(org, filter)
+        .await
+        .wrap_err_with(|| format!("Failed to list repositories in {org}"))?;
+    ingest_repositories(state, repos, since, until).await
+}
+
+/// Ingests one branch of a repository, its default branch unless `branch` is given
+#[instrument(skip(state))]
+pub async fn ingest_repository(
+    state: &AppState,
+    owner: &str,
+    repo: &str,
+    branch: Option<&str>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> eyre::Result<ProcessRepositoriesResponse> {
+    let repository = match branch {
+        Some(branch) => Repository {
+            name: repo.to_string(),
+            owner: owner.to_string(),
+            default_branch: branch.to_string(),
+            commit_count: 0,
+        },
+        None => state
+            .github_client
+            .get_repository(owner, repo)
+            .await
+            .wrap_err_with(|| format!("Failed to look up {owner}/{repo}"))?,
+    };
+    ingest_repositories(state, vec![repository], since, until).await
+}
+
+/// Walks the branch history of `repos` for every author, attributing each commit to
+/// the GitHub account its author email is linked to
+async fn ingest_repositories(
+    state: &AppState,
+    repos: Vec<Repository>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> eyre::Result<ProcessRepositoriesResponse> {
+    let mut response = ProcessRepositoriesResponse::default();
+    let mut authors = BTreeSet::new();
+
+    for repo in repos {
+        debug!("Processing repository: {}/{}", repo.owner, repo.name);
+        let commits = state
+            .github_client
+            .get_branch_history(&repo.owner, &repo.name, &repo.default_branch, since, until)
+            .await
+            .wrap_err_with(|| {
+                format!(
+                    "Failed to get history of {}/{}@{}",
+                    repo.owner, repo.name, repo.default_branch
                 )
-            } else {
-                None
+            })?;
+        store_github_commits(state, &repo, commits, &mut response, &mut authors).await?;
+    }
+
+    response.authors = authors.into_iter().collect();
+    info!(
+        "Processed {}/{} commits from {} repositories by {} authors",
+        response.commits_processed,
+        response.commits_seen,
+        response.repositories.len(),
+        response.authors.len()
+    );
+    Ok(response)
+}
+
+/// Ingests commits a webhook reported as pushed to `repo`, skipping any that are
+/// already stored
+#[instrument(skip(state, commits), fields(commits = commits.len()))]
+pub async fn ingest_pushed_commits(
+    state: &AppState,
+    repo: &Repository,
+    commits: Vec<CommitInfo>,
+) -> eyre::Result<ProcessRepositoriesResponse> {
+    let mut response = ProcessRepositoriesResponse::default();
+    let mut authors = BTreeSet::new();
+    store_github_commits(state, repo, commits, &mut response, &mut authors).await?;
+    response.authors = authors.into_iter().collect();
+    info!(
+        "Processed {}/{} pushed commits in {}/{}",
+        response.commits_processed, response.commits_seen, repo.owner, repo.name
+    );
+    Ok(response)
+}
+
+/// Stores GitHub commits of `repo`, tallying them in `response` and `authors`
+async fn store_github_commits(
+    state: &AppState,
+    repo: &Repository,
+    commits: Vec<CommitInfo>,
+    response: &mut ProcessRepositoriesResponse,
+    authors: &mut BTreeSet<String>,
+) -> eyre::Result<()> {
+    response.commits_seen += commits.len();
+
+    let readme_summary = OnceCell::new();
+    for commit in commits {
+        let login = commit.author.login.clone();
+        let source = CommitSource::Forge(&state.github_client);
+        if store_commit(state, source, repo, &readme_summary, commit, login.clone()).await? {
+            response.commits_processed += 1;
+        }
+        match login {
+            Some(login) => {
+                authors.insert(login);
+            }
+            None => response.unattributed_commits += 1,
+        }
+    }
+    response
+        .repositories
+        .push(format!("{}/{}", repo.owner, repo.name));
+    Ok(())
+}
+
+/// Ingests one branch of a local clone or bare repository without calling the GitHub
+/// API, storing its commits under `org`/`repo`.
+///
+/// Authors are attributed by email: GitHub noreply addresses carry the login, and
+/// any other address is matched against commits ingested earlier.
+#[instrument(skip(state))]
+pub async fn ingest_local_repository(
+    state: &AppState,
+    path: &Path,
+    org: &str,
+    repo: &str,
+    branch: Option<&str>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> eyre::Result<ProcessRepositoriesResponse> {
+    let (local, branch) = {
+        let path = path.to_path_buf();
+        let branch = branch.map(String::from);
+        tokio::task::spawn_blocking(move || {
+            let local = LocalRepository::open(&path)?;
+            let branch = match branch {
+                Some(branch) => branch,
+                None => local.default_branch()?,
             };
+            eyre::Ok((Arc::new(local), branch))
+        })
+        .await
+        .wrap_err("Failed to open the local repository")??
+    };
+    let repository = Repository {
+        name: repo.to_string(),
+        owner: org.to_string(),
+        default_branch: branch,
+        commit_count: 0,
+    };
+    let branch = repository.default_branch.clone();
+    let commits = run_blocking(&local, move |local| local.history(&branch, since, until))
+        .await
+        .wrap_err_with(|| format!("Failed to walk history of {}", path.display()))?;
+
+    let mut response = ProcessRepositoriesResponse {
+        repositories: vec![format!("{org}/{repo}")],
+        commits_seen: commits.len(),
+        ..ProcessRepositoriesResponse::default()
+    };
+    let mut authors = BTreeSet::new();
+    let readme_summary = OnceCell::new();
+    for commit in commits {
+        let login = match (&commit.author.login, &commit.author.email) {
+            (Some(login), _) => Some(login.clone()),
+            (None, Some(email)) => state.db.login_for_email(email).await?,
+            (None, None) => None,
+        };
+        let source = CommitSource::Local(&local);
+        if store_commit(
+            state,
+            source,
+            &repository,
+            &readme_summary,
+            commit,
+            login.clone(),
+        )
+        .await?
+        {
+            response.commits_processed += 1;
+        }
+        match login {
+            Some(login) => {
+                authors.insert(login);
+            }
+            None => response.unattributed_commits += 1,
+        }
+    }
+
+    response.authors = authors.into_iter().collect();
+    info!(
+        "Processed {}/{} commits from {} by {} authors",
+        response.commits_processed,
+        response.commits_seen,
+        path.display(),
+        response.authors.len()
+    );
+    Ok(response)
+}
+
+/// Stores `commit` for `repo`, generating its summary and embedding unless the SHA
+/// was already processed in another repository. Returns whether a new commit was
+/// stored.
+#[instrument(skip_all, fields(sha = %commit.oid))]
+async fn store_commit(
+    state: &AppState,
+    source: CommitSource<'_>,
+    repo: &Repository,
+    readme_summary: &OnceCell<Option<String>>,
+    commit: CommitInfo,
+    author_login: Option<String>,
+) -> eyre::Result<bool> {
+    debug!("Processing commit: {}", commit.oid);
+    // Skip if already processed for this repository
+    let exists = state
+        .db
+        .commit_exists(source.kind(), &commit.oid, &repo.owner, &repo.name)
+        .await
+        .wrap_err_with(|| format!("Failed to check if commit {} exists in DB", commit.oid))?;
+
+    if exists {
+        debug!("Commit already processed: {}", commit.oid);
+        return Ok(false);
+    }
 
-            // Combine patch with README summary for context if available
-            let text_to_summarize = readme_summary.map_or_else(
-                || patch.clone(),
-                |readme| {
-                    format!("Repository README Summary:\n{readme}\n\nCommit Changes:\n{patch}",)
-                },
+    // Reuse the summary and embedding if this SHA was already processed in another
+    // repository (e.g. a fork or mirror)
+    let existing_content = state
+        .db
+        .get_commit_content(&commit.oid)
+        .await
+        .wrap_err_with(|| format!("Failed to look up content for commit {}", commit.oid))?;
+
+    let content = if let Some(content) = existing_content {
+        debug!(
+            "Reusing content for commit {} already seen in {:?}",
+            commit.oid, content.repositories
+        );
+        content
+    } else {
+        let Some(content) =
+            generate_commit_content(state, source, repo, readme_summary, &commit.oid).await?
+        else {
+            return Ok(false);
+        };
+
+        state
+            .db
+            .insert_commit_content(content.clone())
+            .await
+            .wrap_err_with(|| format!("Failed to store content for commit {}", commit.oid))?;
+        content
+    };
+
+    // Store in database
+    let commit_doc = CommitDocument {
+        sha: commit.oid.clone(),
+        message: commit.message_headline,
+        date: commit.committed_date,
+        org: repo.owner.clone(),
+        repo: repo.name.clone(),
+        forge: source.kind(),
+        author_login,
+        author_name: commit.author.name,
+        author_email: commit.author.email,
+        patch: content.patch,
+        summary: content.summary,
+        embedding: content.embedding,
+    };
+
+    state
+        .db
+        .insert_commit(commit_doc)
+        .await
+        .wrap_err_with(|| format!("Failed to insert commit {} into DB", commit.oid))?;
+
+    let attributes = [KeyValue::new("forge", source.kind().as_str())];
+    state.metrics.commits_processed.add(1, &attributes);
+    debug!("Successfully stored commit: {}", commit.oid);
+    Ok(true)
+}
+
+/// An activity document before it's summarized, with the text its summary is
+/// generated from
+#[derive(Debug)]
+struct NewActivity {
+    document: ActivityDocument,
+    text: String,
+}
+
+impl NewActivity {
+    fn pull_request(author: &str, pr: PullRequestInfo) -> Self {
+        let text = format!(
+            "Pull request: {}\n\n{}\n\n{} additions and {} deletions across {} files",
+            pr.title, pr.body, pr.additions, pr.deletions, pr.changed_files
+        );
+        let document = ActivityDocument {
+            id: pr.id,
+            kind: DocumentKind::PullRequest,
+            org: pr.owner,
+            repo: pr.repo,
+            author_login: Some(author.to_string()),
+            url: pr.url,
+            title: pr.title,
+            body: pr.body,
+            date: pr.created_at,
+            diff_stats: Some(DiffStats {
+                additions: pr.additions,
+                deletions: pr.deletions,
+                changed_files: pr.changed_files,
+            }),
+            merged: Some(pr.merged),
+            path: None,
+            diff_hunk: None,
+            labels: Vec::new(),
+            category: None,
+            summary: CommitSummary::default(),
+            embedding: Vec::new(),
+        };
+        Self { document, text }
+    }
+
+    /// The review's inline comments, then the review itself if it has a body
+    fn review(author: &str, mut review: ReviewInfo) -> Vec<Self> {
+        let comments = std::mem::take(&mut review.comments);
+        let mut activities: Vec<Self> = comments
+            .into_iter()
+            .map(|comment| Self::review_comment(author, &review, comment))
+            .collect();
+
+        // Approvals without a written review carry no signal of their own
+        if review.body.trim().is_empty() {
+            return activities;
+        }
+        let text = format!(
+            "Review of pull request: {}\n\n{}",
+            review.pull_request_title, review.body
+        );
+        let document = ActivityDocument {
+            id: review.id,
+            kind: DocumentKind::Review,
+            org: review.owner,
+            repo: review.repo,
+            author_login: Some(author.to_string()),
+            url: review.url,
+            title: review.pull_request_title,
+            body: review.body,
+            date: review.created_at,
+            diff_stats: None,
+            merged: None,
+            path: None,
+            diff_hunk: None,
+            labels: Vec::new(),
+            category: None,
+            summary: CommitSummary::default(),
+            embedding: Vec::new(),
+        };
+        activities.push(Self { document, text });
+        activities
+    }
+
+    fn review_comment(author: &str, review: &ReviewInfo, comment: ReviewCommentInfo) -> Self {
+        let text = format!(
+            "Review comment on {} in pull request: {}\n\n{}\n\n{}",
+            comment.path, review.pull_request_title, comment.diff_hunk, comment.body
+        );
+        let document = ActivityDocument {
+            id: comment.id,
+            kind: DocumentKind::ReviewComment,
+            org: review.owner.clone(),
+            repo: review.repo.clone(),
+            author_login: Some(author.to_string()),
+            url: comment.url,
+            title: review.pull_request_title.clone(),
+            body: comment.body,
+            date: comment.created_at,
+            diff_stats: None,
+            merged: None,
+            path: Some(comment.path),
+            diff_hunk: Some(comment.diff_hunk),
+            labels: Vec::new(),
+            category: None,
+            summary: CommitSummary::default(),
+            embedding: Vec::new(),
+        };
+        Self { document, text }
+    }
+
+    /// An issue or discussion, or a comment on one
+    fn issue_activity(author: &str, post: IssueActivity) -> Self {
+        let heading = match post.kind {
+            DocumentKind::Issue => "Issue",
+            DocumentKind::IssueComment => "Comment on issue",
+            DocumentKind::Discussion => "Discussion",
+            _ => "Comment on discussion",
+        };
+        let mut text = format!("{heading}: {}\n", post.title);
+        if let Some(category) = &post.category {
+            text.push_str(&format!("Category: {category}\n"));
+        }
+        if !post.labels.is_empty() {
+            text.push_str(&format!("Labels: {}\n", post.labels.join(", ")));
+        }
+        text.push_str(&format!("\n{}", post.body));
+
+        let document = ActivityDocument {
+            id: post.id,
+            kind: post.kind,
+            org: post.owner,
+            repo: post.repo,
+            author_login: Some(author.to_string()),
+            url: post.url,
+            title: post.title,
+            body: post.body,
+            date: post.created_at,
+            diff_stats: None,
+            merged: None,
+            path: None,
+            diff_hunk: None,
+            labels: post.labels,
+            category: post.category,
+            summary: CommitSummary::default(),
+            embedding: Vec::new(),
+        };
+        Self { document, text }
+    }
+}
+
+/// Ingests the pull requests, reviews, issues, discussions and comments `user`
+/// authored during `period`, returning how many new documents were stored
+#[instrument(skip(state))]
+async fn ingest_activity(
+    state: &AppState,
+    user: &str,
+    period: &ContributionPeriod,
+) -> eyre::Result<usize> {
+    let pull_requests = state
+        .github_client
+        .get_user_pull_requests(user, period)
+        .await
+        .wrap_err_with(|| format!("Failed to get pull requests for user {user}"))?;
+    let reviews = state
+        .github_client
+        .get_user_reviews(user, period)
+        .await
+        .wrap_err_with(|| format!("Failed to get reviews for user {user}"))?;
+    let issue_activity = state
+        .github_client
+        .get_user_issue_activity(user, period)
+        .await
+        .wrap_err_with(|| format!("Failed to get issues and discussions for user {user}"))?;
+
+    let activities = pull_requests
+        .into_iter()
+        .map(|pr| NewActivity::pull_request(user, pr))
+        .chain(
+            reviews
+                .into_iter()
+                .flat_map(|review| NewActivity::review(user, review)),
+        )
+        .chain(
+            issue_activity
+                .into_iter()
+                .map(|post| NewActivity::issue_activity(user, post)),
+        );
+
+    let mut stored = 0;
+    for NewActivity { mut document, text } in activities {
+        let exists = state
+            .db
+            .activity_exists(&document.id)
+            .await
+            .wrap_err_with(|| format!("Failed to check if activity {} exists", document.id))?;
+        if exists {
+            debug!("Activity already processed: {}", document.id);
+            continue;
+        }
+        if text.len() > MAX_PATCH_SIZE_BYTES {
+            warn!(
+                "Skipping large {:?} {}: {} bytes",
+                document.kind,
+                document.id,
+                text.len()
             );
+            continue;
+        }
 
-            // Generate summary first since we'll use it for embedding
-            let summary = state
-                .machine_learning
-                .summarize_text(&text_to_summarize)
-                .await
-                .wrap_err_with(|| {
-                    format!("Failed to generate summary for commit {}", commit.oid)
-                })?;
-
-            // Serialize summary to JSON for embedding
-            let summary_json = serde_json::to_string(&summary).wrap_err_with(|| {
-                format!("Failed to serialize summary for commit {}", commit.oid)
-            })?;
+        document.summary = state
+            .machine_learning
+            .summarize_text(&text)
+            .await
+            .wrap_err_with(|| format!("Failed to generate summary for {}", document.url))?;
+        let summary_json = serde_json::to_string(&document.summary)
+            .wrap_err_with(|| format!("Failed to serialize summary for {}", document.url))?;
+        document.embedding = embed(state, &summary_json)
+            .await
+            .wrap_err_with(|| format!("Failed to generate embedding for {}", document.url))?;
 
-            // Generate embedding from the serialized summary
-            let embedding = state
-                .machine_learning
-                .get_embedding(&summary_json)
-                .await
-                .wrap_err_with(|| {
-                    format!("Failed to generate embedding for commit {}", commit.oid)
-                })?;
-
-            debug!("Generated embedding and summary for commit: {}", commit.oid);
-
-            // Store in database
-            let commit_doc = CommitDocument {
-                sha: commit.oid.clone(),
-                message: commit.message_headline,
-                date: commit.committed_date,
-                org: repo.owner.clone(),
-                repo: repo.name.clone(),
-                patch,
-                summary,
-                embedding,
-            };
+        let id = document.id.clone();
+        state
+            .db
+            .insert_activity(document)
+            .await
+            .wrap_err_with(|| format!("Failed to insert activity {id} into DB"))?;
+        stored += 1;
+    }
 
-            state
-                .db
-                .insert_commit(commit_doc)
-                .await
-                .wrap_err_with(|| format!("Failed to insert commit {} into DB", commit.oid))?;
+    Ok(stored)
+}
 
-            debug!("Successfully stored commit: {}", commit.oid);
+/// Embedding of a serialized summary, reusing the one computed for an identical
+/// summary before
+async fn embed(state: &AppState, summary_json: &str) -> eyre::Result<Vec<f32>> {
+    if let Some(embedding) = state
+        .db
+        .get_cached_embedding(EMBEDDING_MODEL, summary_json)
+        .await?
+    {
+        let attributes = [KeyValue::new("result", "hit")];
+        state.metrics.embedding_cache.add(1, &attributes);
+        return Ok(embedding);
+    }
+    state
+        .metrics
+        .embedding_cache
+        .add(1, &[KeyValue::new("result", "miss")]);
+
+    let embedding = state.machine_learning.get_embedding(summary_json).await?;
+    state
+        .db
+        .cache_embedding(EMBEDDING_MODEL, summary_json, embedding.clone())
+        .await?;
+    Ok(embedding)
+}
+
+/// Fetches a commit's patch and generates its summary and embedding.
+///
+/// Returns `None` if the patch is empty or too large to process.
+#[instrument(skip(state, source, repo, readme_summary))]
+async fn generate_commit_content(
+    state: &AppState,
+    source: CommitSource<'_>,
+    repo: &Repository,
+    readme_summary: &OnceCell<Option<String>>,
+    sha: &str,
+) -> eyre::Result<Option<CommitContentDocument>> {
+    // Get commit patch
+    let patch = match source {
+        CommitSource::Forge(forge) => forge.commit_patch(&repo.owner, &repo.name, sha).await,
+        CommitSource::Local(local) => {
+            let sha = sha.to_string();
+            run_blocking(local, move |local| local.patch(&sha)).await
         }
+    };
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(e) if is_not_found(&e) => {
+            warn!("Skipping commit {sha} in {}/{}: {e}", repo.owner, repo.name);
+            return Ok(None);
+        }
+        Err(e) => {
+            return Err(e.wrap_err(format!(
+                "Failed to get patch for commit {} in {}/{}",
+                sha, repo.owner, repo.name
+            )))
+        }
+    };
+
+    // Skip if patch is too large (50KB)
+    if patch.len() > MAX_PATCH_SIZE_BYTES {
+        warn!(
+            "Skipping large patch for commit {}: {} bytes",
+            sha,
+            patch.len()
+        );
+        return Ok(None);
     }
 
-    info!(
-        "Completed processing user {}. Processed {}/{} commits",
-        query.user, total_processed, total_expected
+    // Skip if patch is empty
+    if patch.is_empty() {
+        warn!("Skipping empty patch for commit {}", sha);
+        return Ok(None);
+    }
+
+    // Get the README summary for additional context if available. It's shared by
+    // every commit in the repository, so it's only looked up once.
+    let readme_summary = readme_summary
+        .get_or_try_init(|| repo_readme_summary(state, source, repo))
+        .await?;
+
+    // Combine patch with README summary for context if available
+    let text_to_summarize = readme_summary.as_ref().map_or_else(
+        || patch.clone(),
+        |readme| format!("Repository README Summary:\n{readme}\n\nCommit Changes:\n{patch}",),
     );
-    Ok(Json(ProcessUserResponse {
-        total_expected,
-        total_processed,
-        repositories,
+
+    // Generate summary first since we'll use it for embedding
+    let summary = state
+        .machine_learning
+        .summarize_text(&text_to_summarize)
+        .await
+        .wrap_err_with(|| format!("Failed to generate summary for commit {}", sha))?;
+
+    // Serialize summary to JSON for embedding
+    let summary_json = serde_json::to_string(&summary)
+        .wrap_err_with(|| format!("Failed to serialize summary for commit {}", sha))?;
+
+    // Generate embedding from the serialized summary
+    let embedding = embed(state, &summary_json)
+        .await
+        .wrap_err_with(|| format!("Failed to generate embedding for commit {}", sha))?;
+
+    debug!("Generated embedding and summary for commit: {}", sha);
+
+    Ok(Some(CommitContentDocument {
+        sha: sha.to_string(),
+        patch,
+        summary,
+        embedding,
+        repositories: Vec::new(),
     }))
 }
+
+/// Returns the summary of a repository's README, generating it only when the README
+/// changed or was summarized by a different model.
+#[instrument(skip(state, source))]
+async fn repo_readme_summary(
+    state: &AppState,
+    source: CommitSource<'_>,
+    repo: &Repository,
+) -> eyre::Result<Option<String>> {
+    let readme = match source {
+        CommitSource::Forge(forge) => forge.readme(&repo.owner, &repo.name, &state.db).await,
+        CommitSource::Local(local) => local_readme(state, local, repo).await,
+    };
+    let readme = match readme {
+        Ok(readme) => readme,
+        Err(e) if is_not_found(&e) => None,
+        Err(e) => {
+            return Err(e.wrap_err(format!(
+                "Failed to get README for repository {}/{}",
+                repo.owner, repo.name
+            )))
+        }
+    };
+
+    let Some(readme) = readme else {
+        return Ok(None);
+    };
+
+    if let Some(summary) = readme
+        .summary
+        .filter(|summary| summary.model == SUMMARY_MODEL)
+    {
+        debug!(
+            "Using cached README summary for {}/{}",
+            repo.owner, repo.name
+        );
+        return Ok(Some(summary.text));
+    }
+
+    let text = state
+        .machine_learning
+        .summarize_readme(&readme.content)
+        .await
+        .wrap_err_with(|| {
+            format!(
+                "Failed to generate README summary for repository {}/{}",
+                repo.owner, repo.name
+            )
+        })?;
+
+    let summary = ReadmeSummary {
+        text,
+        model: SUMMARY_MODEL.to_string(),
+        generated_at: Utc::now(),
+    };
+    state
+        .db
+        .cache_readme_summary(
+            source.kind(),
+            &repo.owner,
+            &repo.name,
+            &readme.content,
+            &summary,
+        )
+        .await?;
+
+    Ok(Some(summary.text))
+}
+
+/// Runs `read` on the blocking thread pool, since git2 reads the repository
+/// synchronously and would otherwise stall the runtime's worker threads
+async fn run_blocking<T: Send + 'static>(
+    local: &Arc<LocalRepository>,
+    read: impl FnOnce(&LocalRepository) -> eyre::Result<T> + Send + 'static,
+) -> eyre::Result<T> {
+    let local = Arc::clone(local);
+    tokio::task::spawn_blocking(move || read(&local))
+        .await
+        .wrap_err("Failed to read the local repository")?
+}
+
+/// Reads the README from a local repository, reusing the cached document (and its
+/// summary) while the content is unchanged
+async fn local_readme(
+    state: &AppState,
+    local: &Arc<LocalRepository>,
+    repo: &Repository,
+) -> eyre::Result<Option<ReadmeDocument>> {
+    let branch = repo.default_branch.clone();
+    let Some(content) = run_blocking(local, move |local| local.readme(&branch)).await? else {
+        return Ok(None);
+    };
+    let readme = state
+        .db
+        .store_readme(ForgeKind::Local, &repo.owner, &repo.name, content, None)
+        .await?;
+    Ok(Some(readme))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::MongoDb,
+        github::fake::FakeGitHub,
+        ml::{Cassette, MachineLearning, GEMINI_URL_PREFIX},
+    };
+    use axum::http::StatusCode;
+
+    fn review(body: &str) -> ReviewInfo {
+        ReviewInfo {
+            id: "PRR_1".to_string(),
+            body: body.to_string(),
+            url: "https://github.com/octocat/Hello-World/pull/1#pullrequestreview-1".to_string(),
+            created_at: "2025-01-05T09:00:00Z".to_string(),
+            pull_request_title: "Fix the README heading".to_string(),
+            owner: "octocat".to_string(),
+            repo: "Hello-World".to_string(),
+            comments: vec![ReviewCommentInfo {
+                id: "PRRC_1".to_string(),
+                body: "Typo.".to_string(),
+                url: "https://github.com/octocat/Hello-World/pull/1#discussion_r1".to_string(),
+                created_at: "2025-01-05T08:58:00Z".to_string(),
+                path: "README".to_string(),
+                diff_hunk: "@@ -1 +1 @@".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_review_activities() {
+        let activities = NewActivity::review("octocat", review("Looks good."));
+        let kinds: Vec<DocumentKind> = activities.iter().map(|a| a.document.kind).collect();
+        assert_eq!(kinds, [DocumentKind::ReviewComment, DocumentKind::Review]);
+        assert_eq!(
+            activities[0].text,
+            "Review comment on README in pull request: Fix the README heading\n\n@@ -1 +1 @@\n\nTypo."
+        );
+        assert_eq!(activities[0].document.path.as_deref(), Some("README"));
+        assert_eq!(
+            activities[1].document.author_login.as_deref(),
+            Some("octocat")
+        );
+
+        // A bare approval only contributes its comments
+        let activities = NewActivity::review("octocat", review("  "));
+        assert_eq!(activities.len(), 1);
+        assert_eq!(activities[0].document.kind, DocumentKind::ReviewComment);
+    }
+
+    #[test]
+    fn test_issue_activity_text() {
+        let activity = NewActivity::issue_activity(
+            "octocat",
+            IssueActivity {
+                id: "D_1".to_string(),
+                kind: DocumentKind::Discussion,
+                title: "Translating the greeting".to_string(),
+                body: "Should the README say hello in other languages?".to_string(),
+                url: "https://github.com/octocat/Hello-World/discussions/1".to_string(),
+                created_at: "2025-01-08T12:00:00Z".to_string(),
+                owner: "octocat".to_string(),
+                repo: "Hello-World".to_string(),
+                labels: vec!["i18n".to_string()],
+                category: Some("Ideas".to_string()),
+            },
+        );
+        assert_eq!(
+            activity.text,
+            "Discussion: Translating the greeting\nCategory: Ideas\nLabels: i18n\n\n\
+             Should the README say hello in other languages?"
+        );
+        assert_eq!(activity.document.labels, ["i18n"]);
+        assert_eq!(activity.document.category.as_deref(), Some("Ideas"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_ingest_user() -> eyre::Result<()> {
+        let db = MongoDb::for_tests().await?;
+        // Three commits and the issues come in two pages each
+        let github = FakeGitHub::start("user").await?;
+        let mut config = github.config();
+        config.commits_per_page = 2;
+        let state = AppState::for_tests(config, db)?;
+        let period = ContributionPeriod::new(
+            Some("2025-01-01T00:00:00Z".parse()?),
+            Some("2025-07-01T00:00:00Z".parse()?),
+            false,
+        )?;
+
+        let response = ingest_user(&state, &state.github_client, "octocat", &period, &()).await?;
+        assert_eq!(response.repositories, ["octocat/Hello-World"]);
+        assert_eq!((response.total_expected, response.total_processed), (3, 3));
+        // A pull request, a review and its comment, two issues, an issue comment, a
+        // discussion and a comment on it
+        assert_eq!(response.activities_processed, 8);
+        for sha in [
+            "55eda6ad7094d2f384b75b32613e9a460353ca68",
+            "2fd9cc11c24744b720ba574a38da6a7ddf4d3cc7",
+            "abae923d1ef4051eeb0be1d6de869932affebdd6",
+        ] {
+            assert!(
+                state
+                    .db
+                    .commit_exists(ForgeKind::GitHub, sha, "octocat", "Hello-World")
+                    .await?
+            );
+        }
+        let pages = |target: &str| {
+            github
+                .requests()
+                .iter()
+                .filter(|request| request.target == target)
+                .count()
+        };
+        assert_eq!(pages("POST /graphql Commits"), 2);
+        assert_eq!(pages("POST /graphql UserIssues"), 2);
+        // The README is fetched once for all three commits
+        assert_eq!(pages("GET /repos/octocat/Hello-World/readme"), 1);
+
+        // Everything is stored already, so a second run adds nothing
+        let again = ingest_user(&state, &state.github_client, "octocat", &period, &()).await?;
+        assert_eq!(again.activities_processed, 0);
+
+        state.db.drop_for_tests().await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_process_user_reports_model_errors() -> eyre::Result<()> {
+        for (model_status, status) in [
+            (429, StatusCode::TOO_MANY_REQUESTS),
+            (500, StatusCode::BAD_GATEWAY),
+        ] {
+            let db = MongoDb::for_tests().await?;
+            let github = FakeGitHub::start("user").await?;
+            let mut state = AppState::for_tests(github.config(), db)?;
+            let cassettes = Cassette::recorded_failing(GEMINI_URL_PREFIX, model_status)?;
+            state.machine_learning =
+                MachineLearning::with_cassette(None, None, Some(Cassette::open(cassettes.path())))?;
+            let state = Arc::new(state);
+
+            let query = ProcessUserQuery {
+                user: "octocat".to_string(),
+                forge: ForgeKind::GitHub,
+                from: Some("2025-01-01T00:00:00Z".parse()?),
+                to: Some("2025-07-01T00:00:00Z".parse()?),
+                full_history: false,
+            };
+            let Err(error) = process_user(State(state.clone()), Query(query)).await else {
+                panic!("processing succeeded despite the failing summaries");
+            };
+            assert_eq!(error.status(), status);
+
+            Arc::into_inner(state)
+                .expect("the handler is done with the state")
+                .db
+                .drop_for_tests()
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -1,43 +1,78 @@
-use axum::{
-    extract::{Query, State},
-    Json,
-};
+use axum::extract::State;
 use std::sync::Arc;
 
 use crate::{
-    api::types::{AppState, SearchQuery, SearchResult},
+    api::{
+        error::{AppError, AppResult, ProblemDetails},
+        extract::{Json, Query},
+        types::{AppState, SearchQuery, SearchResult},
+    },
+    database::{CommitFilter, DocumentKind},
     ml::MachineLearning,
 };
 
-/// Search through commits using semantic similarity
+/// Search through commits, pull requests and reviews using semantic similarity
 #[utoipa::path(
     get,
     path = "/search",
     params(
-        ("query" = String, Query, description = "The search query to find similar commits")
+        ("query" = String, Query, description = "The search query to find similar commits"),
+        ("org" = Option<String>, Query, description = "Only return commits from this organization or user"),
+        ("repo" = Option<String>, Query, description = "Only return commits from repositories with this name"),
+        ("kind" = Option<DocumentKind>, Query, description = "Only return documents of this kind")
     ),
     responses(
-        (status = 200, description = "List of commits sorted by similarity to the query", body = Vec<SearchResult>),
-        (status = 500, description = "Internal server error")
+        (status = 200, description = "List of documents sorted by similarity to the query", body = Vec<SearchResult>),
+        (status = 400, description = "Missing or malformed query parameters", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, invalid or revoked API key", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 403, description = "The API key doesn't have the search scope", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 429, description = "The API key's rate limit or daily quota is used up, or the embedding API is rate limited; see Retry-After", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 502, description = "The embedding API failed", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json")
     ),
+    security(("api_key" = ["search"])),
     tag = "search"
 )]
 pub async fn search(
     State(state): State<Arc<AppState>>,
     Query(query): Query<SearchQuery>,
-) -> Json<Vec<SearchResult>> {
-    let commits = state.db.get_all_commits().await.unwrap_or_default();
-    let Ok(query_embedding) = state.machine_learning.get_embedding(&query.query).await else {
-        return Json(Vec::new());
+) -> AppResult<Json<Vec<SearchResult>>> {
+    let filter = CommitFilter {
+        org: query.org,
+        repo: query.repo,
+        ..CommitFilter::default()
+    };
+    let commits = match query.kind {
+        None | Some(DocumentKind::Commit) => state.db.get_commits(&filter).await?,
+        Some(_) => Vec::new(),
+    };
+    let activities = match query.kind {
+        Some(DocumentKind::Commit) => Vec::new(),
+        kind => state.db.get_activities(&filter, kind).await?,
     };
+    let query_embedding = state
+        .machine_learning
+        .get_embedding(&query.query)
+        .await
+        .map_err(|report| match AppError::from(report) {
+            // Such as failing to reach the embedding API at all
+            AppError::Internal(report) => AppError::Upstream(report),
+            error => error,
+        })?;
 
-    let mut results: Vec<_> = commits
-        .into_iter()
-        .map(|commit| SearchResult {
-            similarity: MachineLearning::cosine_similarity(&query_embedding, &commit.embedding),
-            commit,
-        })
-        .collect();
+    let commit_results = commits.into_iter().map(|commit| SearchResult {
+        similarity: MachineLearning::cosine_similarity(&query_embedding, &commit.embedding),
+        kind: DocumentKind::Commit,
+        commit: Some(commit),
+        activity: None,
+    });
+    let activity_results = activities.into_iter().map(|activity| SearchResult {
+        similarity: MachineLearning::cosine_similarity(&query_embedding, &activity.embedding),
+        kind: activity.kind,
+        commit: None,
+        activity: Some(activity),
+    });
+    let mut results: Vec<_> = commit_results.chain(activity_results).collect();
 
     results.sort_by(|a, b| {
         b.similarity
@@ -45,5 +80,51 @@ pub async fn search(
             .expect("Similarity scores should be comparable")
     });
 
-    Json(results)
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        database::MongoDb,
+        ml::{Cassette, OPENAI_URL_PREFIX},
+    };
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_search_reports_model_errors() -> eyre::Result<()> {
+        for (model_status, status) in [
+            (429, StatusCode::TOO_MANY_REQUESTS),
+            (500, StatusCode::BAD_GATEWAY),
+        ] {
+            let db = MongoDb::for_tests().await?;
+            let mut state = AppState::for_tests(Config::for_tests("http://127.0.0.1:0"), db)?;
+            let cassettes = Cassette::recorded_failing(OPENAI_URL_PREFIX, model_status)?;
+            state.machine_learning =
+                MachineLearning::with_cassette(None, None, Some(Cassette::open(cassettes.path())))?;
+            let state = Arc::new(state);
+
+            // The query embedded by the recorded embedding test
+            let query = SearchQuery {
+                query: "Hello, world!".to_string(),
+                org: None,
+                repo: None,
+                kind: None,
+            };
+            let Err(error) = search(State(state.clone()), Query(query)).await else {
+                panic!("searching succeeded despite the failing embedding");
+            };
+            assert_eq!(error.status(), status);
+
+            Arc::into_inner(state)
+                .expect("the handler is done with the state")
+                .db
+                .drop_for_tests()
+                .await?;
+        }
+        Ok(())
+    }
 }
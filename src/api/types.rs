@@ -1,5 +1,15 @@
-use crate::database::CommitDocument;
-use crate::{config::Config, database::MongoDb, github::GitHubClient, ml::MachineLearning};
+use crate::api::{auth::RateLimiter, health::ProviderChecks, webhooks::WebhookQueue};
+use crate::corpus::CorpusFormat;
+use crate::database::{ActivityDocument, ApiKey, ApiScope, CommitDocument, DocumentKind};
+use crate::{
+    config::Config,
+    database::MongoDb,
+    forge::{Forge, ForgeKind, GitLabClient, GiteaClient},
+    github::GitHubClient,
+    ml::MachineLearning,
+    telemetry::Metrics,
+};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
@@ -8,24 +18,132 @@ pub struct AppState {
     pub config: Config,
     pub machine_learning: MachineLearning,
     pub github_client: GitHubClient,
+    pub gitlab_client: GitLabClient,
+    pub gitea_client: GiteaClient,
+    pub webhooks: WebhookQueue,
+    /// Per-minute request counts of each API key
+    pub rate_limiter: RateLimiter,
+    /// Last result of the `/readyz` provider checks
+    pub provider_checks: ProviderChecks,
+    pub metrics: Metrics,
+}
+
+impl AppState {
+    /// The client for a remote forge
+    pub fn forge(&self, kind: ForgeKind) -> eyre::Result<&dyn Forge> {
+        match kind {
+            ForgeKind::GitHub => Ok(&self.github_client),
+            ForgeKind::GitLab => Ok(&self.gitlab_client),
+            ForgeKind::Gitea => Ok(&self.gitea_client),
+            ForgeKind::Local => eyre::bail!("Local repositories can't be processed by user"),
+        }
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for handler tests, with the forges and `db` configured by `config` and
+    /// model calls replayed from the recorded cassettes. API keys are taken from the
+    /// environment, for recording them.
+    pub fn for_tests(config: Config, db: MongoDb) -> eyre::Result<Self> {
+        let cassettes = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/ml/cassettes");
+        Ok(Self {
+            github_client: GitHubClient::new(config.clone())?,
+            gitlab_client: GitLabClient::new(&config)?,
+            gitea_client: GiteaClient::new(&config)?,
+            machine_learning: MachineLearning::with_cassette(
+                std::env::var("GEMINI_API_KEY").ok(),
+                std::env::var("OPENAI_API_KEY").ok(),
+                Some(crate::ml::Cassette::open(cassettes)),
+            )?,
+            webhooks: WebhookQueue::new(),
+            rate_limiter: RateLimiter::default(),
+            provider_checks: ProviderChecks::default(),
+            metrics: Metrics::new(),
+            db,
+            config,
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct SearchQuery {
     pub query: String,
+    /// Only return commits from this organization or user
+    pub org: Option<String>,
+    /// Only return commits from repositories with this name
+    pub repo: Option<String>,
+    /// Only return documents of this kind
+    pub kind: Option<DocumentKind>,
 }
 
 #[derive(Debug, Deserialize, ToSchema)]
 pub struct ProcessUserQuery {
-    /// GitHub username to process
+    /// Username to process
     pub user: String,
+    /// Forge the user is on
+    #[serde(default)]
+    pub forge: ForgeKind,
+    /// Start of the contribution range (RFC 3339); defaults to a year before `to`
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub from: Option<DateTime<Utc>>,
+    /// End of the contribution range (RFC 3339); defaults to now
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub to: Option<DateTime<Utc>>,
+    /// Walk every year the user has contributions in instead of a single range
+    #[serde(default)]
+    pub full_history: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ProcessOrgQuery {
+    /// GitHub organization to process
+    pub org: String,
+    /// Also process archived repositories
+    #[serde(default)]
+    pub include_archived: bool,
+    /// Also process forks
+    #[serde(default)]
+    pub include_forks: bool,
+    /// Only process repositories with this primary language
+    pub language: Option<String>,
+    /// Only process repositories tagged with this topic
+    pub topic: Option<String>,
+    /// Only process commits made at or after this time (RFC 3339)
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub from: Option<DateTime<Utc>>,
+    /// Only process commits made at or before this time (RFC 3339)
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ProcessRepositoryQuery {
+    /// Owner of the repository
+    pub owner: String,
+    /// Name of the repository
+    pub repo: String,
+    /// Branch to walk; defaults to the repository's default branch
+    pub branch: Option<String>,
+    /// Only process commits made at or after this time (RFC 3339)
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub from: Option<DateTime<Utc>>,
+    /// Only process commits made at or before this time (RFC 3339)
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub to: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
 pub struct SearchResult {
     /// Similarity score between 0 and 1
     pub similarity: f32,
-    pub commit: CommitDocument,
+    pub kind: DocumentKind,
+    /// Set when `kind` is `commit`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<CommitDocument>,
+    /// Set for every other kind
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity: Option<ActivityDocument>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -36,4 +154,122 @@ pub struct ProcessUserResponse {
     pub total_processed: i32,
     /// List of repositories that were processed
     pub repositories: Vec<String>,
+    /// Number of new pull requests, reviews, issues, discussions and comments stored
+    pub activities_processed: usize,
+}
+
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ProcessRepositoriesResponse {
+    /// Repositories whose history was walked
+    pub repositories: Vec<String>,
+    /// Commits found in those histories
+    pub commits_seen: usize,
+    /// Commits that were newly stored
+    pub commits_processed: usize,
+    /// GitHub logins the commits were attributed to
+    pub authors: Vec<String>,
+    /// Commits whose author email isn't linked to a GitHub account
+    pub unattributed_commits: usize,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ExportQuery {
+    /// Output format
+    #[serde(default)]
+    pub format: CorpusFormat,
+    /// Only export commits from this organization or user
+    pub org: Option<String>,
+    /// Only export commits from repositories with this name
+    pub repo: Option<String>,
+    /// Include commit patches
+    #[serde(default = "default_true")]
+    pub include_patches: bool,
+    /// Include embeddings
+    #[serde(default = "default_true")]
+    pub include_embeddings: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ImportQuery {
+    /// Input format
+    #[serde(default)]
+    pub format: CorpusFormat,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportResponse {
+    /// Number of commits upserted
+    pub imported: usize,
+    /// Records left out because they had no embedding and none could be generated
+    pub skipped: usize,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteRepositoryQuery {
+    /// Forge the repository is on
+    #[serde(default)]
+    pub forge: ForgeKind,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteOlderThanQuery {
+    /// Delete commits committed more than this many days ago
+    pub older_than_days: u32,
+    /// Only delete commits from this organization or user
+    pub org: Option<String>,
+    /// Only delete commits from repositories with this name
+    pub repo: Option<String>,
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateApiKeyRequest {
+    /// Who or what the key is for
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Defaults to 60
+    pub requests_per_minute: Option<u32>,
+    /// Requests allowed per UTC day; unlimited when unset
+    pub daily_quota: Option<u64>,
+}
+
+/// An API key, without its value
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    /// Start of the key, to tell keys apart
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    pub requests_per_minute: u32,
+    pub daily_quota: Option<u64>,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            scopes: key.scopes,
+            requests_per_minute: key.requests_per_minute,
+            daily_quota: key.daily_quota,
+            created_at: key.created_at,
+            revoked_at: key.revoked_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedApiKey {
+    /// The key itself, which can't be retrieved again
+    pub key: String,
+    pub info: ApiKeyInfo,
 }
@@ -0,0 +1,588 @@
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use eyre::{eyre, WrapErr};
+use hmac::{Hmac, Mac};
+use opentelemetry::{global, metrics::ObservableGauge};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, instrument};
+use utoipa::ToSchema;
+
+use crate::{
+    api::{
+        error::{AppError, AppResult, ProblemDetails},
+        process::ingest_pushed_commits,
+        types::AppState,
+    },
+    database::WebhookDelivery,
+    forge::normalize_timestamp,
+    github::{CommitAuthor, CommitInfo, Repository},
+};
+
+/// Deliveries that can wait for the worker before the endpoint stops accepting more
+const QUEUE_SIZE: usize = 256;
+/// Most commits a push payload lists; longer pushes are fetched with a comparison
+const PUSH_COMMIT_LIMIT: usize = 20;
+
+/// Commits a webhook delivery asked to be ingested
+#[derive(Debug, PartialEq)]
+pub enum WebhookJob {
+    /// Commits listed in a push payload
+    Commits {
+        repository: Repository,
+        commits: Vec<CommitInfo>,
+    },
+    /// Commits between two SHAs, for pull request events, whose payloads don't
+    /// list commits, and pushes too long for their payloads to list every commit
+    Range {
+        repository: Repository,
+        base: String,
+        head: String,
+    },
+}
+
+/// Hands accepted deliveries to the background worker
+#[derive(Debug)]
+pub struct WebhookQueue {
+    sender: mpsc::Sender<WebhookJob>,
+    /// Taken by the worker when the server starts
+    receiver: Mutex<Option<mpsc::Receiver<WebhookJob>>>,
+    /// Jobs waiting for the worker, observed when metrics are collected
+    _depth: ObservableGauge<u64>,
+}
+
+impl WebhookQueue {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let weak = sender.downgrade();
+        let depth = global::meter("github-research-rs")
+            .u64_observable_gauge("webhook.queue.depth")
+            .with_description("Webhook jobs waiting for the ingestion worker")
+            .with_callback(move |observer| {
+                if let Some(sender) = weak.upgrade() {
+                    observer.observe((QUEUE_SIZE - sender.capacity()) as u64, &[]);
+                }
+            })
+            .build();
+        Self {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            _depth: depth,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookStatus {
+    /// New commits were queued for ingestion
+    Queued,
+    /// The delivery ID was seen before
+    Duplicate,
+    /// The event has no commits to ingest
+    Ignored,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookResponse {
+    pub status: WebhookStatus,
+    pub detail: String,
+}
+
+impl WebhookResponse {
+    fn new(status: WebhookStatus, detail: impl Into<String>) -> Json<Self> {
+        Json(Self {
+            status,
+            detail: detail.into(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PayloadRepository {
+    name: String,
+    owner: PayloadAccount,
+    default_branch: String,
+}
+
+impl PayloadRepository {
+    fn into_repository(self) -> Repository {
+        Repository {
+            name: self.name,
+            owner: self.owner.login,
+            default_branch: self.default_branch,
+            commit_count: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PayloadAccount {
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    /// Previous tip of the branch
+    before: String,
+    /// New tip of the branch
+    after: String,
+    #[serde(default)]
+    created: bool,
+    #[serde(default)]
+    deleted: bool,
+    commits: Vec<PushCommit>,
+    repository: PayloadRepository,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushCommit {
+    id: String,
+    /// `false` for commits that were already pushed to another branch
+    distinct: bool,
+    message: String,
+    timestamp: String,
+    author: PushAuthor,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushAuthor {
+    name: Option<String>,
+    email: Option<String>,
+    /// GitHub account linked to the author's email, if any
+    username: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullRequestEvent {
+    action: String,
+    /// Previous head, on `synchronize`
+    before: Option<String>,
+    /// New head, on `synchronize`
+    after: Option<String>,
+    pull_request: PullRequest,
+    repository: PayloadRepository,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullRequest {
+    head: PullRequestRef,
+    base: PullRequestRef,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullRequestRef {
+    sha: String,
+}
+
+/// Receive GitHub webhook deliveries
+///
+/// Verifies `X-Hub-Signature-256` against the configured secret, ignores
+/// redeliveries and queues the commits that `push` and `pull_request` events add.
+#[utoipa::path(
+    post,
+    path = "/webhooks/github",
+    request_body(content = String, description = "GitHub webhook payload", content_type = "application/json"),
+    params(
+        ("X-GitHub-Event" = String, Header, description = "Event name, e.g. `push` or `pull_request`"),
+        ("X-GitHub-Delivery" = String, Header, description = "Unique ID of the delivery"),
+        ("X-Hub-Signature-256" = String, Header, description = "`sha256=` HMAC of the body keyed with the webhook secret")
+    ),
+    responses(
+        (status = 202, description = "Commits were queued for ingestion", body = WebhookResponse),
+        (status = 200, description = "Redelivery or event without commits to ingest", body = WebhookResponse),
+        (status = 400, description = "Missing headers or malformed payload", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid signature, or no webhook secret configured", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 500, description = "Internal server error", body = ProblemDetails, content_type = "application/problem+json"),
+        (status = 503, description = "The ingestion queue is full; the delivery isn't recorded, so it can be redelivered", body = ProblemDetails, content_type = "application/problem+json")
+    ),
+    tag = "webhooks"
+)]
+#[instrument(skip_all)]
+pub async fn github_webhook(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> AppResult<(StatusCode, Json<WebhookResponse>)> {
+    let Some(secret) = &state.config.github_webhook_secret else {
+        return Err(AppError::Unauthorized(eyre!(
+            "Webhooks are disabled; set GITHUB_WEBHOOK_SECRET to enable them"
+        )));
+    };
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let signature = header("x-hub-signature-256")
+        .ok_or_else(|| AppError::Unauthorized(eyre!("Missing X-Hub-Signature-256")))?;
+    if !verify_signature(secret.as_bytes(), &body, signature) {
+        return Err(AppError::Unauthorized(eyre!("Invalid webhook signature")));
+    }
+    let (Some(event), Some(delivery_id)) = (header("x-github-event"), header("x-github-delivery"))
+    else {
+        return Err(AppError::BadRequest(eyre!(
+            "Missing X-GitHub-Event or X-GitHub-Delivery"
+        )));
+    };
+
+    let Some(job) = job_for(event, &body).map_err(AppError::BadRequest)? else {
+        debug!("Ignoring {event} delivery {delivery_id}");
+        return Ok((
+            StatusCode::OK,
+            WebhookResponse::new(
+                WebhookStatus::Ignored,
+                format!("Nothing to ingest from {event}"),
+            ),
+        ));
+    };
+
+    // Reserved before the delivery is recorded, so one that's turned away because
+    // the queue is full isn't mistaken for a duplicate when it's redelivered
+    let permit = state.webhooks.sender.try_reserve().map_err(|e| match e {
+        TrySendError::Full(()) => AppError::Unavailable(eyre!(
+            "The webhook queue is full; redeliver {delivery_id} later"
+        )),
+        TrySendError::Closed(()) => AppError::Internal(eyre!("Webhook worker has stopped")),
+    })?;
+    let new = state
+        .db
+        .record_webhook_delivery(WebhookDelivery {
+            delivery_id: delivery_id.to_string(),
+            event: event.to_string(),
+            received_at: Utc::now(),
+        })
+        .await?;
+    if !new {
+        info!("Ignoring redelivery of {delivery_id}");
+        return Ok((
+            StatusCode::OK,
+            WebhookResponse::new(
+                WebhookStatus::Duplicate,
+                format!("Delivery {delivery_id} was already received"),
+            ),
+        ));
+    }
+
+    let detail = match &job {
+        WebhookJob::Commits {
+            repository,
+            commits,
+        } => format!(
+            "Queued {} commits in {}/{}",
+            commits.len(),
+            repository.owner,
+            repository.name
+        ),
+        WebhookJob::Range {
+            repository,
+            base,
+            head,
+        } => format!(
+            "Queued {base}...{head} in {}/{}",
+            repository.owner, repository.name
+        ),
+    };
+    permit.send(job);
+    info!("{detail} from {event} delivery {delivery_id}");
+    Ok((
+        StatusCode::ACCEPTED,
+        WebhookResponse::new(WebhookStatus::Queued, detail),
+    ))
+}
+
+/// Checks a `sha256=<hex>` signature of `body` in constant time
+fn verify_signature(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let Some(Ok(signature)) = signature.strip_prefix("sha256=").map(hex::decode) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// What to ingest for an event, or `None` when it adds no commits to the default
+/// branch or a pull request
+fn job_for(event: &str, body: &[u8]) -> eyre::Result<Option<WebhookJob>> {
+    match event {
+        "push" => {
+            let push: PushEvent =
+                serde_json::from_slice(body).wrap_err("Malformed push payload")?;
+            let default_ref = format!("refs/heads/{}", push.repository.default_branch);
+            if push.deleted || push.git_ref != default_ref {
+                return Ok(None);
+            }
+            // The payload lists only the first commits of a longer push. A new branch
+            // has no previous tip to compare with, and its commits mostly exist on
+            // other branches already.
+            if push.commits.len() >= PUSH_COMMIT_LIMIT && !push.created {
+                return Ok(Some(WebhookJob::Range {
+                    repository: push.repository.into_repository(),
+                    base: push.before,
+                    head: push.after,
+                }));
+            }
+            let commits = push
+                .commits
+                .into_iter()
+                .filter(|commit| commit.distinct)
+                .map(|commit| {
+                    Ok(CommitInfo {
+                        committed_date: normalize_timestamp(&commit.timestamp)?,
+                        message_headline: commit
+                            .message
+                            .lines()
+                            .next()
+                            .unwrap_or_default()
+                            .to_string(),
+                        author: CommitAuthor {
+                            email: commit.author.email,
+                            name: commit.author.name,
+                            login: commit.author.username,
+                        },
+                        oid: commit.id,
+                    })
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            if commits.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(WebhookJob::Commits {
+                repository: push.repository.into_repository(),
+                commits,
+            }))
+        }
+        "pull_request" => {
+            let event: PullRequestEvent =
+                serde_json::from_slice(body).wrap_err("Malformed pull_request payload")?;
+            let (base, head) = match (event.action.as_str(), event.before, event.after) {
+                ("synchronize", Some(before), Some(after)) => (before, after),
+                ("opened" | "reopened", _, _) => {
+                    (event.pull_request.base.sha, event.pull_request.head.sha)
+                }
+                _ => return Ok(None),
+            };
+            Ok(Some(WebhookJob::Range {
+                repository: event.repository.into_repository(),
+                base,
+                head,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Ingests queued webhook deliveries one at a time
+pub async fn run_worker(state: Arc<AppState>) {
+    let receiver = state
+        .webhooks
+        .receiver
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+    let Some(mut jobs) = receiver else {
+        error!("Webhook worker is already running");
+        return;
+    };
+
+    while let Some(job) = jobs.recv().await {
+        let result = match job {
+            WebhookJob::Commits {
+                repository,
+                commits,
+            } => ingest_pushed_commits(&state, &repository, commits).await,
+            WebhookJob::Range {
+                repository,
+                base,
+                head,
+            } => match state
+                .github_client
+                .compare_commits(&repository.owner, &repository.name, &base, &head)
+                .await
+            {
+                Ok(commits) => ingest_pushed_commits(&state, &repository, commits).await,
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            error!("Failed to ingest webhook commits: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, database::MongoDb};
+
+    #[test]
+    fn test_verify_signature() {
+        // Example from GitHub's webhook validation docs
+        let secret = b"It's a Secret to Everybody";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(verify_signature(secret, b"Hello, World!", signature));
+        assert!(!verify_signature(secret, b"Hello, World?", signature));
+        assert!(!verify_signature(b"wrong", b"Hello, World!", signature));
+        assert!(!verify_signature(secret, b"Hello, World!", "sha1=757107ea"));
+    }
+
+    #[test]
+    fn test_recorded_payloads() {
+        let push = include_bytes!("fixtures/webhooks/push.json");
+        let Some(WebhookJob::Commits {
+            repository,
+            commits,
+        }) = job_for("push", push).unwrap()
+        else {
+            panic!("push should queue its commits");
+        };
+        assert_eq!(repository.owner, "octo-org");
+        assert_eq!(repository.default_branch, "main");
+        // The merge was already pushed to another branch
+        let shas: Vec<&str> = commits.iter().map(|commit| commit.oid.as_str()).collect();
+        assert_eq!(
+            shas,
+            [
+                "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c",
+                "6dcb09b5b57875f334f61aebed695e2e4193db5e"
+            ]
+        );
+        assert_eq!(commits[0].message_headline, "Handle empty request bodies");
+        assert_eq!(commits[0].committed_date, "2024-05-02T07:15:03Z");
+        assert_eq!(commits[0].author.login.as_deref(), Some("octocat"));
+        assert_eq!(commits[1].author.login, None);
+
+        let branch_push =
+            String::from_utf8_lossy(push).replace("refs/heads/main", "refs/heads/wip");
+        assert_eq!(job_for("push", branch_push.as_bytes()).unwrap(), None);
+
+        let pull_request = include_bytes!("fixtures/webhooks/pull_request.json");
+        assert_eq!(
+            job_for("pull_request", pull_request).unwrap(),
+            Some(WebhookJob::Range {
+                repository: Repository {
+                    name: "hello-world".to_string(),
+                    owner: "octo-org".to_string(),
+                    default_branch: "main".to_string(),
+                    commit_count: 0,
+                },
+                base: "7638417db6d59f3c431d3e1f261cc637155684cd".to_string(),
+                head: "e5bd3914e2e596debea16f433f57875b5b90bcd6".to_string(),
+            })
+        );
+        let closed = String::from_utf8_lossy(pull_request).replace("synchronize", "closed");
+        assert_eq!(job_for("pull_request", closed.as_bytes()).unwrap(), None);
+        assert_eq!(job_for("ping", b"{}").unwrap(), None);
+    }
+
+    #[test]
+    fn test_long_push_is_compared() {
+        let mut push: serde_json::Value =
+            serde_json::from_slice(include_bytes!("fixtures/webhooks/push.json")).unwrap();
+        let commit = push["commits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|commit| commit["distinct"] == true)
+            .unwrap()
+            .clone();
+        push["commits"] = vec![commit; PUSH_COMMIT_LIMIT].into();
+        let body = serde_json::to_vec(&push).unwrap();
+
+        let Some(WebhookJob::Range { base, head, .. }) = job_for("push", &body).unwrap() else {
+            panic!("a push listing {PUSH_COMMIT_LIMIT} commits should be compared");
+        };
+        assert_eq!(base, "9049f1265b7d61be4a8904a9a27120d2064dab3b");
+        assert_eq!(head, "0d1a26e67d8f5eaf1f6ba5c57fc3c7d91ac0fd1c");
+
+        // A new branch has nothing to compare with
+        push["created"] = true.into();
+        let body = serde_json::to_vec(&push).unwrap();
+        assert!(matches!(
+            job_for("push", &body).unwrap(),
+            Some(WebhookJob::Commits { .. })
+        ));
+    }
+
+    /// Headers of a signed delivery of `body`
+    fn delivery(secret: &str, event: &str, id: &str, body: &[u8]) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-hub-signature-256", signature.parse().unwrap());
+        headers.insert("x-github-event", event.parse().unwrap());
+        headers.insert("x-github-delivery", id.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_full_queue_leaves_delivery_unrecorded() -> eyre::Result<()> {
+        let db = MongoDb::for_tests().await?;
+        let mut config = Config::for_tests("http://127.0.0.1:0");
+        config.github_webhook_secret = Some("secret".to_string());
+        let state = Arc::new(AppState::for_tests(config, db)?);
+        let body = Bytes::from_static(include_bytes!("fixtures/webhooks/push.json"));
+        let headers = delivery(
+            "secret",
+            "push",
+            "72d3162e-cc78-11e3-81ab-4c9367dc0958",
+            &body,
+        );
+
+        // No worker is running, so the queue fills up
+        let filler = || WebhookJob::Range {
+            repository: Repository {
+                name: "hello-world".to_string(),
+                owner: "octo-org".to_string(),
+                default_branch: "main".to_string(),
+                commit_count: 0,
+            },
+            base: "a".to_string(),
+            head: "b".to_string(),
+        };
+        while state.webhooks.sender.try_send(filler()).is_ok() {}
+        let error = github_webhook(State(state.clone()), headers.clone(), body.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The redelivery is queued once there's room, not taken for a duplicate
+        let take_one = || {
+            state
+                .webhooks
+                .receiver
+                .lock()
+                .unwrap()
+                .as_mut()
+                .unwrap()
+                .try_recv()
+                .unwrap()
+        };
+        take_one();
+        let (status, Json(response)) =
+            github_webhook(State(state.clone()), headers.clone(), body.clone())
+                .await
+                .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(response.status, WebhookStatus::Queued);
+
+        take_one();
+        let (status, Json(response)) = github_webhook(State(state.clone()), headers, body)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.status, WebhookStatus::Duplicate);
+
+        let state = Arc::into_inner(state).unwrap();
+        state.db.drop_for_tests().await
+    }
+}
@@ -0,0 +1,675 @@
+pub mod jsonl;
+pub mod parquet;
+
+use std::{
+    fmt,
+    fs::File,
+    future::Future,
+    io::{self, BufReader, Write},
+    mem,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use axum::body::Bytes;
+use clap::ValueEnum;
+use color_eyre::eyre::{Result, WrapErr};
+use futures::{Stream, TryStreamExt};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{debug, info, instrument, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    database::{CommitContentDocument, CommitDocument, CommitFilter, MongoDb},
+    ml::{MachineLearning, EMBEDDING_MODEL},
+};
+
+/// Bytes of output collected before an export hands them on
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Records read and written to the database at a time when importing
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// Batches an import decodes ahead of the ones being written
+const IMPORT_BATCHES_AHEAD: usize = 2;
+
+/// File format used when moving the commit corpus between environments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CorpusFormat {
+    /// Newline-delimited JSON, one `CommitDocument` per line
+    #[default]
+    Jsonl,
+    /// Apache Parquet with the embedding stored as a fixed-size list column
+    Parquet,
+}
+
+impl CorpusFormat {
+    /// Guesses the format from a file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "jsonl" | "ndjson" => Some(Self::Jsonl),
+            "parquet" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Jsonl => "application/x-ndjson",
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Jsonl => "jsonl",
+            Self::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub filter: CommitFilter,
+    pub include_patches: bool,
+    pub include_embeddings: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            filter: CommitFilter::default(),
+            include_patches: true,
+            include_embeddings: true,
+        }
+    }
+}
+
+/// Destination for exported commits
+pub trait CorpusWriter {
+    fn write(&mut self, commit: &CommitDocument) -> Result<()>;
+
+    /// Flushes buffered rows and writes any trailing metadata
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// A writer for `format`. Parquet needs the length every exported embedding has,
+/// or `None` if none of the commits has one.
+pub fn corpus_writer<'w, W: Write + Send + 'w>(
+    format: CorpusFormat,
+    options: &ExportOptions,
+    embedding_dimensions: Option<usize>,
+    output: W,
+) -> Result<Box<dyn CorpusWriter + Send + 'w>> {
+    Ok(match format {
+        CorpusFormat::Jsonl => Box::new(jsonl::JsonlWriter::new(output, options)),
+        CorpusFormat::Parquet => Box::new(parquet::ParquetWriter::new(
+            output,
+            options,
+            embedding_dimensions,
+        )?),
+    })
+}
+
+/// Output the corpus writers fill, drained in chunks while an export streams
+#[derive(Clone, Default)]
+struct ChunkBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl ChunkBuffer {
+    fn len(&self) -> usize {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn take(&self) -> Vec<u8> {
+        mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Write for ChunkBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streams commits matching `options.filter` from the database, handing the encoded
+/// output to `sink` a chunk at a time so the export is never held in memory whole.
+///
+/// Returns the number of exported commits.
+#[instrument(skip(db, sink))]
+pub async fn export_commits<S, F>(
+    db: &MongoDb,
+    format: CorpusFormat,
+    options: &ExportOptions,
+    mut sink: S,
+) -> Result<usize>
+where
+    S: FnMut(Vec<u8>) -> F + Send,
+    F: Future<Output = Result<()>> + Send,
+{
+    let embedding_dimensions = match format {
+        CorpusFormat::Parquet if options.include_embeddings => {
+            db.embedding_dimensions(&options.filter).await?
+        }
+        _ => None,
+    };
+    let buffer = ChunkBuffer::default();
+    let mut writer = corpus_writer(format, options, embedding_dimensions, buffer.clone())?;
+
+    let mut cursor = db.stream_commits(&options.filter).await?;
+    let mut count = 0;
+    while let Some(mut commit) = cursor
+        .try_next()
+        .await
+        .wrap_err("Failed to read commit from database")?
+    {
+        if !options.include_patches {
+            commit.patch.clear();
+        }
+        if !options.include_embeddings {
+            commit.embedding.clear();
+        }
+
+        writer
+            .write(&commit)
+            .wrap_err_with(|| format!("Failed to export commit {}", commit.sha))?;
+        count += 1;
+        if buffer.len() >= EXPORT_CHUNK_SIZE {
+            sink(buffer.take()).await?;
+        }
+    }
+
+    writer.finish().wrap_err("Failed to finish export")?;
+    let rest = buffer.take();
+    if !rest.is_empty() {
+        sink(rest).await?;
+    }
+    info!("Exported {count} commits as {format:?}");
+    Ok(count)
+}
+
+/// Outcome of an import
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Commits upserted
+    pub imported: usize,
+    /// Records left out because they had no embedding and none could be generated
+    pub skipped: usize,
+}
+
+/// Upserts commits read from an export file into the database, a batch at a time.
+///
+/// The file is decoded on a blocking thread, which hands the batches over as they're
+/// read so a large export neither stalls the runtime nor is held in memory whole.
+///
+/// Records exported without patches or embeddings are filled in from the shared
+/// commit content when the SHA is already known, so importing a slim export doesn't
+/// wipe existing data. Embeddings that are still missing are generated from the
+/// summary with `embedder`; records that end up without one are skipped, since
+/// search can't find them.
+#[instrument(skip(db, embedder, file))]
+pub async fn import_file(
+    db: &MongoDb,
+    embedder: Option<&MachineLearning>,
+    format: CorpusFormat,
+    file: File,
+) -> Result<ImportReport> {
+    let (sender, mut batches) = mpsc::channel(IMPORT_BATCHES_AHEAD);
+    let decoder = tokio::task::spawn_blocking(move || match format {
+        CorpusFormat::Jsonl => send_batches(jsonl::read(BufReader::new(file)), &sender),
+        CorpusFormat::Parquet => send_batches(parquet::read(file)?, &sender),
+    });
+
+    let mut report = ImportReport::default();
+    while let Some(batch) = batches.recv().await {
+        import_batch(db, embedder, batch, &mut report).await?;
+    }
+    // The batches stop early when decoding fails
+    decoder.await.wrap_err("Failed to decode the export")??;
+
+    info!(
+        "Imported {} commits, skipped {} without embeddings",
+        report.imported, report.skipped
+    );
+    Ok(report)
+}
+
+/// Sends `commits` to `batches` [`IMPORT_BATCH_SIZE`] at a time, until they run out
+/// or the import stops receiving them
+fn send_batches(
+    mut commits: impl Iterator<Item = Result<CommitDocument>>,
+    batches: &mpsc::Sender<Vec<CommitDocument>>,
+) -> Result<()> {
+    loop {
+        let batch = commits
+            .by_ref()
+            .take(IMPORT_BATCH_SIZE)
+            .collect::<Result<Vec<_>>>()?;
+        if batch.is_empty() || batches.blocking_send(batch).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn import_batch(
+    db: &MongoDb,
+    embedder: Option<&MachineLearning>,
+    batch: Vec<CommitDocument>,
+    report: &mut ImportReport,
+) -> Result<()> {
+    let shas: Vec<String> = batch.iter().map(|commit| commit.sha.clone()).collect();
+    let mut contents = db.get_commit_contents(&shas).await?;
+
+    for mut commit in batch {
+        debug!(
+            "Importing commit {}/{}@{}",
+            commit.org, commit.repo, commit.sha
+        );
+
+        let known = contents.remove(&commit.sha);
+        if let Some(content) = &known {
+            if commit.patch.is_empty() {
+                commit.patch.clone_from(&content.patch);
+            }
+            if commit.embedding.is_empty() {
+                commit.embedding.clone_from(&content.embedding);
+            }
+        }
+        if commit.embedding.is_empty() {
+            commit.embedding = match embedder {
+                Some(embedder) => embed_summary(db, embedder, &commit).await?,
+                None => Vec::new(),
+            };
+        }
+        if commit.embedding.is_empty() {
+            warn!(
+                "Skipping commit {}/{}@{}: it has no embedding",
+                commit.org, commit.repo, commit.sha
+            );
+            report.skipped += 1;
+            continue;
+        }
+
+        if known
+            .as_ref()
+            .is_none_or(|content| content.embedding.is_empty())
+        {
+            db.insert_commit_content(CommitContentDocument {
+                sha: commit.sha.clone(),
+                patch: commit.patch.clone(),
+                summary: commit.summary.clone(),
+                embedding: commit.embedding.clone(),
+                repositories: known
+                    .map(|content| content.repositories)
+                    .unwrap_or_default(),
+            })
+            .await?;
+        }
+
+        let sha = commit.sha.clone();
+        db.insert_commit(commit)
+            .await
+            .wrap_err_with(|| format!("Failed to import commit {sha}"))?;
+        report.imported += 1;
+    }
+    Ok(())
+}
+
+/// Embeds a commit's summary the way ingestion does, reusing the embedding cache
+async fn embed_summary(
+    db: &MongoDb,
+    embedder: &MachineLearning,
+    commit: &CommitDocument,
+) -> Result<Vec<f32>> {
+    let summary_json = serde_json::to_string(&commit.summary)
+        .wrap_err_with(|| format!("Failed to serialize summary of commit {}", commit.sha))?;
+    if let Some(embedding) = db
+        .get_cached_embedding(EMBEDDING_MODEL, &summary_json)
+        .await?
+    {
+        return Ok(embedding);
+    }
+    let embedding = embedder
+        .get_embedding(&summary_json)
+        .await
+        .wrap_err_with(|| format!("Failed to embed the summary of commit {}", commit.sha))?;
+    db.cache_embedding(EMBEDDING_MODEL, &summary_json, embedding.clone())
+        .await?;
+    Ok(embedding)
+}
+
+/// An upload larger than the import allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImportTooLarge {
+    pub max_bytes: u64,
+}
+
+impl fmt::Display for ImportTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The export is larger than the {} MiB that can be imported at once",
+            self.max_bytes / (1024 * 1024)
+        )
+    }
+}
+
+impl std::error::Error for ImportTooLarge {}
+
+/// Imports an export arriving in chunks, such as an upload, of at most `max_bytes`.
+///
+/// The chunks are spooled to a temporary file rather than memory, since Parquet is
+/// read starting from its footer.
+pub async fn import_stream<S, E>(
+    db: &MongoDb,
+    embedder: Option<&MachineLearning>,
+    format: CorpusFormat,
+    chunks: S,
+    max_bytes: u64,
+) -> Result<ImportReport>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let file = spool(chunks, max_bytes).await?;
+    import_file(db, embedder, format, file).await
+}
+
+/// Writes `chunks` to a temporary file, failing once more than `max_bytes` arrive
+async fn spool<S, E>(mut chunks: S, max_bytes: u64) -> Result<File>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let file = tempfile::tempfile().wrap_err("Failed to create a file to spool the import to")?;
+    let mut file = tokio::fs::File::from_std(file);
+    let mut size = 0;
+    while let Some(chunk) = chunks
+        .try_next()
+        .await
+        .wrap_err("Failed to receive the export")?
+    {
+        size += chunk.len() as u64;
+        if size > max_bytes {
+            return Err(ImportTooLarge { max_bytes }.into());
+        }
+        file.write_all(&chunk)
+            .await
+            .wrap_err("Failed to spool the export")?;
+    }
+    file.rewind().await.wrap_err("Failed to spool the export")?;
+    debug!("Spooled {size} bytes to import");
+    Ok(file.into_std().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::CommitSummary, forge::ForgeKind};
+
+    fn sample_commit(sha: &str, embedding: Vec<f32>) -> CommitDocument {
+        CommitDocument {
+            sha: sha.to_string(),
+            message: "Add parser".to_string(),
+            date: "2024-01-01T00:00:00Z".to_string(),
+            org: "octocat".to_string(),
+            repo: "Hello-World".to_string(),
+            forge: ForgeKind::GitLab,
+            author_login: Some("octocat".to_string()),
+            author_name: Some("The Octocat".to_string()),
+            author_email: None,
+            patch: "diff --git a/src/lib.rs b/src/lib.rs".to_string(),
+            summary: CommitSummary {
+                languages: vec!["Rust".to_string()],
+                frameworks_libraries: vec![],
+                patterns: vec!["Visitor".to_string(), "Builder".to_string()],
+                specialized_knowledge: vec!["Parsing".to_string()],
+            },
+            embedding,
+        }
+    }
+
+    fn export(
+        format: CorpusFormat,
+        options: &ExportOptions,
+        commits: &[CommitDocument],
+    ) -> Vec<u8> {
+        // Like the database, the first embedding found gives the dimension
+        let embedding_dimensions = commits
+            .iter()
+            .find(|commit| !commit.embedding.is_empty())
+            .map(|commit| commit.embedding.len());
+        let mut buffer = Vec::new();
+        let mut writer = corpus_writer(format, options, embedding_dimensions, &mut buffer).unwrap();
+        for commit in commits {
+            writer.write(commit).unwrap();
+        }
+        writer.finish().unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_jsonl_round_trip() {
+        let commits = vec![
+            sample_commit("a1", vec![0.1, 0.2, 0.3]),
+            sample_commit("b2", vec![0.4, 0.5, 0.6]),
+        ];
+        let bytes = export(CorpusFormat::Jsonl, &ExportOptions::default(), &commits);
+
+        let imported: Vec<_> = jsonl::read(bytes.as_slice())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported[1].sha, "b2");
+        assert_eq!(imported[1].embedding, vec![0.4, 0.5, 0.6]);
+        assert_eq!(imported[0].summary.patterns, commits[0].summary.patterns);
+    }
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let commits = vec![
+            sample_commit("a1", vec![0.1, 0.2, 0.3]),
+            sample_commit("b2", vec![]),
+            sample_commit("c3", vec![0.7, 0.8, 0.9]),
+        ];
+        let bytes = export(CorpusFormat::Parquet, &ExportOptions::default(), &commits);
+
+        let imported: Vec<_> = parquet::read(axum::body::Bytes::from(bytes))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(imported.len(), 3);
+        assert_eq!(imported[0].embedding, vec![0.1, 0.2, 0.3]);
+        assert!(imported[1].embedding.is_empty());
+        assert_eq!(imported[2].patch, commits[2].patch);
+        assert_eq!(imported[2].summary.languages, vec!["Rust"]);
+        assert!(imported[2].summary.frameworks_libraries.is_empty());
+        assert_eq!(imported[2].author_login.as_deref(), Some("octocat"));
+        assert!(imported[2].author_email.is_none());
+        assert_eq!(imported[2].forge, ForgeKind::GitLab);
+    }
+
+    #[test]
+    fn test_parquet_embeddings_after_first_batch() {
+        let mut commits: Vec<_> = (0..=parquet::BATCH_SIZE)
+            .map(|i| sample_commit(&format!("sha{i}"), vec![]))
+            .collect();
+        commits.push(sample_commit("late", vec![0.1, 0.2]));
+        let bytes = export(CorpusFormat::Parquet, &ExportOptions::default(), &commits);
+
+        let imported: Vec<_> = parquet::read(axum::body::Bytes::from(bytes))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(imported.len(), commits.len());
+        assert!(imported[0].embedding.is_empty());
+        assert_eq!(imported.last().unwrap().embedding, vec![0.1, 0.2]);
+    }
+
+    #[test]
+    fn test_parquet_embedding_column_type() {
+        let embedding_type = |commits: &[CommitDocument]| {
+            let bytes = export(CorpusFormat::Parquet, &ExportOptions::default(), commits);
+            ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(
+                axum::body::Bytes::from(bytes),
+            )
+            .unwrap()
+            .schema()
+            .field_with_name("embedding")
+            .unwrap()
+            .data_type()
+            .clone()
+        };
+
+        let commits = [
+            sample_commit("a1", vec![]),
+            sample_commit("b2", vec![0.1, 0.2]),
+        ];
+        assert!(matches!(
+            embedding_type(&commits),
+            arrow_schema::DataType::FixedSizeList(_, 2)
+        ));
+
+        // Even when a full batch without embeddings comes first
+        let mut commits: Vec<_> = (0..parquet::BATCH_SIZE)
+            .map(|i| sample_commit(&format!("sha{i}"), vec![]))
+            .collect();
+        commits.push(sample_commit("late", vec![0.1, 0.2]));
+        assert!(matches!(
+            embedding_type(&commits),
+            arrow_schema::DataType::FixedSizeList(_, 2)
+        ));
+    }
+
+    #[test]
+    fn test_parquet_rejects_mismatched_embeddings() {
+        let mut buffer = Vec::new();
+        let mut writer = corpus_writer(
+            CorpusFormat::Parquet,
+            &ExportOptions::default(),
+            Some(2),
+            &mut buffer,
+        )
+        .unwrap();
+        writer
+            .write(&sample_commit("a1", vec![0.1, 0.2, 0.3]))
+            .unwrap();
+        let err = writer.finish().unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Embedding for commit a1 has 3 dimensions, expected 2"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn test_export_without_patches_or_embeddings() {
+        let options = ExportOptions {
+            include_patches: false,
+            include_embeddings: false,
+            ..ExportOptions::default()
+        };
+        let mut commit = sample_commit("a1", vec![]);
+        commit.patch.clear();
+
+        let bytes = export(CorpusFormat::Jsonl, &options, &[commit.clone()]);
+        let line = String::from_utf8(bytes).unwrap();
+        assert!(!line.contains("\"patch\""));
+        assert!(!line.contains("\"embedding\""));
+
+        let bytes = export(CorpusFormat::Parquet, &options, &[commit]);
+        let imported: Vec<_> = parquet::read(axum::body::Bytes::from(bytes))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(imported[0].patch.is_empty());
+        assert!(imported[0].embedding.is_empty());
+    }
+
+    #[test]
+    fn test_chunked_parquet_export_matches_whole() {
+        let commits: Vec<_> = (0..3)
+            .map(|i| sample_commit(&format!("sha{i}"), vec![0.1, 0.2]))
+            .collect();
+        let whole = export(CorpusFormat::Parquet, &ExportOptions::default(), &commits);
+
+        let buffer = ChunkBuffer::default();
+        let mut writer = corpus_writer(
+            CorpusFormat::Parquet,
+            &ExportOptions::default(),
+            Some(2),
+            buffer.clone(),
+        )
+        .unwrap();
+        let mut chunked = Vec::new();
+        for commit in &commits {
+            writer.write(commit).unwrap();
+            chunked.extend(buffer.take());
+        }
+        writer.finish().unwrap();
+        chunked.extend(buffer.take());
+
+        assert_eq!(chunked, whole);
+    }
+
+    #[tokio::test]
+    async fn test_import_is_decoded_in_batches() {
+        let commits: Vec<_> = (0..=IMPORT_BATCH_SIZE)
+            .map(|i| sample_commit(&format!("sha{i}"), vec![0.1, 0.2]))
+            .collect();
+        let mut bytes = export(CorpusFormat::Jsonl, &ExportOptions::default(), &commits);
+        bytes.extend_from_slice(b"not a commit\n");
+
+        let (sender, mut batches) = mpsc::channel(IMPORT_BATCHES_AHEAD);
+        let decoder = tokio::task::spawn_blocking(move || {
+            send_batches(jsonl::read(bytes.as_slice()), &sender)
+        });
+        let mut sizes = Vec::new();
+        while let Some(batch) = batches.recv().await {
+            sizes.push(batch.len());
+        }
+        // The batch with the invalid line is never handed over
+        assert_eq!(sizes, [IMPORT_BATCH_SIZE]);
+        let err = decoder.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("line 502"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_spooled_import_is_limited() {
+        let commits = vec![sample_commit("a1", vec![0.1, 0.2, 0.3])];
+        let bytes = export(CorpusFormat::Jsonl, &ExportOptions::default(), &commits);
+        let chunks = || {
+            futures::stream::iter(
+                bytes
+                    .chunks(16)
+                    .map(|chunk| Ok::<_, io::Error>(Bytes::copy_from_slice(chunk)))
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let file = spool(chunks(), bytes.len() as u64).await.unwrap();
+        let imported: Vec<_> = jsonl::read(BufReader::new(file))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(imported[0].sha, "a1");
+
+        let err = spool(chunks(), bytes.len() as u64 - 1).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ImportTooLarge>(),
+            Some(&ImportTooLarge {
+                max_bytes: bytes.len() as u64 - 1
+            })
+        );
+    }
+}
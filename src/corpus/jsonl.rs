@@ -0,0 +1,62 @@
+use std::io::{BufRead, Write};
+
+use color_eyre::eyre::{Result, WrapErr};
+
+use crate::{
+    corpus::{CorpusWriter, ExportOptions},
+    database::CommitDocument,
+};
+
+/// Writes one JSON-encoded `CommitDocument` per line
+pub struct JsonlWriter<W: Write> {
+    output: W,
+    include_patches: bool,
+    include_embeddings: bool,
+}
+
+impl<W: Write> JsonlWriter<W> {
+    pub const fn new(output: W, options: &ExportOptions) -> Self {
+        Self {
+            output,
+            include_patches: options.include_patches,
+            include_embeddings: options.include_embeddings,
+        }
+    }
+}
+
+impl<W: Write> CorpusWriter for JsonlWriter<W> {
+    fn write(&mut self, commit: &CommitDocument) -> Result<()> {
+        let mut value = serde_json::to_value(commit).wrap_err("Failed to serialize commit")?;
+        if let Some(fields) = value.as_object_mut() {
+            if !self.include_patches {
+                fields.remove("patch");
+            }
+            if !self.include_embeddings {
+                fields.remove("embedding");
+            }
+        }
+
+        serde_json::to_writer(&mut self.output, &value).wrap_err("Failed to write commit")?;
+        self.output
+            .write_all(b"\n")
+            .wrap_err("Failed to write newline")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.output.flush().wrap_err("Failed to flush JSONL output")
+    }
+}
+
+/// Parses commits from newline-delimited JSON, skipping blank lines
+pub fn read(input: impl BufRead) -> impl Iterator<Item = Result<CommitDocument>> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|(index, line)| {
+            let line = line.wrap_err("Failed to read JSONL input")?;
+            serde_json::from_str(&line)
+                .wrap_err_with(|| format!("Invalid commit on line {}", index + 1))
+        })
+}
@@ -0,0 +1,369 @@
+use std::{io::Write, sync::Arc};
+
+use arrow_array::{
+    builder::{FixedSizeListBuilder, Float32Builder, ListBuilder, StringBuilder},
+    cast::AsArray,
+    types::Float32Type,
+    Array, ArrayRef, ListArray, RecordBatch, StringArray,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use clap::ValueEnum;
+use color_eyre::eyre::{bail, eyre, Result, WrapErr};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    basic::Compression,
+    file::{properties::WriterProperties, reader::ChunkReader},
+};
+
+use crate::{
+    corpus::{CorpusWriter, ExportOptions},
+    database::{CommitDocument, CommitSummary},
+    forge::ForgeKind,
+};
+
+/// Number of commits buffered into each record batch
+pub(super) const BATCH_SIZE: usize = 1024;
+
+const SUMMARY_COLUMNS: [&str; 4] = [
+    "languages",
+    "frameworks_libraries",
+    "patterns",
+    "specialized_knowledge",
+];
+
+/// Writes commits as Parquet row groups.
+///
+/// The embedding column is a fixed-size list of `embedding_dimensions` values, left
+/// out when embeddings aren't exported or no commit has one.
+pub struct ParquetWriter<W: Write + Send> {
+    output: Option<W>,
+    writer: Option<ArrowWriter<W>>,
+    schema: Option<SchemaRef>,
+    include_patches: bool,
+    embedding_dimensions: Option<i32>,
+    pending: Vec<CommitDocument>,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    pub fn new(
+        output: W,
+        options: &ExportOptions,
+        embedding_dimensions: Option<usize>,
+    ) -> Result<Self> {
+        let embedding_dimensions = embedding_dimensions
+            .filter(|_| options.include_embeddings)
+            .map(i32::try_from)
+            .transpose()
+            .wrap_err("Embeddings are too large")?;
+        Ok(Self {
+            output: Some(output),
+            writer: None,
+            schema: None,
+            include_patches: options.include_patches,
+            embedding_dimensions,
+            pending: Vec::new(),
+        })
+    }
+
+    fn build_schema(&self) -> Schema {
+        let mut fields = vec![
+            Field::new("sha", DataType::Utf8, false),
+            Field::new("message", DataType::Utf8, false),
+            Field::new("date", DataType::Utf8, false),
+            Field::new("org", DataType::Utf8, false),
+            Field::new("repo", DataType::Utf8, false),
+            Field::new("forge", DataType::Utf8, false),
+            Field::new("author_login", DataType::Utf8, true),
+            Field::new("author_name", DataType::Utf8, true),
+            Field::new("author_email", DataType::Utf8, true),
+        ];
+        if self.include_patches {
+            fields.push(Field::new("patch", DataType::Utf8, false));
+        }
+        for column in SUMMARY_COLUMNS {
+            fields.push(Field::new_list(
+                column,
+                Field::new_list_field(DataType::Utf8, true),
+                false,
+            ));
+        }
+        if let Some(dimensions) = self.embedding_dimensions {
+            fields.push(Field::new_fixed_size_list(
+                "embedding",
+                Field::new_list_field(DataType::Float32, true),
+                dimensions,
+                true,
+            ));
+        }
+        Schema::new(fields)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            let schema = Arc::new(self.build_schema());
+            let output = self
+                .output
+                .take()
+                .ok_or_else(|| eyre!("Parquet output already consumed"))?;
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            self.writer = Some(
+                ArrowWriter::try_new(output, schema.clone(), Some(properties))
+                    .wrap_err("Failed to create Parquet writer")?,
+            );
+            self.schema = Some(schema);
+        }
+
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let (Some(writer), Some(schema)) = (self.writer.as_mut(), self.schema.as_ref()) else {
+            bail!("Parquet writer was not initialized");
+        };
+        let batch = record_batch(schema, &self.pending)?;
+        writer
+            .write(&batch)
+            .wrap_err("Failed to write Parquet record batch")?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> CorpusWriter for ParquetWriter<W> {
+    fn write(&mut self, commit: &CommitDocument) -> Result<()> {
+        if self.embedding_dimensions.is_none() && !commit.embedding.is_empty() {
+            bail!(
+                "Commit {} has an embedding, but the export has no embedding column",
+                commit.sha
+            );
+        }
+        self.pending.push(commit.clone());
+
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush()?;
+        let writer = self
+            .writer
+            .take()
+            .ok_or_else(|| eyre!("Parquet writer was not initialized"))?;
+        writer.close().wrap_err("Failed to write Parquet footer")?;
+        Ok(())
+    }
+}
+
+fn record_batch(schema: &SchemaRef, commits: &[CommitDocument]) -> Result<RecordBatch> {
+    let string_column = |value: fn(&CommitDocument) -> &str| -> ArrayRef {
+        Arc::new(commits.iter().map(value).map(Some).collect::<StringArray>())
+    };
+    let optional_string_column = |value: fn(&CommitDocument) -> Option<&str>| -> ArrayRef {
+        Arc::new(commits.iter().map(value).collect::<StringArray>())
+    };
+
+    let mut columns = vec![
+        string_column(|commit| &commit.sha),
+        string_column(|commit| &commit.message),
+        string_column(|commit| &commit.date),
+        string_column(|commit| &commit.org),
+        string_column(|commit| &commit.repo),
+        string_column(|commit| commit.forge.as_str()),
+        optional_string_column(|commit| commit.author_login.as_deref()),
+        optional_string_column(|commit| commit.author_name.as_deref()),
+        optional_string_column(|commit| commit.author_email.as_deref()),
+    ];
+    if schema.column_with_name("patch").is_some() {
+        columns.push(string_column(|commit| &commit.patch));
+    }
+
+    let summary_fields: [fn(&CommitSummary) -> &Vec<String>; 4] = [
+        |summary| &summary.languages,
+        |summary| &summary.frameworks_libraries,
+        |summary| &summary.patterns,
+        |summary| &summary.specialized_knowledge,
+    ];
+    for field in summary_fields {
+        let mut builder = ListBuilder::new(StringBuilder::new());
+        for commit in commits {
+            for value in field(&commit.summary) {
+                builder.values().append_value(value);
+            }
+            builder.append(true);
+        }
+        columns.push(Arc::new(builder.finish()));
+    }
+
+    if let Some((_, field)) = schema.column_with_name("embedding") {
+        columns.push(embedding_column(field, commits)?);
+    }
+
+    RecordBatch::try_new(schema.clone(), columns).wrap_err("Failed to build record batch")
+}
+
+/// Embeddings as a fixed-size list column, with a null for each missing one
+fn embedding_column(field: &Field, commits: &[CommitDocument]) -> Result<ArrayRef> {
+    let DataType::FixedSizeList(_, dimensions) = field.data_type() else {
+        bail!("Embedding column is not a fixed-size list");
+    };
+    let dimensions = *dimensions;
+    let mut builder = FixedSizeListBuilder::new(Float32Builder::new(), dimensions);
+    for commit in commits {
+        if commit.embedding.is_empty() {
+            builder
+                .values()
+                .append_nulls(usize::try_from(dimensions).unwrap_or_default());
+            builder.append(false);
+            continue;
+        }
+        if i32::try_from(commit.embedding.len()).ok() != Some(dimensions) {
+            bail!(
+                "Embedding for commit {} has {} dimensions, expected {dimensions}",
+                commit.sha,
+                commit.embedding.len()
+            );
+        }
+        builder.values().append_slice(&commit.embedding);
+        builder.append(true);
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+/// Reads commits from a Parquet file written by [`ParquetWriter`].
+///
+/// `patch`, `embedding`, `forge` and author columns are optional; missing values come
+/// back empty, or as GitHub for the forge.
+pub fn read<R: ChunkReader + 'static>(
+    input: R,
+) -> Result<impl Iterator<Item = Result<CommitDocument>>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(input)
+        .wrap_err("Failed to open Parquet input")?
+        .build()
+        .wrap_err("Failed to build Parquet reader")?;
+
+    Ok(reader.flat_map(|batch| {
+        let commits = batch
+            .wrap_err("Failed to read Parquet record batch")
+            .and_then(|batch| commits_from_batch(&batch));
+        match commits {
+            Ok(commits) => commits.into_iter().map(Ok).collect::<Vec<_>>(),
+            Err(e) => vec![Err(e)],
+        }
+    }))
+}
+
+fn commits_from_batch(batch: &RecordBatch) -> Result<Vec<CommitDocument>> {
+    let sha = string_column(batch, "sha")?;
+    let message = string_column(batch, "message")?;
+    let date = string_column(batch, "date")?;
+    let org = string_column(batch, "org")?;
+    let repo = string_column(batch, "repo")?;
+    let forge = optional_string_column(batch, "forge")?;
+    let author_login = optional_string_column(batch, "author_login")?;
+    let author_name = optional_string_column(batch, "author_name")?;
+    let author_email = optional_string_column(batch, "author_email")?;
+    let patch = optional_string_column(batch, "patch")?;
+    let [languages, frameworks_libraries, patterns, specialized_knowledge] =
+        SUMMARY_COLUMNS.map(|name| list_column(batch, name));
+    let (languages, frameworks_libraries, patterns, specialized_knowledge) = (
+        languages?,
+        frameworks_libraries?,
+        patterns?,
+        specialized_knowledge?,
+    );
+    let embedding = batch
+        .column_by_name("embedding")
+        .map(|column| {
+            column
+                .as_fixed_size_list_opt()
+                .ok_or_else(|| eyre!("Column embedding is not a fixed-size list"))
+        })
+        .transpose()?;
+
+    (0..batch.num_rows())
+        .map(|row| {
+            let embedding = match embedding {
+                Some(embedding) if embedding.is_valid(row) => embedding
+                    .value(row)
+                    .as_primitive_opt::<Float32Type>()
+                    .ok_or_else(|| eyre!("Embedding values are not 32-bit floats"))?
+                    .values()
+                    .to_vec(),
+                _ => Vec::new(),
+            };
+
+            Ok(CommitDocument {
+                sha: sha.value(row).to_string(),
+                message: message.value(row).to_string(),
+                date: date.value(row).to_string(),
+                org: org.value(row).to_string(),
+                repo: repo.value(row).to_string(),
+                forge: optional_value(forge, row)
+                    .map(|forge| {
+                        ForgeKind::from_str(&forge, true)
+                            .map_err(|_| eyre!("Unknown forge {forge} in row {row}"))
+                    })
+                    .transpose()?
+                    .unwrap_or_default(),
+                author_login: optional_value(author_login, row),
+                author_name: optional_value(author_name, row),
+                author_email: optional_value(author_email, row),
+                patch: patch
+                    .map(|patch| patch.value(row).to_string())
+                    .unwrap_or_default(),
+                summary: CommitSummary {
+                    languages: list_values(languages, row)?,
+                    frameworks_libraries: list_values(frameworks_libraries, row)?,
+                    patterns: list_values(patterns, row)?,
+                    specialized_knowledge: list_values(specialized_knowledge, row)?,
+                },
+                embedding,
+            })
+        })
+        .collect()
+}
+
+fn string_column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a StringArray> {
+    batch
+        .column_by_name(name)
+        .ok_or_else(|| eyre!("Missing column {name}"))?
+        .as_string_opt::<i32>()
+        .ok_or_else(|| eyre!("Column {name} is not a string column"))
+}
+
+/// Like [`string_column`], but a missing column is `None` rather than an error
+fn optional_string_column<'a>(
+    batch: &'a RecordBatch,
+    name: &str,
+) -> Result<Option<&'a StringArray>> {
+    batch
+        .column_by_name(name)
+        .map(|_| string_column(batch, name))
+        .transpose()
+}
+
+fn optional_value(column: Option<&StringArray>, row: usize) -> Option<String> {
+    column
+        .filter(|column| column.is_valid(row))
+        .map(|column| column.value(row).to_string())
+}
+
+fn list_column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a ListArray> {
+    batch
+        .column_by_name(name)
+        .ok_or_else(|| eyre!("Missing column {name}"))?
+        .as_list_opt::<i32>()
+        .ok_or_else(|| eyre!("Column {name} is not a list column"))
+}
+
+fn list_values(list: &ListArray, row: usize) -> Result<Vec<String>> {
+    let values = list.value(row);
+    let strings = values
+        .as_string_opt::<i32>()
+        .ok_or_else(|| eyre!("List values are not strings"))?;
+    Ok(strings.iter().flatten().map(String::from).collect())
+}
@@ -1,16 +1,18 @@
-use crate::config::Config;
+use crate::{config::Config, forge::ForgeKind};
+use chrono::{DateTime, SecondsFormat, Utc};
 use color_eyre::eyre::{eyre, Result, WrapErr};
 use futures::TryStreamExt;
 use mongodb::{
     bson::{doc, Document},
-    options::ClientOptions,
-    Client, Collection,
+    options::{ClientOptions, IndexOptions, ReturnDocument},
+    Client, Collection, Cursor, IndexModel,
 };
 use serde::{Deserialize, Serialize};
-use tracing::instrument;
+use std::collections::{BTreeSet, HashMap};
+use tracing::{debug, info, instrument};
 use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
 pub struct CommitSummary {
     pub languages: Vec<String>,
     pub frameworks_libraries: Vec<String>,
@@ -20,22 +22,284 @@ pub struct CommitSummary {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReadmeDocument {
+    /// Forge the repository is on; older documents are all from GitHub
+    #[serde(default)]
+    pub forge: ForgeKind,
     pub owner: String,
     pub repo: String,
     pub content: String,
+    /// When the content was last fetched or revalidated
     pub cached_at: chrono::DateTime<chrono::Utc>,
+    /// GitHub's ETag for `content`, used to revalidate with `If-None-Match`
+    #[serde(default)]
+    pub etag: Option<String>,
+    /// Summary of `content`, shared by every commit in the repository
+    #[serde(default)]
+    pub summary: Option<ReadmeSummary>,
 }
 
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadmeSummary {
+    pub text: String,
+    /// Model that produced the summary, so a model change triggers a refresh
+    pub model: String,
+    pub generated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A commit as it appears in one repository.
+///
+/// The same SHA can show up in several repositories (forks, mirrors) and on several
+/// forges, so a commit is identified by `(sha, forge, org, repo)`. The summary and
+/// embedding are copied from the shared [`CommitContentDocument`] so searches don't
+/// need a join.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct CommitDocument {
     pub sha: String,
     pub message: String,
     pub date: String,
     pub org: String,
     pub repo: String,
+    /// Where the commit was ingested from; older documents are all from GitHub
+    #[serde(default)]
+    pub forge: ForgeKind,
+    /// Login the commit was ingested for, if known
+    #[serde(default)]
+    pub author_login: Option<String>,
+    #[serde(default)]
+    pub author_name: Option<String>,
+    #[serde(default)]
+    pub author_email: Option<String>,
+    #[serde(default)]
+    pub patch: String,
+    pub summary: CommitSummary,
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+/// Content of a commit that only depends on its SHA.
+///
+/// Summaries and embeddings are expensive to produce, so they're computed once per SHA
+/// and reused for every repository the commit appears in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitContentDocument {
+    pub sha: String,
     pub patch: String,
     pub summary: CommitSummary,
     pub embedding: Vec<f32>,
+    /// Every repository this commit has been seen in, as [`repository_key`]s
+    pub repositories: Vec<String>,
+}
+
+/// How a repository is listed in [`CommitContentDocument::repositories`]: `org/repo`
+/// on GitHub, which predates other forges, and `forge:org/repo` anywhere else
+pub fn repository_key(forge: ForgeKind, org: &str, repo: &str) -> String {
+    match forge {
+        ForgeKind::GitHub => format!("{org}/{repo}"),
+        _ => format!("{}:{org}/{repo}", forge.as_str()),
+    }
+}
+
+/// Kinds of searchable documents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentKind {
+    Commit,
+    PullRequest,
+    Review,
+    ReviewComment,
+    Issue,
+    IssueComment,
+    Discussion,
+    DiscussionComment,
+}
+
+/// Size of a pull request's diff
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct DiffStats {
+    pub additions: i64,
+    pub deletions: i64,
+    pub changed_files: i64,
+}
+
+/// A pull request, review, issue or discussion authored by a user, or a comment they
+/// left on one.
+///
+/// Summarized and embedded like a [`CommitDocument`] so it can be searched alongside
+/// commits. Identified by its GitHub node ID.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ActivityDocument {
+    pub id: String,
+    pub kind: DocumentKind,
+    pub org: String,
+    pub repo: String,
+    #[serde(default)]
+    pub author_login: Option<String>,
+    pub url: String,
+    /// Title of the pull request, issue or discussion, including the one commented on
+    pub title: String,
+    pub body: String,
+    pub date: String,
+    /// Set for pull requests
+    #[serde(default)]
+    pub diff_stats: Option<DiffStats>,
+    /// Set for pull requests
+    #[serde(default)]
+    pub merged: Option<bool>,
+    /// File a review comment is attached to
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Diff context a review comment is attached to
+    #[serde(default)]
+    pub diff_hunk: Option<String>,
+    /// Labels of the issue or discussion
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Category of the discussion
+    #[serde(default)]
+    pub category: Option<String>,
+    pub summary: CommitSummary,
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+/// Optional filters applied when listing commits.
+///
+/// Activities share the `org`, `repo`, `author_login` and `date` fields, so the same
+/// filter applies to them.
+#[derive(Debug, Clone, Default)]
+pub struct CommitFilter {
+    /// Activities only come from GitHub, so other forges match none
+    pub forge: Option<ForgeKind>,
+    pub org: Option<String>,
+    pub repo: Option<String>,
+    pub author_login: Option<String>,
+    /// Also match commits by any of these emails, e.g. ones stored without an
+    /// `author_login` by the local or push ingestion paths
+    pub author_emails: Vec<String>,
+    /// Only match commits committed before this instant
+    pub committed_before: Option<DateTime<Utc>>,
+}
+
+impl CommitFilter {
+    fn to_document(&self) -> Document {
+        let mut filter = doc! {};
+        if let Some(forge) = self.forge {
+            filter.insert("forge", forge.as_str());
+        }
+        if let Some(org) = &self.org {
+            filter.insert("org", org);
+        }
+        if let Some(repo) = &self.repo {
+            filter.insert("repo", repo);
+        }
+        match (&self.author_login, self.author_emails.as_slice()) {
+            (Some(author_login), []) => {
+                filter.insert("author_login", author_login);
+            }
+            (Some(author_login), emails) => {
+                filter.insert(
+                    "$or",
+                    vec![
+                        doc! { "author_login": author_login },
+                        doc! { "author_email": { "$in": emails } },
+                    ],
+                );
+            }
+            (None, []) => {}
+            (None, emails) => {
+                filter.insert("author_email", doc! { "$in": emails });
+            }
+        }
+        if let Some(before) = &self.committed_before {
+            // GitHub returns UTC ISO 8601 timestamps, which sort lexicographically
+            filter.insert(
+                "date",
+                doc! { "$lt": before.to_rfc3339_opts(SecondsFormat::Secs, true) },
+            );
+        }
+        filter
+    }
+}
+
+/// What a deletion removed, returned to callers and stored in the audit log
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct DeletionReport {
+    pub commits_deleted: u64,
+    /// Pull requests, reviews, issues, discussions and their comments
+    #[serde(default)]
+    pub activities_deleted: u64,
+    /// Shared commit content no longer referenced by any repository
+    pub contents_deleted: u64,
+    pub embeddings_deleted: u64,
+    pub readmes_deleted: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// e.g. `delete_user`, `delete_repository`, `delete_older_than`
+    pub action: String,
+    pub target: String,
+    /// Who asked for the change (`api`, `cli`, `retention`)
+    pub requested_by: String,
+    pub report: DeletionReport,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A webhook delivery that was accepted, kept so redeliveries are ignored
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    /// GitHub's `X-GitHub-Delivery` ID
+    pub delivery_id: String,
+    pub event: String,
+    pub received_at: DateTime<Utc>,
+}
+
+/// What an API key may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    /// `/search`
+    Search,
+    /// `/process` and its variants, which spend forge and model API budget
+    Process,
+    /// `/admin`, including managing API keys; implies every other scope
+    Admin,
+}
+
+impl ApiScope {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Search => "search",
+            Self::Process => "process",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+/// An API key, stored by the SHA-256 of its value, which is only shown when it's
+/// created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// Start of the key, to tell keys apart without revealing them
+    pub prefix: String,
+    /// Hex SHA-256 of the key
+    pub hash: String,
+    pub scopes: Vec<ApiScope>,
+    pub requests_per_minute: u32,
+    /// Requests allowed per UTC day; unlimited when unset
+    pub daily_quota: Option<u64>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes
+            .iter()
+            .any(|granted| *granted == scope || *granted == ApiScope::Admin)
+    }
 }
 
 #[derive(Debug)]
@@ -62,7 +326,130 @@ impl MongoDb {
                 "Failed to connect to MongoDB - please check your credentials and connection",
             )?;
 
-        Ok(Self { client, config })
+        let db = Self { client, config };
+        db.migrate().await?;
+        db.ensure_indexes().await?;
+        Ok(db)
+    }
+
+    /// Checks the server is reachable, for readiness probes
+    #[instrument(skip(self))]
+    pub async fn ping(&self) -> Result<()> {
+        self.client
+            .database("admin")
+            .run_command(doc! { "ping": 1 })
+            .await
+            .wrap_err("MongoDB didn't answer a ping")?;
+        Ok(())
+    }
+
+    /// Upgrades documents written by older versions. Runs on connect, before the
+    /// indexes are created.
+    #[instrument(skip(self))]
+    pub async fn migrate(&self) -> Result<()> {
+        // Documents stored before other forges were supported are all from GitHub
+        let unset = doc! { "forge": { "$exists": false } };
+        let github = doc! { "$set": { "forge": ForgeKind::GitHub.as_str() } };
+        self.get_collection()
+            .update_many(unset.clone(), github.clone())
+            .await
+            .wrap_err("Failed to record the forge of older commits")?;
+        self.get_readme_collection()
+            .update_many(unset, github)
+            .await
+            .wrap_err("Failed to record the forge of older READMEs")?;
+
+        // The old identity index would keep a repository from existing on two forges
+        let indexes = self
+            .get_collection()
+            .list_index_names()
+            .await
+            .unwrap_or_default();
+        if indexes.iter().any(|name| name == "sha_1_org_1_repo_1") {
+            info!("Replacing the commit identity index with one that includes the forge");
+            self.get_collection()
+                .drop_index("sha_1_org_1_repo_1")
+                .await
+                .wrap_err("Failed to drop the old commit identity index")?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn ensure_indexes(&self) -> Result<()> {
+        let unique = IndexOptions::builder().unique(true).build();
+
+        self.get_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "sha": 1, "forge": 1, "org": 1, "repo": 1 })
+                    .options(unique.clone())
+                    .build(),
+            )
+            .await
+            .wrap_err("Failed to create commit identity index")?;
+
+        self.get_readme_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "forge": 1, "owner": 1, "repo": 1 })
+                    .options(unique.clone())
+                    .build(),
+            )
+            .await
+            .wrap_err("Failed to create README index")?;
+
+        self.get_content_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "sha": 1 })
+                    .options(unique.clone())
+                    .build(),
+            )
+            .await
+            .wrap_err("Failed to create commit content index")?;
+
+        self.get_activity_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "id": 1 })
+                    .options(unique.clone())
+                    .build(),
+            )
+            .await
+            .wrap_err("Failed to create activity index")?;
+
+        self.get_webhook_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "delivery_id": 1 })
+                    .options(unique.clone())
+                    .build(),
+            )
+            .await
+            .wrap_err("Failed to create webhook delivery index")?;
+
+        self.get_api_key_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "hash": 1 })
+                    .options(unique.clone())
+                    .build(),
+            )
+            .await
+            .wrap_err("Failed to create API key index")?;
+
+        self.get_api_key_usage_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "key_id": 1, "day": 1 })
+                    .options(unique)
+                    .build(),
+            )
+            .await
+            .wrap_err("Failed to create API key usage index")?;
+
+        Ok(())
     }
 
     fn get_collection(&self) -> Collection<CommitDocument> {
@@ -71,53 +458,561 @@ impl MongoDb {
             .collection(&self.config.collection_name)
     }
 
+    fn get_content_collection(&self) -> Collection<CommitContentDocument> {
+        self.client
+            .database(&self.config.db_name)
+            .collection("commit_contents")
+    }
+
+    fn get_activity_collection(&self) -> Collection<ActivityDocument> {
+        self.client
+            .database(&self.config.db_name)
+            .collection("activities")
+    }
+
     fn get_readme_collection(&self) -> Collection<ReadmeDocument> {
         self.client
             .database(&self.config.db_name)
             .collection("readmes")
     }
 
+    fn get_embeddings_collection(&self) -> Collection<Document> {
+        self.client
+            .database(&self.config.db_name)
+            .collection("embeddings")
+    }
+
+    fn get_webhook_collection(&self) -> Collection<WebhookDelivery> {
+        self.client
+            .database(&self.config.db_name)
+            .collection("webhook_deliveries")
+    }
+
+    fn get_api_key_collection(&self) -> Collection<ApiKey> {
+        self.client
+            .database(&self.config.db_name)
+            .collection("api_keys")
+    }
+
+    /// Requests per API key and UTC day, as `{key_id, day, requests}`
+    fn get_api_key_usage_collection(&self) -> Collection<Document> {
+        self.client
+            .database(&self.config.db_name)
+            .collection("api_key_usage")
+    }
+
+    fn get_audit_collection(&self) -> Collection<AuditRecord> {
+        self.client
+            .database(&self.config.db_name)
+            .collection("audit_log")
+    }
+
+    /// Stores a commit for its repository and records the repository on the shared
+    /// content record.
     #[instrument(skip(self, commit))]
     pub async fn insert_commit(&self, commit: CommitDocument) -> Result<()> {
+        let filter = doc! {
+            "sha": &commit.sha,
+            "forge": commit.forge.as_str(),
+            "org": &commit.org,
+            "repo": &commit.repo
+        };
+        let repository = repository_key(commit.forge, &commit.org, &commit.repo);
+        let sha = commit.sha.clone();
+
         self.get_collection()
-            .insert_one(commit)
+            .replace_one(filter, commit)
+            .upsert(true)
             .await
             .wrap_err("Failed to insert commit into MongoDB")?;
+
+        self.get_content_collection()
+            .update_one(
+                doc! { "sha": &sha },
+                doc! { "$addToSet": { "repositories": repository } },
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to link repository to commit content {sha}"))?;
         Ok(())
     }
 
     #[instrument(skip(self))]
-    pub async fn commit_exists(&self, sha: &str) -> Result<bool> {
-        let filter = doc! { "sha": sha };
+    pub async fn commit_exists(
+        &self,
+        forge: ForgeKind,
+        sha: &str,
+        org: &str,
+        repo: &str,
+    ) -> Result<bool> {
+        let filter = doc! { "sha": sha, "forge": forge.as_str(), "org": org, "repo": repo };
         let count = self
             .get_collection()
             .count_documents(filter)
             .await
-            .wrap_err_with(|| format!("Failed to count documents for SHA: {}", sha))?;
+            .wrap_err_with(|| format!("Failed to count documents for {org}/{repo}@{sha}"))?;
         Ok(count > 0)
     }
 
+    /// Finds the GitHub login a previously ingested commit by `email` was attributed to
+    #[instrument(skip(self))]
+    pub async fn login_for_email(&self, email: &str) -> Result<Option<String>> {
+        let commit = self
+            .get_collection()
+            .find_one(doc! { "author_email": email, "author_login": { "$ne": null } })
+            .await
+            .wrap_err_with(|| format!("Failed to look up login for {email}"))?;
+        Ok(commit.and_then(|commit| commit.author_login))
+    }
+
+    /// Every email commits attributed to `login` were authored with
+    #[instrument(skip(self))]
+    pub async fn emails_for_login(&self, login: &str) -> Result<Vec<String>> {
+        let emails = self
+            .get_collection()
+            .distinct(
+                "author_email",
+                doc! { "author_login": login, "author_email": { "$ne": null } },
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to look up emails of {login}"))?;
+        Ok(emails
+            .into_iter()
+            .filter_map(|email| email.as_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Repositories with GitHub commits stored before `author_login` was recorded
+    #[instrument(skip(self))]
+    pub async fn unattributed_repositories(&self) -> Result<BTreeSet<(String, String)>> {
+        let groups: Vec<Document> = self
+            .get_collection()
+            .aggregate(vec![
+                doc! { "$match": { "author_login": null, "forge": ForgeKind::GitHub.as_str() } },
+                doc! { "$group": { "_id": { "org": "$org", "repo": "$repo" } } },
+            ])
+            .await
+            .wrap_err("Failed to find unattributed commits")?
+            .try_collect()
+            .await
+            .wrap_err("Failed to collect unattributed commits")?;
+        Ok(groups
+            .iter()
+            .filter_map(|group| {
+                let id = group.get_document("_id").ok()?;
+                Some((
+                    id.get_str("org").ok()?.to_string(),
+                    id.get_str("repo").ok()?.to_string(),
+                ))
+            })
+            .collect())
+    }
+
+    /// Attributes the commits of `org/repo` in `shas` that were stored without an
+    /// `author_login` to `login`, returning how many were updated
+    #[instrument(skip(self, shas), fields(count = shas.len()))]
+    pub async fn attribute_commits(
+        &self,
+        org: &str,
+        repo: &str,
+        shas: &[String],
+        login: &str,
+    ) -> Result<u64> {
+        let updated = self
+            .get_collection()
+            .update_many(
+                doc! {
+                    "forge": ForgeKind::GitHub.as_str(),
+                    "org": org,
+                    "repo": repo,
+                    "sha": { "$in": shas },
+                    "author_login": null,
+                },
+                doc! { "$set": { "author_login": login } },
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to attribute commits in {org}/{repo}"))?
+            .modified_count;
+        Ok(updated)
+    }
+
     #[instrument(skip(self))]
-    pub async fn get_all_commits(&self) -> Result<Vec<CommitDocument>> {
+    pub async fn get_commits(&self, filter: &CommitFilter) -> Result<Vec<CommitDocument>> {
         self.get_collection()
-            .find(doc! {})
+            .find(filter.to_document())
             .await
-            .wrap_err("Failed to find all commits")?
+            .wrap_err("Failed to find commits")?
             .try_collect()
             .await
             .wrap_err("Failed to collect commits")
     }
 
+    /// Length of the embedding of any matching commit that has one
+    #[instrument(skip(self))]
+    pub async fn embedding_dimensions(&self, filter: &CommitFilter) -> Result<Option<usize>> {
+        let mut filter = filter.to_document();
+        filter.insert("embedding.0", doc! { "$exists": true });
+        let commit = self
+            .get_collection()
+            .find_one(filter)
+            .await
+            .wrap_err("Failed to find a commit with an embedding")?;
+        Ok(commit.map(|commit| commit.embedding.len()))
+    }
+
+    /// Returns a cursor over matching commits so large result sets can be streamed
+    #[instrument(skip(self))]
+    pub async fn stream_commits(&self, filter: &CommitFilter) -> Result<Cursor<CommitDocument>> {
+        self.get_collection()
+            .find(filter.to_document())
+            .await
+            .wrap_err("Failed to find commits")
+    }
+
+    /// Deletes matching commits and activities and cascades to data nothing else
+    /// references: shared commit content, cached embeddings of its summaries and of
+    /// the activities' summaries, and READMEs of repositories left without commits
+    /// or activities.
+    #[instrument(skip(self))]
+    pub async fn delete_matching(&self, filter: &CommitFilter) -> Result<DeletionReport> {
+        // Activities have no forge of their own since they only come from GitHub
+        let activity_filter = match filter.forge {
+            None | Some(ForgeKind::GitHub) => Some(CommitFilter {
+                forge: None,
+                ..filter.clone()
+            }),
+            Some(_) => None,
+        };
+        let filter = filter.to_document();
+        let matched: Vec<Document> = self
+            .get_collection()
+            .clone_with_type::<Document>()
+            .find(filter.clone())
+            .projection(doc! { "sha": 1, "forge": 1, "org": 1, "repo": 1 })
+            .await
+            .wrap_err("Failed to find commits to delete")?
+            .try_collect()
+            .await
+            .wrap_err("Failed to collect commits to delete")?;
+
+        let mut report = DeletionReport {
+            commits_deleted: self
+                .get_collection()
+                .delete_many(filter.clone())
+                .await
+                .wrap_err("Failed to delete commits")?
+                .deleted_count,
+            ..DeletionReport::default()
+        };
+
+        let mut shas = BTreeSet::new();
+        let mut repositories = BTreeSet::new();
+        for commit in &matched {
+            let (Ok(sha), Ok(org), Ok(repo)) = (
+                commit.get_str("sha"),
+                commit.get_str("org"),
+                commit.get_str("repo"),
+            ) else {
+                continue;
+            };
+            let forge: ForgeKind = commit
+                .get("forge")
+                .and_then(|forge| mongodb::bson::from_bson(forge.clone()).ok())
+                .unwrap_or_default();
+            let key = repository_key(forge, org, repo);
+            self.get_content_collection()
+                .update_one(
+                    doc! { "sha": sha },
+                    doc! { "$pull": { "repositories": &key } },
+                )
+                .await
+                .wrap_err_with(|| format!("Failed to unlink {key} from {sha}"))?;
+            shas.insert(sha.to_string());
+            repositories.insert((forge, org.to_string(), repo.to_string()));
+        }
+
+        // Content and embeddings only go once no repository references the SHA
+        let orphan_filter = doc! {
+            "sha": { "$in": shas.iter().collect::<Vec<_>>() },
+            "repositories": { "$size": 0 }
+        };
+        let orphans: Vec<CommitContentDocument> = self
+            .get_content_collection()
+            .find(orphan_filter.clone())
+            .await
+            .wrap_err("Failed to find orphaned commit content")?
+            .try_collect()
+            .await
+            .wrap_err("Failed to collect orphaned commit content")?;
+        let embedding_inputs = orphans
+            .iter()
+            .map(|content| serde_json::to_string(&content.summary))
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err("Failed to serialize summary")?;
+
+        report.embeddings_deleted = self
+            .get_embeddings_collection()
+            .delete_many(doc! { "input": { "$in": embedding_inputs } })
+            .await
+            .wrap_err("Failed to delete cached embeddings")?
+            .deleted_count;
+        report.contents_deleted = self
+            .get_content_collection()
+            .delete_many(orphan_filter)
+            .await
+            .wrap_err("Failed to delete orphaned commit content")?
+            .deleted_count;
+
+        if let Some(activity_filter) = activity_filter {
+            let activity_filter = activity_filter.to_document();
+            let activities: Vec<Document> = self
+                .get_activity_collection()
+                .clone_with_type::<Document>()
+                .find(activity_filter.clone())
+                .projection(doc! { "org": 1, "repo": 1, "summary": 1 })
+                .await
+                .wrap_err("Failed to find activities to delete")?
+                .try_collect()
+                .await
+                .wrap_err("Failed to collect activities to delete")?;
+            repositories.extend(activities.iter().filter_map(|activity| {
+                Some((
+                    ForgeKind::GitHub,
+                    activity.get_str("org").ok()?.to_string(),
+                    activity.get_str("repo").ok()?.to_string(),
+                ))
+            }));
+
+            // Activities are embedded through the same cache as commit summaries
+            let embedding_inputs = activities
+                .iter()
+                .filter_map(|activity| activity.get("summary"))
+                .map(|summary| {
+                    let summary: CommitSummary = mongodb::bson::from_bson(summary.clone())
+                        .wrap_err("Failed to read activity summary")?;
+                    serde_json::to_string(&summary).wrap_err("Failed to serialize summary")
+                })
+                .collect::<Result<Vec<_>>>()?;
+            report.embeddings_deleted += self
+                .get_embeddings_collection()
+                .delete_many(doc! { "input": { "$in": embedding_inputs } })
+                .await
+                .wrap_err("Failed to delete cached activity embeddings")?
+                .deleted_count;
+            report.activities_deleted = self
+                .get_activity_collection()
+                .delete_many(activity_filter)
+                .await
+                .wrap_err("Failed to delete activities")?
+                .deleted_count;
+        }
+
+        for (forge, org, repo) in repositories {
+            let repository = doc! { "org": &org, "repo": &repo };
+            let mut remaining = self
+                .get_collection()
+                .count_documents(doc! { "forge": forge.as_str(), "org": &org, "repo": &repo })
+                .await
+                .wrap_err_with(|| format!("Failed to count commits for {org}/{repo}"))?;
+            if forge == ForgeKind::GitHub {
+                remaining += self
+                    .get_activity_collection()
+                    .count_documents(repository)
+                    .await
+                    .wrap_err_with(|| format!("Failed to count activities for {org}/{repo}"))?;
+            }
+            if remaining > 0 {
+                continue;
+            }
+            debug!(
+                "Nothing left for {org}/{repo} on {}, deleting cached README",
+                forge.as_str()
+            );
+            report.readmes_deleted += self
+                .get_readme_collection()
+                .delete_one(doc! { "forge": forge.as_str(), "owner": &org, "repo": &repo })
+                .await
+                .wrap_err_with(|| format!("Failed to delete README for {org}/{repo}"))?
+                .deleted_count;
+        }
+
+        info!("Deleted {report:?}");
+        Ok(report)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn activity_exists(&self, id: &str) -> Result<bool> {
+        let count = self
+            .get_activity_collection()
+            .count_documents(doc! { "id": id })
+            .await
+            .wrap_err_with(|| format!("Failed to count activities with ID {id}"))?;
+        Ok(count > 0)
+    }
+
+    #[instrument(skip(self, activity))]
+    pub async fn insert_activity(&self, activity: ActivityDocument) -> Result<()> {
+        let filter = doc! { "id": &activity.id };
+        self.get_activity_collection()
+            .replace_one(filter, activity)
+            .upsert(true)
+            .await
+            .wrap_err("Failed to insert activity into MongoDB")?;
+        Ok(())
+    }
+
+    /// Lists matching activities, optionally only of one kind
+    #[instrument(skip(self))]
+    pub async fn get_activities(
+        &self,
+        filter: &CommitFilter,
+        kind: Option<DocumentKind>,
+    ) -> Result<Vec<ActivityDocument>> {
+        let mut filter = filter.to_document();
+        if let Some(kind) = kind {
+            filter.insert(
+                "kind",
+                mongodb::bson::to_bson(&kind).wrap_err("Failed to encode kind")?,
+            );
+        }
+        self.get_activity_collection()
+            .find(filter)
+            .await
+            .wrap_err("Failed to find activities")?
+            .try_collect()
+            .await
+            .wrap_err("Failed to collect activities")
+    }
+
+    /// Records a webhook delivery, returning `false` if it was already recorded
+    #[instrument(skip(self, delivery), fields(delivery_id = %delivery.delivery_id))]
+    pub async fn record_webhook_delivery(&self, delivery: WebhookDelivery) -> Result<bool> {
+        let fields =
+            mongodb::bson::to_document(&delivery).wrap_err("Failed to encode webhook delivery")?;
+        let result = self
+            .get_webhook_collection()
+            .update_one(
+                doc! { "delivery_id": &delivery.delivery_id },
+                doc! { "$setOnInsert": fields },
+            )
+            .upsert(true)
+            .await
+            .wrap_err("Failed to record webhook delivery")?;
+        Ok(result.upserted_id.is_some())
+    }
+
+    #[instrument(skip(self, key), fields(id = %key.id))]
+    pub async fn insert_api_key(&self, key: ApiKey) -> Result<()> {
+        self.get_api_key_collection()
+            .insert_one(key)
+            .await
+            .wrap_err("Failed to store API key")?;
+        Ok(())
+    }
+
+    #[instrument(skip(self, hash))]
+    pub async fn find_api_key(&self, hash: &str) -> Result<Option<ApiKey>> {
+        self.get_api_key_collection()
+            .find_one(doc! { "hash": hash })
+            .await
+            .wrap_err("Failed to look up API key")
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        self.get_api_key_collection()
+            .find(doc! {})
+            .sort(doc! { "created_at": 1 })
+            .await
+            .wrap_err("Failed to list API keys")?
+            .try_collect()
+            .await
+            .wrap_err("Failed to read API keys")
+    }
+
+    /// Marks a key revoked, returning it, or `None` if there's no such key
+    #[instrument(skip(self))]
+    pub async fn revoke_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        let now = mongodb::bson::to_bson(&Utc::now())?;
+        self.get_api_key_collection()
+            .find_one_and_update(doc! { "id": id }, doc! { "$set": { "revoked_at": now } })
+            .return_document(ReturnDocument::After)
+            .await
+            .wrap_err_with(|| format!("Failed to revoke API key {id}"))
+    }
+
+    /// Counts a request against a key's quota for `day`, returning how many it has
+    /// made that day
+    #[instrument(skip(self))]
+    pub async fn count_api_key_request(&self, key_id: &str, day: &str) -> Result<u64> {
+        let usage = self
+            .get_api_key_usage_collection()
+            .find_one_and_update(
+                doc! { "key_id": key_id, "day": day },
+                doc! { "$inc": { "requests": 1_i64 } },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await
+            .wrap_err("Failed to count API key request")?
+            .ok_or_else(|| eyre!("API key usage upsert returned nothing"))?;
+        Ok(usage.get_i64("requests").unwrap_or_default().max(0) as u64)
+    }
+
+    #[instrument(skip(self, record))]
+    pub async fn insert_audit_record(&self, record: AuditRecord) -> Result<()> {
+        self.get_audit_collection()
+            .insert_one(record)
+            .await
+            .wrap_err("Failed to write audit record")?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_commit_content(&self, sha: &str) -> Result<Option<CommitContentDocument>> {
+        self.get_content_collection()
+            .find_one(doc! { "sha": sha })
+            .await
+            .wrap_err_with(|| format!("Failed to find commit content for SHA: {sha}"))
+    }
+
+    /// Shared content of every commit in `shas` that has some, by SHA
+    #[instrument(skip_all, fields(count = shas.len()))]
+    pub async fn get_commit_contents(
+        &self,
+        shas: &[String],
+    ) -> Result<HashMap<String, CommitContentDocument>> {
+        let contents: Vec<CommitContentDocument> = self
+            .get_content_collection()
+            .find(doc! { "sha": { "$in": shas } })
+            .await
+            .wrap_err("Failed to find commit contents")?
+            .try_collect()
+            .await
+            .wrap_err("Failed to collect commit contents")?;
+        Ok(contents
+            .into_iter()
+            .map(|content| (content.sha.clone(), content))
+            .collect())
+    }
+
+    #[instrument(skip(self, content))]
+    pub async fn insert_commit_content(&self, content: CommitContentDocument) -> Result<()> {
+        let filter = doc! { "sha": &content.sha };
+        self.get_content_collection()
+            .replace_one(filter, content)
+            .upsert(true)
+            .await
+            .wrap_err("Failed to insert commit content into MongoDB")?;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn get_cached_embedding(
         &self,
         model: &str,
         input_text: &str,
     ) -> Result<Option<Vec<f32>>> {
-        let embeddings_collection: Collection<Document> = self
-            .client
-            .database(&self.config.db_name)
-            .collection("embeddings");
+        let embeddings_collection = self.get_embeddings_collection();
         let filter = doc! {
             "model": model,
             "input": input_text
@@ -153,10 +1048,7 @@ impl MongoDb {
         input_text: &str,
         embedding: Vec<f32>,
     ) -> Result<()> {
-        let embeddings_collection: Collection<Document> = self
-            .client
-            .database(&self.config.db_name)
-            .collection("embeddings");
+        let embeddings_collection = self.get_embeddings_collection();
         let doc = doc! {
             "model": model,
             "input": input_text,
@@ -172,10 +1064,12 @@ impl MongoDb {
     #[instrument(skip(self))]
     pub async fn get_cached_readme(
         &self,
+        forge: ForgeKind,
         owner: &str,
         repo: &str,
     ) -> Result<Option<ReadmeDocument>> {
         let filter = doc! {
+            "forge": forge.as_str(),
             "owner": owner,
             "repo": repo
         };
@@ -186,9 +1080,91 @@ impl MongoDb {
             .wrap_err_with(|| format!("Failed to find cached README for {owner}/{repo}"))
     }
 
+    /// Marks a cached README as still current without replacing its content, so data
+    /// derived from it stays valid
+    #[instrument(skip(self))]
+    pub async fn touch_readme(&self, forge: ForgeKind, owner: &str, repo: &str) -> Result<()> {
+        // Encoded the same way serde encodes `ReadmeDocument::cached_at`
+        let cached_at =
+            mongodb::bson::to_bson(&Utc::now()).wrap_err("Failed to encode timestamp")?;
+        self.get_readme_collection()
+            .update_one(
+                doc! { "forge": forge.as_str(), "owner": owner, "repo": repo },
+                doc! { "$set": { "cached_at": cached_at } },
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to refresh cached README for {owner}/{repo}"))?;
+        Ok(())
+    }
+
+    /// Stores a summary for the cached README, as long as its content is still `content`
+    #[instrument(skip(self, content, summary))]
+    pub async fn cache_readme_summary(
+        &self,
+        forge: ForgeKind,
+        owner: &str,
+        repo: &str,
+        content: &str,
+        summary: &ReadmeSummary,
+    ) -> Result<()> {
+        let summary =
+            mongodb::bson::to_bson(summary).wrap_err("Failed to encode README summary")?;
+        self.get_readme_collection()
+            .update_one(
+                doc! { "forge": forge.as_str(), "owner": owner, "repo": repo, "content": content },
+                doc! { "$set": { "summary": summary } },
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to cache README summary for {owner}/{repo}"))?;
+        Ok(())
+    }
+
+    /// Caches freshly fetched README content, keeping the stored summary when the
+    /// content is unchanged whatever its ETag, so each version is summarized once
+    #[instrument(skip(self, content))]
+    pub async fn store_readme(
+        &self,
+        forge: ForgeKind,
+        owner: &str,
+        repo: &str,
+        content: String,
+        etag: Option<String>,
+    ) -> Result<ReadmeDocument> {
+        let cached = self.get_cached_readme(forge, owner, repo).await?;
+        if let Some(mut cached) = cached.filter(|cached| cached.content == content) {
+            cached.cached_at = Utc::now();
+            cached.etag = etag;
+            let cached_at =
+                mongodb::bson::to_bson(&cached.cached_at).wrap_err("Failed to encode timestamp")?;
+            self.get_readme_collection()
+                .update_one(
+                    doc! { "forge": forge.as_str(), "owner": owner, "repo": repo, "content": &cached.content },
+                    doc! { "$set": { "cached_at": cached_at, "etag": cached.etag.clone() } },
+                )
+                .await
+                .wrap_err_with(|| format!("Failed to refresh cached README for {owner}/{repo}"))?;
+            return Ok(cached);
+        }
+
+        let readme = ReadmeDocument {
+            forge,
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+            content,
+            cached_at: Utc::now(),
+            etag,
+            summary: None,
+        };
+        self.cache_readme(&readme).await?;
+        Ok(readme)
+    }
+
+    /// Replaces the cached README for a repository, dropping anything derived from
+    /// the previous content
     #[instrument(skip(self, readme))]
-    pub async fn cache_readme(&self, readme: ReadmeDocument) -> Result<()> {
+    pub async fn cache_readme(&self, readme: &ReadmeDocument) -> Result<()> {
         let filter = doc! {
+            "forge": readme.forge.as_str(),
             "owner": &readme.owner,
             "repo": &readme.repo
         };
@@ -201,3 +1177,67 @@ impl MongoDb {
         Ok(())
     }
 }
+
+#[cfg(test)]
+impl MongoDb {
+    /// A database of its own on the server at `TEST_MONGO_URI`. Tests that need it
+    /// are `#[ignore = "needs TEST_MONGO_URI"]`, and fail when run without it.
+    pub async fn for_tests() -> Result<Self> {
+        let uri = std::env::var("TEST_MONGO_URI")
+            .wrap_err("TEST_MONGO_URI must point at a MongoDB server to run this test")?;
+        let mut config = Config::for_tests("http://127.0.0.1:0");
+        config.mongo_uri = uri;
+        config.db_name = format!("commit_db_test_{}", uuid::Uuid::new_v4().simple());
+        Self::new(config).await
+    }
+
+    /// Drops a database created by [`MongoDb::for_tests`]
+    pub async fn drop_for_tests(self) -> Result<()> {
+        self.client
+            .database(&self.config.db_name)
+            .drop()
+            .await
+            .wrap_err("Failed to drop test database")
+    }
+
+    /// Stores a commit exactly as given, e.g. in the shape older versions wrote
+    pub async fn insert_raw_commit(&self, commit: Document) -> Result<()> {
+        self.get_collection()
+            .clone_with_type::<Document>()
+            .insert_one(commit)
+            .await
+            .wrap_err("Failed to insert raw commit")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_author_filter_matches_login_or_emails() {
+        let filter = CommitFilter {
+            author_login: Some("octocat".to_string()),
+            ..CommitFilter::default()
+        };
+        assert_eq!(filter.to_document(), doc! { "author_login": "octocat" });
+
+        let filter = CommitFilter {
+            org: Some("octocat".to_string()),
+            author_login: Some("octocat".to_string()),
+            author_emails: vec!["octocat@nowhere.com".to_string()],
+            ..CommitFilter::default()
+        };
+        assert_eq!(
+            filter.to_document(),
+            doc! {
+                "org": "octocat",
+                "$or": [
+                    { "author_login": "octocat" },
+                    { "author_email": { "$in": ["octocat@nowhere.com"] } },
+                ],
+            }
+        );
+    }
+}
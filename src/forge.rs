@@ -0,0 +1,237 @@
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use clap::ValueEnum;
+use eyre::{Context, Result};
+use reqwest::{
+    header::RETRY_AFTER, Certificate, Client, NoProxy, Proxy, RequestBuilder, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+mod gitea;
+mod gitlab;
+
+pub use gitea::GiteaClient;
+pub use gitlab::GitLabClient;
+
+use crate::{
+    config::Config,
+    database::{MongoDb, ReadmeDocument},
+    github::{CommitInfo, ContributionPeriod, Repository},
+};
+
+/// Largest page the GitLab and Gitea REST APIs return
+const PAGE_SIZE: usize = 50;
+
+/// Where a commit was ingested from
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    Serialize,
+    Deserialize,
+    ValueEnum,
+    ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+#[value(rename_all = "lower")]
+pub enum ForgeKind {
+    #[default]
+    GitHub,
+    GitLab,
+    /// Gitea or Forgejo
+    Gitea,
+    /// A local clone read directly with git
+    Local,
+}
+
+impl ForgeKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::GitHub => "github",
+            Self::GitLab => "gitlab",
+            Self::Gitea => "gitea",
+            Self::Local => "local",
+        }
+    }
+}
+
+/// A code hosting service commits can be ingested from
+#[async_trait]
+pub trait Forge: Send + Sync {
+    fn kind(&self) -> ForgeKind;
+
+    /// Lists the repositories `username` committed to during `period`
+    async fn contributed_repos(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<Repository>>;
+
+    /// Lists the commits `username` made on the default branch of `repo` during
+    /// `period`
+    async fn user_commits(
+        &self,
+        repo: &Repository,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<CommitInfo>>;
+
+    /// Unified diff of a commit against its first parent
+    async fn commit_patch(&self, owner: &str, repo: &str, sha: &str) -> Result<String>;
+
+    /// The repository's README, through the database cache
+    async fn readme(&self, owner: &str, repo: &str, db: &MongoDb)
+        -> Result<Option<ReadmeDocument>>;
+}
+
+/// Failures of a forge API callers react to differently, recovered from an
+/// `eyre::Report` with `downcast_ref`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForgeError {
+    /// The user, repository or commit doesn't exist
+    NotFound(String),
+    /// Every credential has used up its rate limit for `resource`
+    RateLimited {
+        resource: String,
+        reset_at: Option<DateTime<Utc>>,
+    },
+    /// The API answered with an unexpected error, described by `message`
+    Upstream { status: u16, message: String },
+}
+
+impl std::fmt::Display for ForgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(what) => write!(f, "{what} not found"),
+            Self::RateLimited {
+                resource,
+                reset_at: Some(reset_at),
+            } => write!(
+                f,
+                "Rate limit for {resource} used up; it resets at {}",
+                reset_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ),
+            Self::RateLimited { resource, .. } => write!(f, "Rate limit for {resource} used up"),
+            Self::Upstream { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ForgeError {}
+
+/// Builds the HTTP client forge APIs are called with, trusting the extra CA
+/// certificates and using the proxy from `config`
+pub fn http_client(config: &Config) -> Result<Client> {
+    let mut builder = Client::builder();
+    if let Some(path) = &config.forge_ca_cert {
+        let pem = std::fs::read(path)
+            .wrap_err_with(|| format!("Failed to read CA certificates from {}", path.display()))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .wrap_err_with(|| format!("Invalid PEM certificates in {}", path.display()))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(proxy) = &config.forge_proxy {
+        let proxy = Proxy::all(proxy)
+            .wrap_err_with(|| format!("Invalid proxy {proxy}"))?
+            .no_proxy(NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    builder.build().wrap_err("Failed to create HTTP client")
+}
+
+/// Sends a REST request, turning unsuccessful responses into errors
+async fn send(request: RequestBuilder, what: &str) -> Result<Response> {
+    let response = request
+        .header("User-Agent", "github-research-rs")
+        .send()
+        .await
+        .wrap_err_with(|| format!("Failed to request {what}"))?;
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND {
+        return Err(ForgeError::NotFound(what.to_string()).into());
+    }
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let reset_at = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map(|seconds| Utc::now() + TimeDelta::seconds(seconds));
+        let error = ForgeError::RateLimited {
+            resource: what.to_string(),
+            reset_at,
+        };
+        return Err(error.into());
+    }
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        let error = ForgeError::Upstream {
+            status: status.as_u16(),
+            message: format!("Failed to fetch {what}: Status: {status}, Body: {body}"),
+        };
+        return Err(error.into());
+    }
+    Ok(response)
+}
+
+/// Returns the cached README for a repository while it's younger than `ttl`
+async fn fresh_readme(
+    db: &MongoDb,
+    forge: ForgeKind,
+    owner: &str,
+    repo: &str,
+    ttl: Duration,
+) -> Result<Option<ReadmeDocument>> {
+    let ttl = TimeDelta::from_std(ttl).unwrap_or(TimeDelta::MAX);
+    Ok(db
+        .get_cached_readme(forge, owner, repo)
+        .await?
+        .filter(|cached| Utc::now() - cached.cached_at < ttl))
+}
+
+/// Converts a REST API timestamp to the UTC, whole-second form GitHub uses
+pub fn normalize_timestamp(timestamp: &str) -> Result<String> {
+    let parsed = DateTime::parse_from_rfc3339(timestamp)
+        .wrap_err_with(|| format!("Invalid timestamp {timestamp}"))?;
+    Ok(parsed.to_utc().to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// Start and end of `period` as REST `since`/`until` parameters
+fn period_bounds(period: &ContributionPeriod) -> Vec<(&'static str, String)> {
+    let (_, until) = period.commit_bounds();
+    let mut query = Vec::new();
+    if let Some(since) = period.start() {
+        query.push(("since", since.to_rfc3339()));
+    }
+    if let Some(until) = until {
+        query.push(("until", until.to_rfc3339()));
+    }
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_timestamp() {
+        assert_eq!(
+            normalize_timestamp("2012-09-20T09:06:12.000+03:00").unwrap(),
+            "2012-09-20T06:06:12Z"
+        );
+        assert_eq!(
+            normalize_timestamp("2024-01-01T00:00:00Z").unwrap(),
+            "2024-01-01T00:00:00Z"
+        );
+        assert!(normalize_timestamp("yesterday").is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use async_trait::async_trait;
+use eyre::{Context, Result};
+use reqwest::{Client, Response};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::time::Duration;
+use tracing::{debug, instrument};
+
+use super::{
+    fresh_readme, http_client, normalize_timestamp, period_bounds, send, Forge, ForgeKind,
+    PAGE_SIZE,
+};
+use crate::{
+    config::Config,
+    database::{MongoDb, ReadmeDocument},
+    github::{CommitAuthor, CommitInfo, ContributionPeriod, Repository},
+};
+
+/// Client for the Gitea REST API (v1), which Forgejo and Codeberg serve as well
+#[derive(Debug, Clone)]
+pub struct GiteaClient {
+    client: Client,
+    /// e.g. `https://codeberg.org/api/v1`
+    api_url: String,
+    token: Option<String>,
+    readme_cache_ttl: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResults {
+    data: Vec<Repo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Repo {
+    name: String,
+    owner: Owner,
+    default_branch: String,
+    #[serde(default)]
+    empty: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Owner {
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+    sha: String,
+    commit: CommitDetails,
+    /// Account linked to the author's email, if any
+    author: Option<Owner>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitDetails {
+    message: String,
+    author: Signature,
+    committer: Signature,
+}
+
+#[derive(Debug, Deserialize)]
+struct Signature {
+    name: Option<String>,
+    email: Option<String>,
+    date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentEntry {
+    name: String,
+    path: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl GiteaClient {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            client: http_client(config)?,
+            api_url: format!("{}/api/v1", config.gitea_url.trim_end_matches('/')),
+            token: config.gitea_token.clone(),
+            readme_cache_ttl: config.readme_cache_ttl,
+        })
+    }
+
+    async fn get(&self, path: &str, query: &[(&str, String)], what: &str) -> Result<Response> {
+        let mut request = self
+            .client
+            .get(format!("{}{path}", self.api_url))
+            .query(query);
+        if let Some(token) = &self.token {
+            request = request.header("Authorization", format!("token {token}"));
+        }
+        send(request, what).await
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+        what: &str,
+    ) -> Result<T> {
+        self.get(path, query, what)
+            .await?
+            .json()
+            .await
+            .wrap_err_with(|| format!("Unexpected response for {what}"))
+    }
+
+    /// Fetches pages until one comes back short; `page` extracts a page's items
+    async fn get_all<P: DeserializeOwned, T>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+        what: &str,
+        page: impl Fn(P) -> Vec<T>,
+    ) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        for number in 1.. {
+            let mut page_query = query.to_vec();
+            page_query.push(("limit", PAGE_SIZE.to_string()));
+            page_query.push(("page", number.to_string()));
+            let page_items = page(self.get_json(path, &page_query, what).await?);
+            let done = page_items.len() < PAGE_SIZE;
+            items.extend(page_items);
+            if done {
+                break;
+            }
+        }
+        Ok(items)
+    }
+}
+
+/// Converts a commit, or returns `None` if it wasn't authored by `username`
+fn commit_info(commit: Commit, username: &str) -> Option<Result<CommitInfo>> {
+    let login = commit.author?.login;
+    if !login.eq_ignore_ascii_case(username) {
+        return None;
+    }
+    let details = commit.commit;
+    Some(
+        normalize_timestamp(&details.committer.date).map(|committed_date| CommitInfo {
+            oid: commit.sha,
+            message_headline: details
+                .message
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            committed_date,
+            author: CommitAuthor {
+                email: details.author.email,
+                name: details.author.name,
+                login: Some(login),
+            },
+        }),
+    )
+}
+
+#[async_trait]
+impl Forge for GiteaClient {
+    fn kind(&self) -> ForgeKind {
+        ForgeKind::Gitea
+    }
+
+    /// Gitea's repository search returns the repositories a user owns or
+    /// contributed to, without dates, so the period is applied to their commits
+    #[instrument(skip(self))]
+    async fn contributed_repos(
+        &self,
+        username: &str,
+        _period: &ContributionPeriod,
+    ) -> Result<Vec<Repository>> {
+        let user: User = self
+            .get_json(
+                &format!("/users/{username}"),
+                &[],
+                &format!("Gitea user {username}"),
+            )
+            .await?;
+        let repos: Vec<Repository> = self
+            .get_all(
+                "/repos/search",
+                &[("uid", user.id.to_string())],
+                &format!("repositories of {username}"),
+                |results: SearchResults| results.data,
+            )
+            .await?
+            .into_iter()
+            .filter(|repo| !repo.empty)
+            .map(|repo| Repository {
+                name: repo.name,
+                owner: repo.owner.login,
+                default_branch: repo.default_branch,
+                commit_count: 0,
+            })
+            .collect();
+        debug!("Found {} Gitea repositories for {username}", repos.len());
+        Ok(repos)
+    }
+
+    /// The commit listing can't be filtered by author, so the history in `period` is
+    /// walked and matched against the account each commit is linked to
+    #[instrument(skip(self))]
+    async fn user_commits(
+        &self,
+        repo: &Repository,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<CommitInfo>> {
+        let mut query = period_bounds(period);
+        query.push(("sha", repo.default_branch.clone()));
+        query.push(("stat", "false".to_string()));
+        query.push(("verification", "false".to_string()));
+        query.push(("files", "false".to_string()));
+
+        let commits = self
+            .get_all(
+                &format!("/repos/{}/{}/commits", repo.owner, repo.name),
+                &query,
+                &format!("commits in {}/{}", repo.owner, repo.name),
+                |commits: Vec<Commit>| commits,
+            )
+            .await?;
+        commits
+            .into_iter()
+            .filter_map(|commit| commit_info(commit, username))
+            // Older Gitea versions ignore `since`/`until`
+            .filter(|commit| {
+                commit
+                    .as_ref()
+                    .map_or(true, |commit| period.contains(&commit.committed_date))
+            })
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn commit_patch(&self, owner: &str, repo: &str, sha: &str) -> Result<String> {
+        let patch = self
+            .get(
+                &format!("/repos/{owner}/{repo}/git/commits/{sha}.diff"),
+                &[],
+                &format!("diff of commit {sha} in {owner}/{repo}"),
+            )
+            .await?
+            .text()
+            .await?;
+        Ok(patch)
+    }
+
+    #[instrument(skip(self, db))]
+    async fn readme(
+        &self,
+        owner: &str,
+        repo: &str,
+        db: &MongoDb,
+    ) -> Result<Option<ReadmeDocument>> {
+        if let Some(cached) =
+            fresh_readme(db, ForgeKind::Gitea, owner, repo, self.readme_cache_ttl).await?
+        {
+            debug!("Using cached README for {owner}/{repo}");
+            return Ok(Some(cached));
+        }
+
+        // Both default to the repository's default branch
+        let entries: Vec<ContentEntry> = self
+            .get_json(
+                &format!("/repos/{owner}/{repo}/contents"),
+                &[],
+                &format!("files in {owner}/{repo}"),
+            )
+            .await?;
+        let Some(entry) = entries.into_iter().find(|entry| {
+            entry.kind == "file" && entry.name.to_ascii_lowercase().starts_with("readme")
+        }) else {
+            return Ok(None);
+        };
+
+        let content = self
+            .get(
+                &format!("/repos/{owner}/{repo}/raw/{}", entry.path),
+                &[],
+                &format!("README of {owner}/{repo}"),
+            )
+            .await?
+            .text()
+            .await?;
+        Ok(Some(
+            db.store_readme(ForgeKind::Gitea, owner, repo, content, None)
+                .await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{github::fake::FakeGitHub, github::parse_date};
+
+    const REPO: &str = "/api/v1/repos/octocat/hello-world";
+
+    async fn fake_gitea() -> Result<(FakeGitHub, GiteaClient)> {
+        let gitea = FakeGitHub::start_forge(ForgeKind::Gitea, "octocat").await?;
+        let mut config = gitea.config();
+        config.gitea_token = Some("gitea-test".to_string());
+        let client = GiteaClient::new(&config)?;
+        Ok((gitea, client))
+    }
+
+    #[test]
+    fn test_recorded_commits() {
+        let commits: Vec<Commit> = serde_json::from_str(
+            r#"[
+                {
+                    "sha": "6d3ce1f2b7a3e8b4a0d3f1c2e5b6a7c8d9e0f1a2",
+                    "commit": {
+                        "message": "Fix login redirect\n\nThe session cookie was dropped",
+                        "author": {"name": "Octo Cat", "email": "octo@example.com", "date": "2024-03-01T10:00:00+01:00"},
+                        "committer": {"name": "Octo Cat", "email": "octo@example.com", "date": "2024-03-01T11:30:00+01:00"}
+                    },
+                    "author": {"id": 1, "login": "OctoCat"}
+                },
+                {
+                    "sha": "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567",
+                    "commit": {
+                        "message": "Unlinked author",
+                        "author": {"name": "Someone", "email": "someone@example.com", "date": "2024-03-02T10:00:00Z"},
+                        "committer": {"name": "Someone", "email": "someone@example.com", "date": "2024-03-02T10:00:00Z"}
+                    },
+                    "author": null
+                }
+            ]"#,
+        )
+        .unwrap();
+
+        let matched: Vec<CommitInfo> = commits
+            .into_iter()
+            .filter_map(|commit| commit_info(commit, "octocat"))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].message_headline, "Fix login redirect");
+        assert_eq!(matched[0].committed_date, "2024-03-01T10:30:00Z");
+        assert_eq!(matched[0].author.login.as_deref(), Some("OctoCat"));
+    }
+
+    #[tokio::test]
+    async fn test_fake_gitea() -> Result<()> {
+        let (gitea, client) = fake_gitea().await?;
+        let period = ContributionPeriod::new(
+            Some(parse_date("2024-01-01")?),
+            Some(parse_date("2024-12-31")?),
+            false,
+        )?;
+
+        // Empty repositories are skipped
+        let repos = client.contributed_repos("octocat", &period).await?;
+        assert_eq!(repos.len(), 1);
+        assert_eq!(repos[0].owner, "octocat");
+        assert_eq!(repos[0].default_branch, "main");
+
+        // A full page of mostly other authors, then a short last page with a commit
+        // from before the period the server didn't filter out
+        let commits = client.user_commits(&repos[0], "octocat", &period).await?;
+        let headlines: Vec<&str> = commits
+            .iter()
+            .map(|commit| commit.message_headline.as_str())
+            .collect();
+        assert_eq!(headlines, ["Add greeting", "Initial commit"]);
+        assert_eq!(commits[1].committed_date, "2024-02-01T17:00:00Z");
+
+        let patch = client
+            .commit_patch("octocat", "hello-world", &commits[0].oid)
+            .await?;
+        assert!(patch.starts_with("diff --git a/README.md b/README.md\n"));
+
+        let requests = gitea.requests();
+        let targets: Vec<&str> = requests
+            .iter()
+            .map(|request| request.target.as_str())
+            .collect();
+        let commits_target = format!("GET {REPO}/commits");
+        let diff_target = format!("GET {REPO}/git/commits/{}.diff", commits[0].oid);
+        assert_eq!(
+            targets,
+            [
+                "GET /api/v1/users/octocat",
+                "GET /api/v1/repos/search",
+                &commits_target,
+                &commits_target,
+                &diff_target,
+            ]
+        );
+        assert!(requests.iter().all(|request| {
+            request
+                .headers
+                .get("authorization")
+                .map(|token| token.as_bytes())
+                == Some(b"token gitea-test".as_slice())
+        }));
+        let commit_queries: Vec<&str> = requests[2..4]
+            .iter()
+            .map(|request| request.query.as_deref().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            commit_queries,
+            [
+                "since=2024-01-01T00%3A00%3A00%2B00%3A00&until=2024-12-31T00%3A00%3A00%2B00%3A00\
+                &sha=main&stat=false&verification=false&files=false&limit=50&page=1",
+                "since=2024-01-01T00%3A00%3A00%2B00%3A00&until=2024-12-31T00%3A00%3A00%2B00%3A00\
+                &sha=main&stat=false&verification=false&files=false&limit=50&page=2",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_fake_gitea_readme() -> Result<()> {
+        let db = MongoDb::for_tests().await?;
+        let (gitea, client) = fake_gitea().await?;
+
+        // Found in the root listing, fetched raw, then served from the cache
+        let readme = client.readme("octocat", "hello-world", &db).await?;
+        let readme = readme.ok_or_else(|| eyre::eyre!("No README"))?;
+        assert_eq!(readme.forge, ForgeKind::Gitea);
+        assert_eq!(readme.content, "# Hello World\n\nSays hello to everyone.\n");
+        client.readme("octocat", "hello-world", &db).await?;
+
+        let requests = gitea.requests();
+        let targets: Vec<&str> = requests
+            .iter()
+            .map(|request| request.target.as_str())
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                format!("GET {REPO}/contents"),
+                format!("GET {REPO}/raw/README.md"),
+            ]
+        );
+        assert!(requests
+            .iter()
+            .all(|request| request.headers.contains_key("authorization")));
+
+        db.drop_for_tests().await
+    }
+}
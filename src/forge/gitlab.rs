@@ -0,0 +1,485 @@
+use async_trait::async_trait;
+use eyre::{Context, Result};
+use reqwest::{Client, Response};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::time::Duration;
+use tracing::{debug, instrument};
+
+use super::{
+    fresh_readme, http_client, normalize_timestamp, period_bounds, send, Forge, ForgeError,
+    ForgeKind, PAGE_SIZE,
+};
+use crate::{
+    config::Config,
+    database::{MongoDb, ReadmeDocument},
+    github::{CommitAuthor, CommitInfo, ContributionPeriod, Repository},
+};
+
+/// Client for the GitLab REST API (v4), on gitlab.com or a self-managed instance
+#[derive(Debug, Clone)]
+pub struct GitLabClient {
+    client: Client,
+    /// e.g. `https://gitlab.com/api/v4`
+    api_url: String,
+    token: Option<String>,
+    readme_cache_ttl: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    id: u64,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Project {
+    path: String,
+    namespace: Namespace,
+    /// `None` for empty projects
+    default_branch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Namespace {
+    full_path: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Commit {
+    id: String,
+    title: String,
+    committed_date: String,
+    author_name: Option<String>,
+    author_email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Diff {
+    old_path: String,
+    new_path: String,
+    new_file: bool,
+    deleted_file: bool,
+    diff: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TreeEntry {
+    name: String,
+    path: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl GitLabClient {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            client: http_client(config)?,
+            api_url: format!("{}/api/v4", config.gitlab_url.trim_end_matches('/')),
+            token: config.gitlab_token.clone(),
+            readme_cache_ttl: config.readme_cache_ttl,
+        })
+    }
+
+    async fn get(&self, path: &str, query: &[(&str, String)], what: &str) -> Result<Response> {
+        let mut request = self
+            .client
+            .get(format!("{}{path}", self.api_url))
+            .query(query);
+        if let Some(token) = &self.token {
+            request = request.header("PRIVATE-TOKEN", token);
+        }
+        send(request, what).await
+    }
+
+    /// Fetches every page of a list endpoint, following `X-Next-Page`
+    async fn get_all<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+        what: &str,
+    ) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        let mut page = "1".to_string();
+        loop {
+            let mut page_query = query.to_vec();
+            page_query.push(("per_page", PAGE_SIZE.to_string()));
+            page_query.push(("page", page));
+            let response = self.get(path, &page_query, what).await?;
+            let next = response
+                .headers()
+                .get("x-next-page")
+                .and_then(|next| next.to_str().ok())
+                .filter(|next| !next.is_empty())
+                .map(String::from);
+            let page_items: Vec<T> = response
+                .json()
+                .await
+                .wrap_err_with(|| format!("Unexpected response for {what}"))?;
+            items.extend(page_items);
+
+            match next {
+                Some(next) => page = next,
+                None => return Ok(items),
+            }
+        }
+    }
+
+    async fn get_user(&self, username: &str) -> Result<User> {
+        let users: Vec<User> = self
+            .get(
+                "/users",
+                &[("username", username.to_string())],
+                &format!("GitLab user {username}"),
+            )
+            .await?
+            .json()
+            .await
+            .wrap_err("Unexpected response for GitLab user lookup")?;
+        let Some(user) = users.into_iter().next() else {
+            return Err(ForgeError::NotFound(format!("GitLab user {username}")).into());
+        };
+        Ok(user)
+    }
+}
+
+/// URL-encoded `namespace/project` path, which GitLab accepts in place of a project ID
+fn project_id(owner: &str, repo: &str) -> String {
+    format!("{owner}/{repo}").replace('/', "%2F")
+}
+
+fn commit_info(commit: Commit) -> Result<CommitInfo> {
+    Ok(CommitInfo {
+        committed_date: normalize_timestamp(&commit.committed_date)?,
+        oid: commit.id,
+        message_headline: commit.title,
+        author: CommitAuthor {
+            email: commit.author_email,
+            name: commit.author_name,
+            login: None,
+        },
+    })
+}
+
+/// Rebuilds a `git diff` style patch from GitLab's per-file diffs
+fn format_patch(diffs: &[Diff]) -> String {
+    let mut patch = String::new();
+    for diff in diffs {
+        let old = if diff.new_file {
+            "/dev/null".to_string()
+        } else {
+            format!("a/{}", diff.old_path)
+        };
+        let new = if diff.deleted_file {
+            "/dev/null".to_string()
+        } else {
+            format!("b/{}", diff.new_path)
+        };
+        patch.push_str(&format!(
+            "diff --git a/{} b/{}\n--- {old}\n+++ {new}\n{}",
+            diff.old_path, diff.new_path, diff.diff
+        ));
+        if !patch.ends_with('\n') {
+            patch.push('\n');
+        }
+    }
+    patch
+}
+
+#[async_trait]
+impl Forge for GitLabClient {
+    fn kind(&self) -> ForgeKind {
+        ForgeKind::GitLab
+    }
+
+    /// GitLab only reports which projects a user contributed to, not when, so every
+    /// project is returned and the period is applied to its commits
+    #[instrument(skip(self))]
+    async fn contributed_repos(
+        &self,
+        username: &str,
+        _period: &ContributionPeriod,
+    ) -> Result<Vec<Repository>> {
+        let user = self.get_user(username).await?;
+        let projects: Vec<Project> = self
+            .get_all(
+                &format!("/users/{}/contributed_projects", user.id),
+                &[],
+                &format!("projects {username} contributed to"),
+            )
+            .await?;
+
+        let repos: Vec<Repository> = projects
+            .into_iter()
+            .filter_map(|project| {
+                Some(Repository {
+                    default_branch: project.default_branch?,
+                    name: project.path,
+                    owner: project.namespace.full_path,
+                    commit_count: 0,
+                })
+            })
+            .collect();
+        debug!("Found {} GitLab projects for {username}", repos.len());
+        Ok(repos)
+    }
+
+    /// Commits are matched by author name, since that's the only author filter
+    /// GitLab's commit listing has
+    #[instrument(skip(self))]
+    async fn user_commits(
+        &self,
+        repo: &Repository,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<CommitInfo>> {
+        let user = self.get_user(username).await?;
+        let mut query = period_bounds(period);
+        query.push(("ref_name", repo.default_branch.clone()));
+        query.push(("author", user.name));
+
+        let commits: Vec<Commit> = self
+            .get_all(
+                &format!(
+                    "/projects/{}/repository/commits",
+                    project_id(&repo.owner, &repo.name)
+                ),
+                &query,
+                &format!("commits in {}/{}", repo.owner, repo.name),
+            )
+            .await?;
+        commits.into_iter().map(commit_info).collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn commit_patch(&self, owner: &str, repo: &str, sha: &str) -> Result<String> {
+        let diffs: Vec<Diff> = self
+            .get_all(
+                &format!(
+                    "/projects/{}/repository/commits/{sha}/diff",
+                    project_id(owner, repo)
+                ),
+                &[],
+                &format!("diff of commit {sha} in {owner}/{repo}"),
+            )
+            .await?;
+        Ok(format_patch(&diffs))
+    }
+
+    #[instrument(skip(self, db))]
+    async fn readme(
+        &self,
+        owner: &str,
+        repo: &str,
+        db: &MongoDb,
+    ) -> Result<Option<ReadmeDocument>> {
+        if let Some(cached) =
+            fresh_readme(db, ForgeKind::GitLab, owner, repo, self.readme_cache_ttl).await?
+        {
+            debug!("Using cached README for {owner}/{repo}");
+            return Ok(Some(cached));
+        }
+
+        // Both default to the project's default branch
+        let project = project_id(owner, repo);
+        let tree: Vec<TreeEntry> = self
+            .get(
+                &format!("/projects/{project}/repository/tree"),
+                &[],
+                &format!("files in {owner}/{repo}"),
+            )
+            .await?
+            .json()
+            .await
+            .wrap_err_with(|| format!("Unexpected file listing for {owner}/{repo}"))?;
+        let Some(entry) = tree.into_iter().find(|entry| {
+            entry.kind == "blob" && entry.name.to_ascii_lowercase().starts_with("readme")
+        }) else {
+            return Ok(None);
+        };
+
+        let content = self
+            .get(
+                &format!(
+                    "/projects/{project}/repository/files/{}/raw",
+                    entry.path.replace('/', "%2F")
+                ),
+                &[],
+                &format!("README of {owner}/{repo}"),
+            )
+            .await?
+            .text()
+            .await?;
+        Ok(Some(
+            db.store_readme(ForgeKind::GitLab, owner, repo, content, None)
+                .await?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{github::fake::FakeGitHub, github::parse_date};
+
+    const PROJECT: &str = "/api/v4/projects/octo-group%2Ftools%2Fhello-world/repository";
+
+    async fn fake_gitlab() -> Result<(FakeGitHub, GitLabClient)> {
+        let gitlab = FakeGitHub::start_forge(ForgeKind::GitLab, "octocat").await?;
+        let mut config = gitlab.config();
+        config.gitlab_token = Some("glpat-test".to_string());
+        let client = GitLabClient::new(&config)?;
+        Ok((gitlab, client))
+    }
+
+    #[test]
+    fn test_recorded_commit_and_diff() {
+        let commits: Vec<Commit> = serde_json::from_str(
+            r#"[{
+                "id": "6104942438c14ec7bd21c6cd5bd995272b3faff6",
+                "short_id": "6104942438c",
+                "title": "Sanitize for network graph",
+                "author_name": "randx",
+                "author_email": "user@example.com",
+                "committed_date": "2021-09-20T09:06:12.300+03:00",
+                "message": "Sanitize for network graph\n\nCloses #1"
+            }]"#,
+        )
+        .unwrap();
+        let commit = commit_info(commits.into_iter().next().unwrap()).unwrap();
+        assert_eq!(commit.message_headline, "Sanitize for network graph");
+        assert_eq!(commit.committed_date, "2021-09-20T06:06:12Z");
+        assert_eq!(commit.author.email.as_deref(), Some("user@example.com"));
+
+        let diffs: Vec<Diff> = serde_json::from_str(
+            r#"[{
+                "diff": "@@ -0,0 +1 @@\n+hello",
+                "new_path": "doc/hello.md",
+                "old_path": "doc/hello.md",
+                "a_mode": null,
+                "b_mode": "100644",
+                "new_file": true,
+                "renamed_file": false,
+                "deleted_file": false
+            }]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format_patch(&diffs),
+            "diff --git a/doc/hello.md b/doc/hello.md\n--- /dev/null\n+++ b/doc/hello.md\n\
+            @@ -0,0 +1 @@\n+hello\n"
+        );
+        assert_eq!(project_id("group/sub", "repo"), "group%2Fsub%2Frepo");
+    }
+
+    #[tokio::test]
+    async fn test_fake_gitlab() -> Result<()> {
+        let (gitlab, client) = fake_gitlab().await?;
+        let period = ContributionPeriod::new(
+            Some(parse_date("2024-01-01")?),
+            Some(parse_date("2024-12-31")?),
+            false,
+        )?;
+
+        // Empty projects have no default branch and are skipped
+        let repos = client.contributed_repos("octocat", &period).await?;
+        assert_eq!(repos.len(), 1);
+        assert_eq!(repos[0].owner, "octo-group/tools");
+        assert_eq!(repos[0].name, "hello-world");
+
+        // Both pages, following `X-Next-Page`
+        let commits = client.user_commits(&repos[0], "octocat", &period).await?;
+        let headlines: Vec<&str> = commits
+            .iter()
+            .map(|commit| commit.message_headline.as_str())
+            .collect();
+        assert_eq!(
+            headlines,
+            ["Add greeting", "Fix typo in README", "Initial commit"]
+        );
+        assert_eq!(commits[0].committed_date, "2024-05-02T08:15:00Z");
+
+        let patch = client
+            .commit_patch("octo-group/tools", "hello-world", &commits[0].oid)
+            .await?;
+        assert!(patch.starts_with(
+            "diff --git a/src/main.rs b/src/main.rs\n--- /dev/null\n+++ b/src/main.rs\n"
+        ));
+        assert!(patch.contains("+Hello, everyone\n"));
+
+        let requests = gitlab.requests();
+        let targets: Vec<&str> = requests
+            .iter()
+            .map(|request| request.target.as_str())
+            .collect();
+        let commits_target = format!("GET {PROJECT}/commits");
+        let diff_target = format!("GET {PROJECT}/commits/{}/diff", commits[0].oid);
+        assert_eq!(
+            targets,
+            [
+                "GET /api/v4/users",
+                "GET /api/v4/users/1234/contributed_projects",
+                "GET /api/v4/users",
+                &commits_target,
+                &commits_target,
+                &diff_target,
+            ]
+        );
+        assert!(requests.iter().all(|request| {
+            request
+                .headers
+                .get("private-token")
+                .map(|token| token.as_bytes())
+                == Some(b"glpat-test".as_slice())
+        }));
+        let commit_queries: Vec<&str> = requests[3..5]
+            .iter()
+            .map(|request| request.query.as_deref().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            commit_queries,
+            [
+                "since=2024-01-01T00%3A00%3A00%2B00%3A00&until=2024-12-31T00%3A00%3A00%2B00%3A00\
+                &ref_name=main&author=Octo+Cat&per_page=50&page=1",
+                "since=2024-01-01T00%3A00%3A00%2B00%3A00&until=2024-12-31T00%3A00%3A00%2B00%3A00\
+                &ref_name=main&author=Octo+Cat&per_page=50&page=2",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_fake_gitlab_readme() -> Result<()> {
+        let db = MongoDb::for_tests().await?;
+        let (gitlab, client) = fake_gitlab().await?;
+
+        // Found in the file tree, fetched raw, then served from the cache
+        let readme = client
+            .readme("octo-group/tools", "hello-world", &db)
+            .await?;
+        let readme = readme.ok_or_else(|| eyre::eyre!("No README"))?;
+        assert_eq!(readme.forge, ForgeKind::GitLab);
+        assert_eq!(readme.content, "# Hello World\n\nSays hello to everyone.\n");
+        client
+            .readme("octo-group/tools", "hello-world", &db)
+            .await?;
+
+        let requests = gitlab.requests();
+        let targets: Vec<&str> = requests
+            .iter()
+            .map(|request| request.target.as_str())
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                format!("GET {PROJECT}/tree"),
+                format!("GET {PROJECT}/files/README.md/raw"),
+            ]
+        );
+        assert!(requests
+            .iter()
+            .all(|request| request.headers.contains_key("private-token")));
+
+        db.drop_for_tests().await
+    }
+}
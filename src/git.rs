@@ -0,0 +1,285 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use git2::{DiffFormat, ObjectType, Oid, Repository, Sort};
+use tracing::{debug, instrument};
+
+use crate::github::{CommitAuthor, CommitInfo};
+
+/// A local clone or bare repository, read directly instead of through the GitHub API.
+///
+/// Produces the same [`CommitInfo`]s and patches as [`crate::github::GitHubClient`]
+/// so both share the summarization pipeline.
+pub struct LocalRepository {
+    path: PathBuf,
+    // `git2::Repository` isn't `Sync`, and ingestion futures need to be `Send`
+    repo: Mutex<Repository>,
+}
+
+impl std::fmt::Debug for LocalRepository {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalRepository")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LocalRepository {
+    pub fn open(path: &Path) -> Result<Self> {
+        let repo = Repository::open(path)
+            .wrap_err_with(|| format!("Failed to open git repository at {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            repo: Mutex::new(repo),
+        })
+    }
+
+    fn repo(&self) -> std::sync::MutexGuard<'_, Repository> {
+        self.repo
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Name of the branch `HEAD` points to
+    pub fn default_branch(&self) -> Result<String> {
+        let repo = self.repo();
+        let head = repo.head().wrap_err("Failed to resolve HEAD")?;
+        head.shorthand()
+            .map(String::from)
+            .ok_or_else(|| eyre!("HEAD of {} isn't a valid branch name", self.path.display()))
+    }
+
+    /// Walks the history of `branch`, newest first, optionally limited to commits
+    /// made between `since` and `until`
+    #[instrument(skip(self))]
+    pub fn history(
+        &self,
+        branch: &str,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CommitInfo>> {
+        let repo = self.repo();
+        let tip = repo
+            .revparse_single(branch)
+            .wrap_err_with(|| format!("Branch {branch} not found in {}", self.path.display()))?
+            .peel_to_commit()
+            .wrap_err_with(|| format!("{branch} doesn't point to a commit"))?;
+
+        let mut walk = repo.revwalk().wrap_err("Failed to walk history")?;
+        walk.set_sorting(Sort::TIME)?;
+        walk.push(tip.id())?;
+
+        let mut commits = Vec::new();
+        for oid in walk {
+            let commit = repo.find_commit(oid?)?;
+            let committed = DateTime::from_timestamp(commit.time().seconds(), 0)
+                .ok_or_else(|| eyre!("Commit {} has an invalid timestamp", commit.id()))?;
+            if since.is_some_and(|since| committed < since)
+                || until.is_some_and(|until| committed > until)
+            {
+                continue;
+            }
+
+            let author = commit.author();
+            let email = author.email().map(String::from);
+            commits.push(CommitInfo {
+                oid: commit.id().to_string(),
+                message_headline: commit.summary().unwrap_or_default().to_string(),
+                // Same format as GitHub's `committedDate`
+                committed_date: committed.to_rfc3339_opts(SecondsFormat::Secs, true),
+                author: CommitAuthor {
+                    login: email.as_deref().and_then(login_from_noreply_email),
+                    name: author.name().map(String::from),
+                    email,
+                },
+            });
+        }
+
+        debug!("Found {} commits on {branch}", commits.len());
+        Ok(commits)
+    }
+
+    /// Unified diff of a commit against its first parent, in the same format as
+    /// GitHub's `application/vnd.github.v3.diff`
+    #[instrument(skip(self))]
+    pub fn patch(&self, sha: &str) -> Result<String> {
+        let repo = self.repo();
+        let oid = Oid::from_str(sha).wrap_err_with(|| format!("Invalid commit SHA {sha}"))?;
+        let commit = repo
+            .find_commit(oid)
+            .wrap_err_with(|| format!("Commit {sha} not found"))?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            // The root commit is diffed against an empty tree
+            Err(_) => None,
+        };
+        let diff = repo
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)
+            .wrap_err_with(|| format!("Failed to diff commit {sha}"))?;
+
+        let mut patch = String::new();
+        diff.print(DiffFormat::Patch, |_, _, line| {
+            if matches!(line.origin(), '+' | '-' | ' ') {
+                patch.push(line.origin());
+            }
+            patch.push_str(&String::from_utf8_lossy(line.content()));
+            true
+        })
+        .wrap_err_with(|| format!("Failed to format diff of commit {sha}"))?;
+        Ok(patch)
+    }
+
+    /// Contents of the README at the tip of `branch`, if there is one
+    pub fn readme(&self, branch: &str) -> Result<Option<String>> {
+        let repo = self.repo();
+        let tree = repo
+            .revparse_single(branch)
+            .and_then(|object| object.peel_to_tree())
+            .wrap_err_with(|| format!("Branch {branch} not found in {}", self.path.display()))?;
+
+        // A `readme/` directory isn't a README
+        let Some(entry) = tree.iter().find(|entry| {
+            entry.kind() == Some(ObjectType::Blob)
+                && entry
+                    .name()
+                    .is_some_and(|name| name.to_ascii_lowercase().starts_with("readme"))
+        }) else {
+            return Ok(None);
+        };
+        let blob = entry
+            .to_object(&repo)
+            .and_then(|object| object.peel_to_blob())
+            .wrap_err("Failed to read README")?;
+        Ok(Some(String::from_utf8_lossy(blob.content()).into_owned()))
+    }
+}
+
+/// Extracts the login from a GitHub noreply address such as
+/// `12345+octocat@users.noreply.github.com`
+pub fn login_from_noreply_email(email: &str) -> Option<String> {
+    let local = email.strip_suffix("@users.noreply.github.com")?;
+    let login = local.split_once('+').map_or(local, |(_, login)| login);
+    (!login.is_empty()).then(|| login.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Signature, Time};
+
+    /// Commits `files` on top of HEAD in a fresh or existing fixture repository
+    fn commit(repo: &Repository, email: &str, time: i64, message: &str, files: &[(&str, &str)]) {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (name, content) in files {
+            let path = workdir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+            index.add_path(Path::new(name)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::new("Octo Cat", email, &Time::new(time, 0)).unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_local_history_and_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit(
+            &repo,
+            "octo@example.com",
+            1_600_000_000,
+            "Add README",
+            &[("README.md", "# Fixture\n")],
+        );
+        commit(
+            &repo,
+            "1+octocat@users.noreply.github.com",
+            1_700_000_000,
+            "Add main\n\nWith a body",
+            &[("main.rs", "fn main() {}\n")],
+        );
+
+        let local = LocalRepository::open(dir.path()).unwrap();
+        let branch = local.default_branch().unwrap();
+        let history = local.history(&branch, None, None).unwrap();
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].message_headline, "Add main");
+        assert_eq!(history[0].committed_date, "2023-11-14T22:13:20Z");
+        assert_eq!(history[0].author.login.as_deref(), Some("octocat"));
+        assert_eq!(history[1].author.login, None);
+        assert_eq!(history[1].author.email.as_deref(), Some("octo@example.com"));
+
+        let since = DateTime::from_timestamp(1_650_000_000, 0);
+        assert_eq!(local.history(&branch, since, None).unwrap().len(), 1);
+
+        let patch = local.patch(&history[0].oid).unwrap();
+        assert!(patch.starts_with("diff --git a/main.rs b/main.rs"));
+        assert!(patch.contains("+fn main() {}"));
+        assert!(local.patch(&history[1].oid).unwrap().contains("+# Fixture"));
+
+        assert_eq!(
+            local.readme(&branch).unwrap().as_deref(),
+            Some("# Fixture\n")
+        );
+    }
+
+    #[test]
+    fn test_readme_skips_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit(
+            &repo,
+            "octo@example.com",
+            1_600_000_000,
+            "Add translations",
+            &[("readme/es.md", "# Hola\n")],
+        );
+        let local = LocalRepository::open(dir.path()).unwrap();
+        let branch = local.default_branch().unwrap();
+        assert_eq!(local.readme(&branch).unwrap(), None);
+
+        // Sorts after the directory, so it's only found if the directory is skipped
+        commit(
+            &repo,
+            "octo@example.com",
+            1_600_000_100,
+            "Add README",
+            &[("readme_first.md", "# Fixture\n")],
+        );
+        assert_eq!(
+            local.readme(&branch).unwrap().as_deref(),
+            Some("# Fixture\n")
+        );
+    }
+
+    #[test]
+    fn test_login_from_noreply_email() {
+        assert_eq!(
+            login_from_noreply_email("12345+octocat@users.noreply.github.com").as_deref(),
+            Some("octocat")
+        );
+        assert_eq!(
+            login_from_noreply_email("octocat@users.noreply.github.com").as_deref(),
+            Some("octocat")
+        );
+        assert_eq!(login_from_noreply_email("octocat@example.com"), None);
+    }
+}
@@ -1,100 +1,443 @@
+use async_trait::async_trait;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use chrono::Utc;
+use chrono::{DateTime, TimeDelta, Utc};
 use eyre::{bail, Context, Result};
-use reqwest::Client;
+use graphql_client::GraphQLQuery;
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Client, RequestBuilder, Response, StatusCode,
+};
 use serde::Deserialize;
+use std::sync::Arc;
 use tracing::{debug, instrument, warn};
 
+mod app;
+mod cache;
+mod contributions;
+#[cfg(test)]
+pub mod fake;
+mod issues;
+mod organizations;
+mod pull_requests;
+mod queries;
+mod tokens;
+
+pub use cache::CacheStats;
+pub use contributions::{parse_date, ContributionPeriod};
+pub use issues::IssueActivity;
+pub use organizations::RepositoryFilter;
+pub use pull_requests::{PullRequestInfo, ReviewCommentInfo, ReviewInfo};
+pub use tokens::{RateLimitStats, TokenStats};
+
 use crate::{
     config::Config,
     database::{MongoDb, ReadmeDocument},
+    forge::{http_client, normalize_timestamp, Forge, ForgeError, ForgeKind},
 };
+use app::AppAuth;
+use cache::{CachedResponse, ResponseCache};
+use contributions::Window;
+use queries::{
+    commits, contribution_years, user_contributed_repos, user_id, Commits, ContributionYears,
+    UserContributedRepos, UserId,
+};
+use tokens::{Resource, TokenPool};
 
 #[derive(Debug, Clone)]
 pub struct GitHubClient {
     client: Client,
     config: Config,
+    cache: ResponseCache,
+    /// Set when authenticating as a GitHub App rather than with personal tokens
+    app: Option<Arc<AppAuth>>,
+    tokens: Arc<TokenPool>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CommitInfo {
     pub oid: String,
-    #[serde(rename = "messageHeadline")]
     pub message_headline: String,
-    #[serde(rename = "committedDate")]
     pub committed_date: String,
     pub author: CommitAuthor,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct CommitAuthor {
     pub email: Option<String>,
     pub name: Option<String>,
+    /// GitHub account the commit's email is linked to
+    pub login: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 pub struct Repository {
     pub name: String,
     pub owner: String,
     pub default_branch: String,
+    /// Commits by the user being processed, or 0 when the repository wasn't found
+    /// through a user's contributions
     pub commit_count: i32,
 }
 
+/// Response of the REST compare endpoint
+#[derive(Debug, Deserialize)]
+struct Comparison {
+    /// Commits in the whole comparison, across every page
+    total_commits: usize,
+    commits: Vec<RestCommit>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestCommit {
+    sha: String,
+    commit: RestCommitDetails,
+    /// Account linked to the author's email, if any
+    author: Option<RestAccount>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestCommitDetails {
+    message: String,
+    author: RestSignature,
+    committer: RestSignature,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestSignature {
+    name: Option<String>,
+    email: Option<String>,
+    date: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestAccount {
+    login: String,
+}
+
+/// The GitHub-specific `type` of each error in a GraphQL response
+#[derive(Debug, Default, Deserialize)]
+struct GraphQlErrorKinds {
+    #[serde(default)]
+    errors: Vec<GraphQlErrorKind>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphQlErrorKind {
+    #[serde(rename = "type", default)]
+    kind: String,
+}
+
 impl GitHubClient {
-    pub fn new(config: Config) -> Self {
-        let client = Client::builder()
-            .build()
-            .expect("Failed to create HTTP client");
+    pub fn new(config: Config) -> Result<Self> {
+        let client = http_client(&config)?;
+        let cache = ResponseCache::new(&config);
+        let app = config
+            .github_app
+            .as_ref()
+            .map(|app| AppAuth::new(app, &config.github_api_url, client.clone()))
+            .transpose()?
+            .map(Arc::new);
+        let tokens = Arc::new(TokenPool::new(&config.github_tokens));
 
-        Self { client, config }
+        Ok(Self {
+            client,
+            config,
+            cache,
+            app,
+            tokens,
+        })
     }
 
-    pub async fn get_user_id<'a>(&'a self, login: &'a str) -> Result<Option<String>> {
-        let query = r"
-        query($login: String!) {
-          user(login: $login) {
-            id
-          }
+    /// Sends a request about `owner`'s resources with the right credentials.
+    ///
+    /// GitHub App tokens are scoped to the installation on that owner, so a rate
+    /// limited installation fails the request. Personal tokens come from the pool,
+    /// which learns their budgets from each response; requests a token was rejected
+    /// or rate limited on are retried with another.
+    async fn send(
+        &self,
+        owner: Option<&str>,
+        resource: Resource,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<Response> {
+        if let Some(app) = &self.app {
+            let token = app.installation_token(owner).await?;
+            let response = request().bearer_auth(token).send().await?;
+            if let Some(error) =
+                tokens::rate_limit_error(resource, response.status(), response.headers())
+            {
+                return Err(error.into());
+            }
+            return Ok(response);
         }
-        ";
 
-        let variables = serde_json::json!({
-            "login": login
-        });
+        loop {
+            let (index, token) = self.tokens.pick(resource)?;
+            let response = request().bearer_auth(token).send().await?;
+            if self
+                .tokens
+                .record(index, resource, response.status(), response.headers())
+            {
+                return Ok(response);
+            }
+        }
+    }
 
-        let response = self.graphql_request(query, variables).await?;
-        let user_id = response["data"]["user"]["id"].as_str().map(String::from);
-        Ok(user_id)
+    /// Whose view of GitHub a response is, so the cache never serves what one
+    /// credential fetched to another that may see less: the installation a GitHub
+    /// App request is sent as, or the set of pooled tokens
+    async fn cache_scope(&self, owner: Option<&str>) -> Result<String> {
+        match &self.app {
+            Some(app) => Ok(format!("installation-{}", app.installation(owner).await?)),
+            None => Ok(self.tokens.fingerprint().to_string()),
+        }
     }
 
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn token_stats(&self) -> Vec<TokenStats> {
+        self.tokens.stats()
+    }
+
+    /// Checks the API is reachable with our credentials. `/rate_limit` doesn't count
+    /// against the rate limit.
+    #[instrument(skip(self))]
+    pub async fn check(&self) -> Result<()> {
+        let url = format!("{}/rate_limit", self.config.github_api_url);
+        let response = self
+            .send(None, Resource::Rest, || {
+                self.client
+                    .get(&url)
+                    .header("User-Agent", "github-research-rs")
+            })
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(ForgeError::Upstream {
+                status: status.as_u16(),
+                message: format!("GitHub API check failed: {status} {body}"),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// GETs a REST resource through the response cache.
+    ///
+    /// Immutable resources are served straight from the cache; anything else is
+    /// revalidated with its stored ETag/Last-Modified. Only `200` responses are cached.
+    async fn rest_get(
+        &self,
+        owner: &str,
+        url: &str,
+        accept: &str,
+        immutable: bool,
+    ) -> Result<(StatusCode, String)> {
+        let scope = self.cache_scope(Some(owner)).await?;
+        let key = ResponseCache::key(&[&scope, url, accept]);
+        let cached = self.cache.get(&key).await;
+        if let Some(cached) = cached.as_ref().filter(|cached| cached.immutable) {
+            debug!("Serving {url} from cache");
+            self.cache.record_hit();
+            return Ok((StatusCode::OK, cached.body.clone()));
+        }
+
+        let response = self
+            .send(Some(owner), Resource::Rest, || {
+                let mut request = self
+                    .client
+                    .get(url)
+                    .header("Accept", accept)
+                    .header("User-Agent", "github-research-rs");
+                if let Some(cached) = &cached {
+                    if let Some(etag) = &cached.etag {
+                        request = request.header(IF_NONE_MATCH, etag);
+                    }
+                    if let Some(last_modified) = &cached.last_modified {
+                        request = request.header(IF_MODIFIED_SINCE, last_modified);
+                    }
+                }
+                request
+            })
+            .await?;
+        let status = response.status();
+        if status == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cached {
+                debug!("{url} is unchanged");
+                self.cache.record_revalidated();
+                return Ok((StatusCode::OK, cached.body));
+            }
+        }
+        self.cache.record_miss();
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let body = response
+            .text()
+            .await
+            .wrap_err_with(|| format!("Failed to read response body from {url}"))?;
+
+        if status == StatusCode::OK {
+            self.cache
+                .put(CachedResponse {
+                    key,
+                    body: body.clone(),
+                    etag,
+                    last_modified,
+                    immutable,
+                    stored_at: Utc::now(),
+                })
+                .await;
+        }
+
+        Ok((status, body))
+    }
+
+    pub async fn get_user_id<'a>(&'a self, login: &'a str) -> Result<Option<String>> {
+        let data = self
+            .graphql_request::<UserId>(
+                None,
+                user_id::Variables {
+                    login: login.to_string(),
+                },
+            )
+            .await?;
+        Ok(data.user.map(|user| user.id))
+    }
+
+    /// Lists the commits on `branch` (the configured default branch when `None`) made
+    /// during `period`, optionally only those authored by the account with node ID
+    /// `by_author_id`
     pub async fn get_commits<'a>(
         &'a self,
         owner: &'a str,
         repo: &'a str,
         branch: Option<&'a str>,
         by_author_id: Option<&'a str>,
+        period: &ContributionPeriod,
     ) -> Result<Vec<CommitInfo>> {
         let branch = branch.unwrap_or(&self.config.default_branch);
-        let query = if by_author_id.is_some() {
-            include_str!("github/queries/commits_by_author.graphql")
-        } else {
-            include_str!("github/queries/commits.graphql")
-        };
+        let (since, until) = period.commit_bounds();
+        self.get_history(owner, repo, branch, by_author_id, since, until)
+            .await
+    }
 
-        let mut variables = serde_json::json!({
-            "owner": owner,
-            "name": repo,
-            "branch": branch,
-            "first": self.config.commits_per_page
-        });
+    /// Walks the whole history of `branch` for every author, optionally limited to
+    /// commits made between `since` and `until`
+    #[instrument(skip(self))]
+    pub async fn get_branch_history(
+        &self,
+        owner: &str,
+        repo: &str,
+        branch: &str,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CommitInfo>> {
+        self.get_history(owner, repo, branch, None, since, until)
+            .await
+    }
 
-        if let Some(author_id) = by_author_id {
-            variables["authorId"] = serde_json::Value::String(author_id.to_string());
+    /// Pages through the history of `branch` until GitHub reports no next page
+    #[instrument(skip(self))]
+    async fn get_history(
+        &self,
+        owner: &str,
+        repo: &str,
+        branch: &str,
+        by_author_id: Option<&str>,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<CommitInfo>> {
+        let mut history = Vec::new();
+        let mut cursor = None;
+        loop {
+            let variables = commits::Variables {
+                owner: owner.to_string(),
+                name: repo.to_string(),
+                branch: branch.to_string(),
+                first: self.config.commits_per_page.into(),
+                author: by_author_id.map(|id| commits::CommitAuthor {
+                    id: Some(id.to_string()),
+                    emails: None,
+                }),
+                since: since.map(|since| since.to_rfc3339()),
+                until: until.map(|until| until.to_rfc3339()),
+                cursor: cursor.take(),
+            };
+            let (commits, next) = self.get_commits_page(variables).await?;
+            history.extend(commits);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
         }
 
-        let response = self.graphql_request(query, variables).await?;
-        let commits = self.parse_commits_response(&response)?;
-        Ok(commits)
+        debug!("Found {} commits on {owner}/{repo}@{branch}", history.len());
+        Ok(history)
+    }
+
+    /// Fetches one page of commit history, returning the cursor of the next page if
+    /// there is one
+    async fn get_commits_page(
+        &self,
+        variables: commits::Variables,
+    ) -> Result<(Vec<CommitInfo>, Option<String>)> {
+        let owner = variables.owner.clone();
+        let repo = variables.name.clone();
+        let branch = variables.branch.clone();
+
+        let data = self
+            .graphql_request::<Commits>(Some(&owner), variables)
+            .await?;
+        let Some(repository) = data.repository else {
+            return Err(ForgeError::NotFound(format!("Repository {owner}/{repo}")).into());
+        };
+        let Some(git_ref) = repository.ref_ else {
+            bail!("Branch {branch} not found in {owner}/{repo}");
+        };
+        let Some(commits::CommitsRepositoryRefTarget::Commit(commit)) = git_ref.target else {
+            bail!("{branch} in {owner}/{repo} doesn't point to a commit");
+        };
+
+        let history = commit.history;
+        let commits = history
+            .edges
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|edge| edge.node)
+            .map(|node| {
+                let author =
+                    node.author
+                        .map_or_else(CommitAuthor::default, |author| CommitAuthor {
+                            email: author.email,
+                            name: author.name,
+                            login: author.user.map(|user| user.login),
+                        });
+                CommitInfo {
+                    oid: node.oid,
+                    message_headline: node.message_headline,
+                    committed_date: node.committed_date,
+                    author,
+                }
+            })
+            .collect();
+        let next = history
+            .page_info
+            .has_next_page
+            .then_some(history.page_info.end_cursor)
+            .flatten();
+        Ok((commits, next))
     }
 
     pub async fn get_commit_patch<'a>(
@@ -104,32 +447,29 @@ impl GitHubClient {
         commit_sha: &'a str,
     ) -> Result<String> {
         let url = format!(
-            "https://api.github.com/repos/{}/{}/commits/{}",
-            owner, repo, commit_sha
+            "{}/repos/{}/{}/commits/{}",
+            self.config.github_api_url, owner, repo, commit_sha
         );
 
-        let response = self
-            .client
-            .get(&url)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.github_token),
-            )
-            .header("Accept", "application/vnd.github.v3.diff")
-            .header("User-Agent", "github-research-rs")
-            .send()
-            .await?;
+        // The diff for a SHA never changes, so it never needs revalidating
+        let (status, body) = self
+            .rest_get(owner, &url, "application/vnd.github.v3.diff", true)
+            .await
+            .wrap_err_with(|| format!("Failed to fetch patch for commit {commit_sha}"))?;
 
-        if response.status().is_server_error() {
-            let text = response.text().await?;
-            bail!("error {text}")
+        if status == StatusCode::NOT_FOUND {
+            let what = format!("Commit {commit_sha} in {owner}/{repo}");
+            return Err(ForgeError::NotFound(what).into());
+        }
+        if !status.is_success() {
+            return Err(ForgeError::Upstream {
+                status: status.as_u16(),
+                message: format!(
+                    "GitHub API error fetching patch for {commit_sha}: {status} {body}"
+                ),
+            }
+            .into());
         }
-
-        // Handle successful response
-        let body = response
-            .text()
-            .await
-            .wrap_err_with(|| format!("Failed to read response body for commit {commit_sha}"))?;
 
         if body.is_empty() {
             warn!(
@@ -142,111 +482,298 @@ impl GitHubClient {
         }
     }
 
-    #[instrument(skip_all)]
+    /// Commits reachable from `head` but not from `base`, oldest first
+    #[instrument(skip(self))]
+    pub async fn compare_commits(
+        &self,
+        owner: &str,
+        repo: &str,
+        base: &str,
+        head: &str,
+    ) -> Result<Vec<CommitInfo>> {
+        let mut commits = Vec::new();
+        // Unpaginated comparisons stop at 250 commits
+        for page in 1.. {
+            let url = format!(
+                "{}/repos/{owner}/{repo}/compare/{base}...{head}?per_page={}&page={page}",
+                self.config.github_api_url, self.config.commits_per_page
+            );
+            // Both ends are SHAs, so the comparison never changes
+            let (status, body) = self
+                .rest_get(owner, &url, "application/vnd.github+json", true)
+                .await
+                .wrap_err_with(|| format!("Failed to compare {base}...{head} in {owner}/{repo}"))?;
+            if status == StatusCode::NOT_FOUND {
+                let what = format!("Comparison {base}...{head} in {owner}/{repo}");
+                return Err(ForgeError::NotFound(what).into());
+            }
+            if !status.is_success() {
+                return Err(ForgeError::Upstream {
+                    status: status.as_u16(),
+                    message: format!(
+                        "GitHub API error comparing {base}...{head} in {owner}/{repo}: {status} {body}"
+                    ),
+                }
+                .into());
+            }
+
+            let comparison: Comparison = serde_json::from_str(&body)
+                .wrap_err_with(|| format!("Unexpected comparison of {base}...{head}"))?;
+            let done = comparison.commits.is_empty()
+                || commits.len() + comparison.commits.len() >= comparison.total_commits;
+            for commit in comparison.commits {
+                commits.push(CommitInfo {
+                    message_headline: commit
+                        .commit
+                        .message
+                        .lines()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                    committed_date: normalize_timestamp(&commit.commit.committer.date)?,
+                    author: CommitAuthor {
+                        email: commit.commit.author.email,
+                        name: commit.commit.author.name,
+                        login: commit.author.map(|author| author.login),
+                    },
+                    oid: commit.sha,
+                });
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(commits)
+    }
+
+    /// Lists the repositories `username` committed to during `period`, with their
+    /// commit counts summed across every window that was queried.
+    #[instrument(skip(self))]
     pub async fn get_user_contributed_repos<'a>(
         &'a self,
         username: &'a str,
+        period: &ContributionPeriod,
     ) -> Result<Vec<Repository>> {
-        let query = include_str!("github/queries/user_contributed_repos.graphql");
-        let variables = serde_json::json!({
-            "username": username
-        });
+        let Some(windows) = self.contribution_windows(username, period).await? else {
+            return Ok(Vec::new());
+        };
 
-        let response = self.graphql_request(query, variables).await?;
+        let mut repos = Vec::new();
+        for window in windows {
+            let window_repos = self.get_contributed_repos_in(username, window).await?;
+            contributions::merge_repositories(&mut repos, window_repos);
+        }
 
-        let contributions_array = response["data"]["user"]["contributionsCollection"]
-            ["commitContributionsByRepository"]
-            .as_array()
-            .unwrap_or(&Vec::new())
-            .to_owned();
+        Ok(repos)
+    }
 
-        let mut repos = Vec::new();
-        for contribution in contributions_array {
-            let repo_obj = &contribution["repository"];
-            let commit_count = contribution["contributions"]["totalCount"]
-                .as_i64()
-                .unwrap_or(0) as i32;
-
-            if commit_count > 0 {
-                repos.push(Repository {
-                    name: repo_obj["name"].as_str().unwrap_or_default().to_string(),
-                    owner: repo_obj["owner"]["login"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                    default_branch: repo_obj["defaultBranchRef"]["name"]
-                        .as_str()
-                        .unwrap_or(&self.config.default_branch)
-                        .to_string(),
-                    commit_count,
-                });
+    /// Splits `period` into the windows `contributionsCollection` is queried with, where
+    /// `None` is GitHub's default of the last year. Returns `None` if the user has no
+    /// contributions at all.
+    async fn contribution_windows(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Option<Vec<Option<Window>>>> {
+        let windows = match period {
+            ContributionPeriod::LastYear => vec![None],
+            ContributionPeriod::Range { from, to } => contributions::yearly_windows(*from, *to)
+                .into_iter()
+                .map(Some)
+                .collect(),
+            ContributionPeriod::FullHistory => {
+                let years = self.get_contribution_years(username).await?;
+                let Some(from) = contributions::start_of_first_year(&years) else {
+                    debug!("{username} has no contributions");
+                    return Ok(None);
+                };
+                contributions::yearly_windows(from, Utc::now())
+                    .into_iter()
+                    .map(Some)
+                    .collect()
             }
-        }
+        };
+        Ok(Some(windows))
+    }
+
+    /// Lists the repositories `username` committed to in a window of at most a year,
+    /// or GitHub's default of the last year when `window` is `None`
+    async fn get_contributed_repos_in(
+        &self,
+        username: &str,
+        window: Option<Window>,
+    ) -> Result<Vec<Repository>> {
+        debug!("Fetching contributions of {username} in {window:?}");
+        let data = self
+            .graphql_request::<UserContributedRepos>(
+                None,
+                user_contributed_repos::Variables {
+                    username: username.to_string(),
+                    from: window.map(|(from, _)| from.to_rfc3339()),
+                    to: window.map(|(_, to)| to.to_rfc3339()),
+                },
+            )
+            .await?;
+        let Some(user) = data.user else {
+            return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+        };
+
+        let repos = user
+            .contributions_collection
+            .commit_contributions_by_repository
+            .into_iter()
+            .filter(|contribution| contribution.contributions.total_count > 0)
+            .map(|contribution| {
+                let repository = contribution.repository;
+                Repository {
+                    name: repository.name,
+                    owner: repository.owner.login,
+                    default_branch: repository
+                        .default_branch_ref
+                        .map_or_else(|| self.config.default_branch.clone(), |r| r.name),
+                    commit_count: contribution.contributions.total_count as i32,
+                }
+            })
+            .collect();
 
         Ok(repos)
     }
 
-    async fn graphql_request(
+    /// Years in which `username` made contributions
+    async fn get_contribution_years(&self, username: &str) -> Result<Vec<i64>> {
+        let data = self
+            .graphql_request::<ContributionYears>(
+                None,
+                contribution_years::Variables {
+                    username: username.to_string(),
+                },
+            )
+            .await?;
+        let Some(user) = data.user else {
+            return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+        };
+        Ok(user.contributions_collection.contribution_years)
+    }
+
+    /// Runs a typed GraphQL operation, returning its data or the GraphQL errors.
+    ///
+    /// `owner` is the account the query is about, which picks the GitHub App
+    /// installation to authenticate as; `None` uses the default installation.
+    async fn graphql_request<Q: GraphQLQuery>(
         &self,
-        query: &str,
-        variables: serde_json::Value,
-    ) -> Result<serde_json::Value> {
-        let body = serde_json::json!({
-            "query": query,
-            "variables": variables
-        });
+        owner: Option<&str>,
+        variables: Q::Variables,
+    ) -> Result<Q::ResponseData> {
+        let body = Q::build_query(variables);
+        let operation = body.operation_name;
+
+        // GraphQL has no conditional requests, so cached responses expire after a TTL
+        let variables_json =
+            serde_json::to_string(&body.variables).wrap_err("Failed to serialize variables")?;
+        let scope = self.cache_scope(owner).await?;
+        let key = ResponseCache::key(&[
+            &scope,
+            &self.config.github_graphql_api,
+            body.query,
+            &variables_json,
+        ]);
+        if let Some(cached) = self
+            .cache
+            .get(&key)
+            .await
+            .filter(|cached| self.cache.is_fresh(cached))
+        {
+            match Self::parse_graphql_response::<Q>(operation, &cached.body) {
+                Ok(data) => {
+                    debug!("Serving {operation} response from cache");
+                    self.cache.record_hit();
+                    return Ok(data);
+                }
+                Err(e) => warn!("Ignoring cached {operation} response: {e:?}"),
+            }
+        }
+        self.cache.record_miss();
 
         let response = self
-            .client
-            .post(&self.config.github_graphql_api)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.github_token),
-            )
-            .header("User-Agent", "github-research-rs")
-            .json(&body)
-            .send()
+            .send(owner, Resource::GraphQl, || {
+                self.client
+                    .post(&self.config.github_graphql_api)
+                    .header("User-Agent", "github-research-rs")
+                    .json(&body)
+            })
             .await?;
 
         // Check if the response was successful
         if !response.status().is_success() {
             let status = response.status();
             let text = response.text().await?;
-            eprintln!("GitHub API error: Status: {status}, Body: {text}");
-            bail!("GitHub API error: {}", status);
+            return Err(ForgeError::Upstream {
+                status: status.as_u16(),
+                message: format!(
+                    "GitHub API error for {operation}: Status: {status}, Body: {text}"
+                ),
+            }
+            .into());
         }
 
-        // Try to parse the response as JSON
+        let reset_at = tokens::reset_at(response.headers());
         let text = response.text().await?;
-        let json: serde_json::Value = serde_json::from_str(&text).inspect_err(|e| {
-            eprintln!("Failed to parse JSON response: {text}... {e:?}");
+        let data = Self::parse_graphql_response::<Q>(operation, &text).map_err(|e| match e
+            .downcast_ref::<ForgeError>(
+        ) {
+            Some(ForgeError::RateLimited { resource, .. }) => ForgeError::RateLimited {
+                resource: resource.clone(),
+                reset_at,
+            }
+            .into(),
+            _ => e,
         })?;
 
-        // Check for GraphQL errors
-        if let Some(errors) = json.get("errors") {
-            eprintln!("GraphQL errors: {errors}");
-            eyre::bail!("GraphQL error: {}", errors);
-        }
+        self.cache
+            .put(CachedResponse {
+                key,
+                body: text,
+                etag: None,
+                last_modified: None,
+                immutable: false,
+                stored_at: Utc::now(),
+            })
+            .await;
 
-        Ok(json)
+        Ok(data)
     }
 
-    fn parse_commits_response(&self, response: &serde_json::Value) -> Result<Vec<CommitInfo>> {
-        let edges = response["data"]["repository"]["ref"]["target"]["history"]["edges"]
-            .as_array()
-            .unwrap_or(&Vec::new())
-            .to_owned();
+    fn parse_graphql_response<Q: GraphQLQuery>(
+        operation: &str,
+        text: &str,
+    ) -> Result<Q::ResponseData> {
+        let response: graphql_client::Response<Q::ResponseData> = serde_json::from_str(text)
+            .wrap_err_with(|| {
+                format!("{operation} response doesn't match the GraphQL schema: {text}")
+            })?;
 
-        let mut commits = Vec::new();
-        for edge in edges {
-            if let Some(node) = edge.get("node").and_then(|n| n.as_object()) {
-                let commit: CommitInfo =
-                    serde_json::from_value(serde_json::Value::Object(node.clone()))?;
-                commits.push(commit);
+        if let Some(errors) = response.errors.filter(|errors| !errors.is_empty()) {
+            // A used up GraphQL budget is reported in a 200 response, as an error
+            // whose `type` the generated error type drops
+            let kinds: GraphQlErrorKinds = serde_json::from_str(text).unwrap_or_default();
+            if kinds
+                .errors
+                .iter()
+                .any(|error| error.kind == "RATE_LIMITED")
+            {
+                return Err(ForgeError::RateLimited {
+                    resource: Resource::GraphQl.as_str().to_string(),
+                    reset_at: None,
+                }
+                .into());
             }
+            let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            bail!("GraphQL errors in {operation}: {}", messages.join("; "));
         }
 
-        Ok(commits)
+        response
+            .data
+            .ok_or_else(|| eyre::eyre!("{operation} response has no data"))
     }
 
     #[instrument(skip(self))]
@@ -255,35 +782,58 @@ impl GitHubClient {
         owner: &'a str,
         repo: &'a str,
         db: &'a MongoDb,
-    ) -> Result<Option<String>> {
-        // Check cache first
-        if let Some(cached) = db.get_cached_readme(owner, repo).await? {
+    ) -> Result<Option<ReadmeDocument>> {
+        // Check cache first, using it as-is until the TTL expires
+        let cached = db.get_cached_readme(ForgeKind::GitHub, owner, repo).await?;
+        let ttl = TimeDelta::from_std(self.config.readme_cache_ttl).unwrap_or(TimeDelta::MAX);
+        if cached
+            .as_ref()
+            .is_some_and(|cached| Utc::now() - cached.cached_at < ttl)
+        {
             debug!("Using cached README for {owner}/{repo}");
-            return Ok(Some(cached.content));
+            return Ok(cached);
         }
 
-        let url = format!("https://api.github.com/repos/{owner}/{repo}/readme");
+        let url = format!("{}/repos/{owner}/{repo}/readme", self.config.github_api_url);
 
         let response = self
-            .client
-            .get(&url)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.github_token),
-            )
-            .header("Accept", "application/vnd.github.v3+json")
-            .header("User-Agent", "github-research-rs")
-            .send()
+            .send(Some(owner), Resource::Rest, || {
+                let request = self
+                    .client
+                    .get(&url)
+                    .header("Accept", "application/vnd.github.v3+json")
+                    .header("User-Agent", "github-research-rs");
+                // Revalidate an expired entry; a 304 doesn't count against the rate limit
+                match cached.as_ref().and_then(|cached| cached.etag.as_deref()) {
+                    Some(etag) => request.header(IF_NONE_MATCH, etag),
+                    None => request,
+                }
+            })
             .await?;
 
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cached {
+                debug!("README for {owner}/{repo} is unchanged");
+                db.touch_readme(ForgeKind::GitHub, owner, repo).await?;
+                return Ok(Some(cached));
+            }
+        }
+
         if !response.status().is_success() {
             warn!(
                 "Failed to fetch README for {owner}/{repo}: {}",
                 response.status()
             );
-            return Ok(None);
+            // A stale README is still better context than none
+            return Ok(cached);
         }
 
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+
         let json: serde_json::Value = response.json().await?;
         let Some(content) = json.get("content").and_then(|c| c.as_str()) else {
             warn!("No content field in README response for {owner}/{repo}");
@@ -301,15 +851,469 @@ impl GitHubClient {
             }
         };
 
-        // Cache the README
-        let readme_doc = ReadmeDocument {
-            owner: owner.to_string(),
-            repo: repo.to_string(),
-            content: decoded.clone(),
-            cached_at: Utc::now(),
+        // A new ETag doesn't mean new content, e.g. when it turns weak
+        let readme = db
+            .store_readme(ForgeKind::GitHub, owner, repo, decoded, etag)
+            .await?;
+        Ok(Some(readme))
+    }
+}
+
+#[async_trait]
+impl Forge for GitHubClient {
+    fn kind(&self) -> ForgeKind {
+        ForgeKind::GitHub
+    }
+
+    async fn contributed_repos(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<Repository>> {
+        self.get_user_contributed_repos(username, period).await
+    }
+
+    async fn user_commits(
+        &self,
+        repo: &Repository,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<CommitInfo>> {
+        let author_id = self
+            .get_user_id(username)
+            .await
+            .wrap_err_with(|| format!("Failed to get GitHub user ID for {username}"))?
+            .ok_or_else(|| ForgeError::NotFound(format!("GitHub ID of user {username}")))?;
+        self.get_commits(
+            &repo.owner,
+            &repo.name,
+            Some(&repo.default_branch),
+            Some(&author_id),
+            period,
+        )
+        .await
+    }
+
+    async fn commit_patch(&self, owner: &str, repo: &str, sha: &str) -> Result<String> {
+        self.get_commit_patch(owner, repo, sha).await
+    }
+
+    async fn readme(
+        &self,
+        owner: &str,
+        repo: &str,
+        db: &MongoDb,
+    ) -> Result<Option<ReadmeDocument>> {
+        self.get_readme(owner, repo, db).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::ReadmeSummary;
+
+    #[test]
+    fn test_parse_graphql_response() {
+        let data = GitHubClient::parse_graphql_response::<UserId>(
+            "UserId",
+            r#"{"data": {"user": {"id": "MDQ6VXNlcjE="}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            data.user.map(|user| user.id).as_deref(),
+            Some("MDQ6VXNlcjE=")
+        );
+
+        // Schema drift is an error rather than an empty value
+        let err = GitHubClient::parse_graphql_response::<UserId>(
+            "UserId",
+            r#"{"data": {"user": {"id": 42}}}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("doesn't match the GraphQL schema"));
+
+        let err = GitHubClient::parse_graphql_response::<UserId>(
+            "UserId",
+            r#"{"data": null, "errors": [{"message": "Could not resolve to a User"}]}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Could not resolve to a User"));
+    }
+
+    #[tokio::test]
+    async fn test_user_flow_against_fixtures() -> Result<()> {
+        let github = fake::FakeGitHub::start("hello_world").await?;
+        let client = GitHubClient::new(github.config())?;
+        let period = ContributionPeriod::default();
+
+        // The same calls `ingest_user` makes
+        let repos = client.contributed_repos("octocat", &period).await?;
+        assert_eq!(repos.len(), 1, "repositories without commits are skipped");
+        assert_eq!(repos[0].default_branch, "master");
+
+        let commits = client.user_commits(&repos[0], "octocat", &period).await?;
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].author.login.as_deref(), Some("octocat"));
+
+        let patch = client
+            .commit_patch("octocat", "Hello-World", &commits[0].oid)
+            .await?;
+        assert!(patch.starts_with("diff --git a/README b/README"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_branch_history_pagination() -> Result<()> {
+        let github = fake::FakeGitHub::start("pagination").await?;
+        let mut config = github.config();
+        config.commits_per_page = 2;
+        let client = GitHubClient::new(config)?;
+
+        let history = client
+            .get_branch_history("octocat", "Hello-World", "master", None, None)
+            .await?;
+        let headlines: Vec<&str> = history
+            .iter()
+            .map(|commit| commit.message_headline.as_str())
+            .collect();
+        assert_eq!(
+            headlines,
+            [
+                "Merge pull request #6 from Spaceghost/patch-1",
+                "New line at end of file. --Signed off by Spaceghost",
+                "first commit"
+            ]
+        );
+        let targets: Vec<String> = github
+            .requests()
+            .into_iter()
+            .map(|request| request.target)
+            .collect();
+        assert_eq!(targets, ["POST /graphql Commits", "POST /graphql Commits"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_user_commits_pagination() -> Result<()> {
+        let github = fake::FakeGitHub::start("pagination").await?;
+        let mut config = github.config();
+        config.commits_per_page = 2;
+        let client = GitHubClient::new(config)?;
+
+        let commits = client
+            .get_commits(
+                "octocat",
+                "Spoon-Knife",
+                Some("main"),
+                Some("MDQ6VXNlcjU4MzIzMQ=="),
+                &ContributionPeriod::FullHistory,
+            )
+            .await?;
+        let headlines: Vec<&str> = commits
+            .iter()
+            .map(|commit| commit.message_headline.as_str())
+            .collect();
+        assert_eq!(
+            headlines,
+            [
+                "Pointing to the guide for forking",
+                "Create styles.css and updated README",
+                "Initial commit"
+            ]
+        );
+        assert!(commits
+            .iter()
+            .all(|commit| commit.author.login.as_deref() == Some("octocat")));
+        assert_eq!(github.requests().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compare_commits_pagination() -> Result<()> {
+        let github = fake::FakeGitHub::start("pagination").await?;
+        let mut config = github.config();
+        config.commits_per_page = 2;
+        let client = GitHubClient::new(config)?;
+
+        let commits = client
+            .compare_commits(
+                "octocat",
+                "Spoon-Knife",
+                "a30c19e3f13765a3b48829788bc1cb8b4e95cee4",
+                "d0dd1f61b33d64e29d8bc1372a94ef6a2fee76a9",
+            )
+            .await?;
+        let headlines: Vec<&str> = commits
+            .iter()
+            .map(|commit| commit.message_headline.as_str())
+            .collect();
+        assert_eq!(
+            headlines,
+            [
+                "Create styles.css and updated README",
+                "Point to the guide for forking",
+                "Merge pull request #6 from Spaceghost/patch-1"
+            ]
+        );
+        assert_eq!(commits[1].committed_date, "2014-02-12T23:18:55Z");
+        assert_eq!(commits[2].author.login, None);
+        // Stops once every commit in the comparison was listed
+        assert_eq!(github.requests().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_and_errors() -> Result<()> {
+        let github = fake::FakeGitHub::start("rate_limit").await?;
+        let client = GitHubClient::new(github.config())?;
+
+        // Retried with the second token once the first runs out
+        let patch = client
+            .get_commit_patch(
+                "octocat",
+                "Hello-World",
+                "762941318ee16e59dabbacb1b4049eec22f0d303",
+            )
+            .await?;
+        assert!(patch.contains("+Hello World!"));
+        let tokens: Vec<Option<String>> = github
+            .requests()
+            .into_iter()
+            .map(|request| request.token)
+            .collect();
+        assert_eq!(
+            tokens,
+            [Some("test-token-1".into()), Some("test-token-2".into())]
+        );
+
+        // Fails once every token is exhausted
+        let err = client
+            .get_commit_patch(
+                "octocat",
+                "Hello-World",
+                "553c2077f0edc3d5dc5d17262f6aa498e69d6f8e",
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("used up its core rate limit"),
+            "{err:#}"
+        );
+        assert!(matches!(
+            err.downcast_ref::<ForgeError>(),
+            Some(ForgeError::RateLimited { .. })
+        ));
+        let stats = client.token_stats();
+        assert!(stats
+            .iter()
+            .all(|token| token.rate_limits[0].remaining == 0));
+
+        let err = client.get_user_id("ghost").await.unwrap_err();
+        assert!(err.to_string().contains("Status: 502"), "{err}");
+        assert!(matches!(
+            err.downcast_ref::<ForgeError>(),
+            Some(ForgeError::Upstream { status: 502, .. })
+        ));
+
+        // GraphQL reports a used up budget in a successful response
+        let err = client.get_user_id("hubot").await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ForgeError>(),
+            Some(&ForgeError::RateLimited {
+                resource: "graphql".to_string(),
+                reset_at: DateTime::from_timestamp(4102444800, 0),
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_app_rate_limit() -> Result<()> {
+        let github = fake::FakeGitHub::start("app").await?;
+        let mut config = github.config();
+        config.github_app = Some(crate::config::GitHubAppConfig {
+            app_id: "12345".to_string(),
+            private_key: include_str!("github/fixtures/app/private-key.pem").to_string(),
+            installation_id: None,
+        });
+        let client = GitHubClient::new(config)?;
+
+        let err = client
+            .get_commit_patch(
+                "octo-org",
+                "hello-world",
+                "7fd1a60b01f91b314f59955a4e4d4e80d8edf11d",
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ForgeError>(),
+            Some(&ForgeError::RateLimited {
+                resource: "core".to_string(),
+                reset_at: DateTime::from_timestamp(4102444800, 0),
+            })
+        );
+        let request = github.requests().pop().unwrap();
+        assert_eq!(request.token.as_deref(), Some("ghs_octoorg"));
+        Ok(())
+    }
+
+    /// Backdates the cached README of `owner/repo` past any TTL
+    async fn expire_readme(db: &MongoDb, owner: &str, repo: &str) -> Result<()> {
+        let mut readme = db
+            .get_cached_readme(ForgeKind::GitHub, owner, repo)
+            .await?
+            .ok_or_else(|| eyre::eyre!("No cached README for {owner}/{repo}"))?;
+        readme.cached_at -= TimeDelta::days(365);
+        db.cache_readme(&readme).await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_readme_expiry_and_revalidation() -> Result<()> {
+        let db = MongoDb::for_tests().await?;
+        let github = fake::FakeGitHub::start("readme").await?;
+        let client = GitHubClient::new(github.config())?;
+        let etags = || -> Vec<Option<String>> {
+            github
+                .requests()
+                .into_iter()
+                .map(|request| request.if_none_match)
+                .collect()
+        };
+
+        // Fetched once, then served from the cache until it expires
+        let readme = client.get_readme("octocat", "Hello-World", &db).await?;
+        let readme = readme.ok_or_else(|| eyre::eyre!("No README"))?;
+        assert_eq!(readme.content, "Hello World!\n");
+        client.get_readme("octocat", "Hello-World", &db).await?;
+        assert_eq!(etags(), [None]);
+
+        // An expired entry is revalidated with its ETag, and a 304 renews it
+        expire_readme(&db, "octocat", "Hello-World").await?;
+        let revalidated = client.get_readme("octocat", "Hello-World", &db).await?;
+        assert_eq!(
+            revalidated
+                .map(|revalidated| revalidated.content)
+                .as_deref(),
+            Some("Hello World!\n")
+        );
+        assert_eq!(etags(), [None, readme.etag.clone()]);
+        let cached = db
+            .get_cached_readme(ForgeKind::GitHub, "octocat", "Hello-World")
+            .await?;
+        assert!(cached.is_some_and(|cached| Utc::now() - cached.cached_at < TimeDelta::minutes(1)));
+
+        // Changed content replaces the entry along with its ETag
+        expire_readme(&db, "octocat", "Hello-World").await?;
+        let changed = client.get_readme("octocat", "Hello-World", &db).await?;
+        let changed = changed.ok_or_else(|| eyre::eyre!("No README"))?;
+        assert_eq!(changed.content, "Hello World, again!\n");
+        assert_ne!(changed.etag, readme.etag);
+        assert_eq!(etags().len(), 3);
+
+        db.drop_for_tests().await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_readme_summary_follows_content() -> Result<()> {
+        let db = MongoDb::for_tests().await?;
+        let github = fake::FakeGitHub::start("readme").await?;
+        let client = GitHubClient::new(github.config())?;
+
+        let readme = client.get_readme("octocat", "Hello-World", &db).await?;
+        let readme = readme.ok_or_else(|| eyre::eyre!("No README"))?;
+        let summary = ReadmeSummary {
+            text: "Prints a greeting".to_string(),
+            model: "test-model".to_string(),
+            generated_at: Utc::now(),
+        };
+        db.cache_readme_summary(
+            ForgeKind::GitHub,
+            "octocat",
+            "Hello-World",
+            &readme.content,
+            &summary,
+        )
+        .await?;
+
+        // A 304 keeps the summary of the unchanged content
+        expire_readme(&db, "octocat", "Hello-World").await?;
+        let revalidated = client.get_readme("octocat", "Hello-World", &db).await?;
+        let revalidated = revalidated.ok_or_else(|| eyre::eyre!("No README"))?;
+        assert_eq!(
+            revalidated.summary.map(|summary| summary.text).as_deref(),
+            Some("Prints a greeting")
+        );
+
+        // Changed content drops it, so the new content gets summarized
+        expire_readme(&db, "octocat", "Hello-World").await?;
+        let changed = client.get_readme("octocat", "Hello-World", &db).await?;
+        assert!(changed.is_some_and(|changed| changed.summary.is_none()));
+        let cached = db
+            .get_cached_readme(ForgeKind::GitHub, "octocat", "Hello-World")
+            .await?;
+        assert!(cached.is_some_and(|cached| cached.summary.is_none()));
+
+        // A summary of the old content that finishes late isn't attached to the new one
+        db.cache_readme_summary(
+            ForgeKind::GitHub,
+            "octocat",
+            "Hello-World",
+            &readme.content,
+            &summary,
+        )
+        .await?;
+        let cached = db
+            .get_cached_readme(ForgeKind::GitHub, "octocat", "Hello-World")
+            .await?;
+        assert!(cached.is_some_and(|cached| cached.summary.is_none()));
+
+        db.drop_for_tests().await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_readme_summary_survives_new_etag() -> Result<()> {
+        let db = MongoDb::for_tests().await?;
+        let github = fake::FakeGitHub::start("readme").await?;
+        let client = GitHubClient::new(github.config())?;
+
+        let readme = client.get_readme("octocat", "Spoon-Knife", &db).await?;
+        let readme = readme.ok_or_else(|| eyre::eyre!("No README"))?;
+        let summary = ReadmeSummary {
+            text: "A repository for practicing forks".to_string(),
+            model: "test-model".to_string(),
+            generated_at: Utc::now(),
         };
-        db.cache_readme(readme_doc).await?;
+        db.cache_readme_summary(
+            ForgeKind::GitHub,
+            "octocat",
+            "Spoon-Knife",
+            &readme.content,
+            &summary,
+        )
+        .await?;
+
+        // The ETag turned weak, so the same content comes back as a 200
+        expire_readme(&db, "octocat", "Spoon-Knife").await?;
+        let refetched = client.get_readme("octocat", "Spoon-Knife", &db).await?;
+        let refetched = refetched.ok_or_else(|| eyre::eyre!("No README"))?;
+        assert_eq!(refetched.content, readme.content);
+        assert_ne!(refetched.etag, readme.etag);
+        let cached = db
+            .get_cached_readme(ForgeKind::GitHub, "octocat", "Spoon-Knife")
+            .await?
+            .ok_or_else(|| eyre::eyre!("No cached README"))?;
+        assert_eq!(cached.etag, refetched.etag);
+        assert!(Utc::now() - cached.cached_at < TimeDelta::minutes(1));
+        assert_eq!(
+            cached.summary.map(|summary| summary.text).as_deref(),
+            Some("A repository for practicing forks")
+        );
 
-        Ok(Some(decoded))
+        db.drop_for_tests().await
     }
 }
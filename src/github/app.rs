@@ -0,0 +1,291 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use eyre::{bail, eyre, Context, Result};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::{Mutex, OnceCell};
+use tracing::{debug, info, instrument};
+
+use crate::config::GitHubAppConfig;
+
+/// GitHub rejects app JWTs that are valid for more than ten minutes
+const JWT_LIFETIME: TimeDelta = TimeDelta::minutes(9);
+/// Allowance for clock drift between us and GitHub when issuing a JWT
+const CLOCK_DRIFT: TimeDelta = TimeDelta::seconds(60);
+/// Installation tokens last an hour; they're replaced once this close to expiring
+const REFRESH_MARGIN: TimeDelta = TimeDelta::minutes(5);
+
+/// Authenticates as a GitHub App, exchanging a JWT signed with the app's private key
+/// for per-installation access tokens
+pub(super) struct AppAuth {
+    app_id: String,
+    key: EncodingKey,
+    api_url: String,
+    client: Client,
+    /// Installation used for requests that aren't about a particular owner
+    default_installation: OnceCell<u64>,
+    /// Installation IDs by lowercased account login, `None` where the app isn't
+    /// installed
+    installations: Mutex<HashMap<String, Option<u64>>>,
+    tokens: Mutex<HashMap<u64, InstallationToken>>,
+}
+
+impl std::fmt::Debug for AppAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppAuth")
+            .field("app_id", &self.app_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
+
+impl Claims {
+    fn new(app_id: &str, now: DateTime<Utc>) -> Self {
+        Self {
+            iat: (now - CLOCK_DRIFT).timestamp(),
+            exp: (now + JWT_LIFETIME).timestamp(),
+            iss: app_id.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Installation {
+    id: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl InstallationToken {
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at - now > REFRESH_MARGIN
+    }
+}
+
+impl AppAuth {
+    pub(super) fn new(config: &GitHubAppConfig, api_url: &str, client: Client) -> Result<Self> {
+        let key = EncodingKey::from_rsa_pem(config.private_key.as_bytes())
+            .wrap_err("Invalid GitHub App private key")?;
+        Ok(Self {
+            app_id: config.app_id.clone(),
+            key,
+            api_url: api_url.to_string(),
+            client,
+            default_installation: OnceCell::new_with(config.installation_id),
+            installations: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The installation on `owner`, or the default installation when the request
+    /// isn't about a particular owner or the app isn't installed on it. Any
+    /// installation's token can read public repositories.
+    pub(super) async fn installation(&self, owner: Option<&str>) -> Result<u64> {
+        if let Some(owner) = owner {
+            if let Some(id) = self.installation_for(owner).await? {
+                return Ok(id);
+            }
+            debug!("The GitHub App isn't installed on {owner}, using the default installation");
+        }
+        Ok(*self
+            .default_installation
+            .get_or_try_init(|| self.any_installation())
+            .await?)
+    }
+
+    /// A token for [`AppAuth::installation`]
+    #[instrument(skip(self))]
+    pub(super) async fn installation_token(&self, owner: Option<&str>) -> Result<String> {
+        let installation = self.installation(owner).await?;
+
+        // Held while refreshing so concurrent requests don't all mint new tokens
+        let mut tokens = self.tokens.lock().await;
+        if let Some(token) = tokens
+            .get(&installation)
+            .filter(|token| token.is_fresh(Utc::now()))
+        {
+            return Ok(token.token.clone());
+        }
+
+        debug!("Requesting a new token for installation {installation}");
+        let response = self
+            .client
+            .post(format!(
+                "{}/app/installations/{installation}/access_tokens",
+                self.api_url
+            ))
+            .bearer_auth(self.jwt()?)
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "github-research-rs")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            bail!("Failed to create a token for installation {installation}: {status} {body}");
+        }
+        let token: InstallationToken = response
+            .json()
+            .await
+            .wrap_err("Unexpected installation token response")?;
+        tokens.insert(installation, token.clone());
+        Ok(token.token)
+    }
+
+    fn jwt(&self) -> Result<String> {
+        jsonwebtoken::encode(
+            &Header::new(Algorithm::RS256),
+            &Claims::new(&self.app_id, Utc::now()),
+            &self.key,
+        )
+        .wrap_err("Failed to sign GitHub App JWT")
+    }
+
+    /// Finds the installation on an organization or user account, if there is one
+    async fn installation_for(&self, owner: &str) -> Result<Option<u64>> {
+        let login = owner.to_lowercase();
+        if let Some(id) = self.installations.lock().await.get(&login) {
+            return Ok(*id);
+        }
+
+        for kind in ["orgs", "users"] {
+            let response = self
+                .client
+                .get(format!("{}/{kind}/{owner}/installation", self.api_url))
+                .bearer_auth(self.jwt()?)
+                .header("Accept", "application/vnd.github+json")
+                .header("User-Agent", "github-research-rs")
+                .send()
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                continue;
+            }
+            if !response.status().is_success() {
+                bail!(
+                    "Failed to look up the GitHub App installation on {owner}: {}",
+                    response.status()
+                );
+            }
+            let installation: Installation = response
+                .json()
+                .await
+                .wrap_err("Unexpected installation response")?;
+            debug!("Using installation {} for {owner}", installation.id);
+            self.installations
+                .lock()
+                .await
+                .insert(login, Some(installation.id));
+            return Ok(Some(installation.id));
+        }
+        self.installations.lock().await.insert(login, None);
+        Ok(None)
+    }
+
+    /// The app's first installation, for when none is configured
+    async fn any_installation(&self) -> Result<u64> {
+        let response = self
+            .client
+            .get(format!("{}/app/installations", self.api_url))
+            .bearer_auth(self.jwt()?)
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "github-research-rs")
+            .send()
+            .await?
+            .error_for_status()
+            .wrap_err("Failed to list GitHub App installations")?;
+        let installations: Vec<Installation> = response
+            .json()
+            .await
+            .wrap_err("Unexpected installation list response")?;
+        match installations.as_slice() {
+            [] => Err(eyre!("The GitHub App has no installations")),
+            [installation] => Ok(installation.id),
+            [installation, ..] => {
+                info!(
+                    "The GitHub App has {} installations, using {} by default; set \
+                    GITHUB_APP_INSTALLATION_ID to choose another",
+                    installations.len(),
+                    installation.id
+                );
+                Ok(installation.id)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::fake::FakeGitHub;
+
+    #[test]
+    fn test_token_lifetimes() {
+        let now = Utc::now();
+        let claims = Claims::new("12345", now);
+        assert_eq!(claims.iss, "12345");
+        assert!(claims.exp - claims.iat <= TimeDelta::minutes(10).num_seconds());
+        assert!(claims.iat < now.timestamp());
+
+        let token = |expires_in| InstallationToken {
+            token: "ghs_test".to_string(),
+            expires_at: now + expires_in,
+        };
+        assert!(token(TimeDelta::minutes(59)).is_fresh(now));
+        assert!(!token(TimeDelta::minutes(4)).is_fresh(now));
+        assert!(!token(TimeDelta::minutes(-1)).is_fresh(now));
+    }
+
+    fn app(github: &FakeGitHub, installation_id: Option<u64>) -> Result<AppAuth> {
+        let config = GitHubAppConfig {
+            app_id: "12345".to_string(),
+            private_key: include_str!("fixtures/app/private-key.pem").to_string(),
+            installation_id,
+        };
+        AppAuth::new(&config, &github.url, Client::new())
+    }
+
+    #[tokio::test]
+    async fn test_installation_falls_back_to_default() -> Result<()> {
+        let github = FakeGitHub::start("app").await?;
+        let auth = app(&github, None)?;
+        assert_eq!(auth.installation(Some("octo-org")).await?, 1001);
+        assert_eq!(auth.installation(Some("octocat")).await?, 1002);
+        // Not installed on torvalds, so its public repositories are read with the
+        // app's first installation
+        assert_eq!(auth.installation(Some("torvalds")).await?, 1001);
+        assert_eq!(auth.installation(Some("torvalds")).await?, 1001);
+        assert_eq!(auth.installation(None).await?, 1001);
+
+        let targets: Vec<String> = github
+            .requests()
+            .into_iter()
+            .map(|request| request.target)
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                "GET /orgs/octo-org/installation",
+                "GET /orgs/octocat/installation",
+                "GET /users/octocat/installation",
+                "GET /orgs/torvalds/installation",
+                "GET /users/torvalds/installation",
+                "GET /app/installations",
+            ]
+        );
+
+        let auth = app(&github, Some(1002))?;
+        assert_eq!(auth.installation(Some("torvalds")).await?, 1002);
+        Ok(())
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+use utoipa::ToSchema;
+
+use crate::config::Config;
+
+/// How often writes trigger a sweep of old entries
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A GitHub response stored on disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub key: String,
+    pub body: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Immutable responses (e.g. the diff for a SHA) are never revalidated
+    pub immutable: bool,
+    pub stored_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    revalidated: AtomicU64,
+    stored: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct CacheStats {
+    /// Whether a cache directory is configured
+    pub enabled: bool,
+    /// Whether the cache is being skipped for this process
+    pub bypassed: bool,
+    /// Responses served from the cache without a request
+    pub hits: u64,
+    /// Requests that had no usable cached response
+    pub misses: u64,
+    /// Conditional requests answered with `304 Not Modified`
+    pub revalidated: u64,
+    /// Responses written to the cache
+    pub stored: u64,
+}
+
+/// On-disk cache for GitHub REST and GraphQL responses.
+///
+/// Entries are JSON files named after the SHA-256 of their key. REST entries are
+/// revalidated with `If-None-Match`/`If-Modified-Since`, since a `304` doesn't count
+/// against the rate limit; GraphQL doesn't support conditional requests, so those
+/// entries expire after `graphql_ttl`. Writes sweep the directory once an hour,
+/// removing entries older than `max_age` and then the oldest until it fits in
+/// `max_bytes`.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: Option<PathBuf>,
+    bypass: bool,
+    graphql_ttl: Duration,
+    max_age: Duration,
+    max_bytes: u64,
+    last_sweep: Arc<Mutex<Option<Instant>>>,
+    counters: Arc<Counters>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            dir: config.github_cache_dir.clone(),
+            bypass: config.github_cache_bypass,
+            graphql_ttl: config.github_cache_graphql_ttl,
+            max_age: config.github_cache_max_age,
+            max_bytes: config.github_cache_max_bytes,
+            last_sweep: Arc::default(),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Builds a cache key from the parts that identify a request. The first part is
+    /// the scope of the credentials it's sent with, since what GitHub returns depends
+    /// on what they can see.
+    pub fn key(parts: &[&str]) -> String {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        if self.bypass {
+            return None;
+        }
+        self.dir.as_ref().map(|dir| dir.join(format!("{key}.json")))
+    }
+
+    /// Returns the stored response for `key`, if any. Unreadable entries count as
+    /// missing.
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let path = self.path(key)?;
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read cached response {}: {e}", path.display());
+                return None;
+            }
+        };
+        serde_json::from_slice(&bytes)
+            .inspect_err(|e| warn!("Ignoring corrupt cache entry {}: {e}", path.display()))
+            .ok()
+    }
+
+    /// Stores a response. Failures are logged rather than returned, since the cache
+    /// is only an optimization.
+    pub async fn put(&self, entry: CachedResponse) {
+        let Some(path) = self.path(&entry.key) else {
+            return;
+        };
+        if let Err(e) = Self::write(&path, &entry).await {
+            warn!("Failed to cache response {}: {e:?}", path.display());
+            return;
+        }
+        debug!("Cached response {}", entry.key);
+        self.counters.stored.fetch_add(1, Ordering::Relaxed);
+        self.sweep_if_due();
+    }
+
+    /// Sweeps in the background unless that happened within the last `SWEEP_INTERVAL`
+    fn sweep_if_due(&self) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap_or_else(|e| e.into_inner());
+            if last_sweep.is_some_and(|at| at.elapsed() < SWEEP_INTERVAL) {
+                return;
+            }
+            *last_sweep = Some(Instant::now());
+        }
+        let cache = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cache.sweep().await {
+                warn!("Failed to sweep the GitHub response cache: {e:?}");
+            }
+        });
+    }
+
+    /// Removes entries written more than `max_age` ago, then the oldest ones until
+    /// the rest fit in `max_bytes`. Returns how many were removed.
+    pub async fn sweep(&self) -> Result<usize> {
+        let Some(dir) = &self.dir else {
+            return Ok(0);
+        };
+        let mut listing = match tokio::fs::read_dir(dir).await {
+            Ok(listing) => listing,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).wrap_err("Failed to list cache directory"),
+        };
+        let mut entries = Vec::new();
+        while let Some(entry) = listing
+            .next_entry()
+            .await
+            .wrap_err("Failed to list cache directory")?
+        {
+            let metadata = entry
+                .metadata()
+                .await
+                .wrap_err("Failed to read cache entry metadata")?;
+            if metadata.is_file() {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push((modified, metadata.len(), entry.path()));
+            }
+        }
+
+        // Oldest first, so once one is young enough and fits, the rest do too
+        entries.sort();
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        let mut removed = 0;
+        for (modified, len, path) in entries {
+            let expired = modified.elapsed().is_ok_and(|age| age > self.max_age);
+            if !expired && size <= self.max_bytes {
+                break;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Failed to remove cache entry {}: {e}", path.display());
+                    continue;
+                }
+            }
+            size -= len;
+        }
+        if removed > 0 {
+            info!("Swept {removed} GitHub responses from the cache");
+        }
+        Ok(removed)
+    }
+
+    async fn write(path: &PathBuf, entry: &CachedResponse) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .wrap_err("Failed to create cache directory")?;
+        }
+        let bytes = serde_json::to_vec(entry).wrap_err("Failed to serialize cache entry")?;
+        // Write to a temporary file first so readers never see a partial entry
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, bytes)
+            .await
+            .wrap_err("Failed to write cache entry")?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .wrap_err("Failed to move cache entry into place")
+    }
+
+    /// Whether a GraphQL entry can be used without asking GitHub again
+    pub fn is_fresh(&self, entry: &CachedResponse) -> bool {
+        let ttl = TimeDelta::from_std(self.graphql_ttl).unwrap_or(TimeDelta::MAX);
+        entry.immutable || Utc::now() - entry.stored_at < ttl
+    }
+
+    fn is_active(&self) -> bool {
+        self.dir.is_some() && !self.bypass
+    }
+
+    pub fn record_hit(&self) {
+        if self.is_active() {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_miss(&self) {
+        if self.is_active() {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_revalidated(&self) {
+        self.counters.revalidated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            enabled: self.dir.is_some(),
+            bypassed: self.bypass,
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            revalidated: self.counters.revalidated.load(Ordering::Relaxed),
+            stored: self.counters.stored.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn cache(dir: PathBuf, bypass: bool) -> ResponseCache {
+        ResponseCache {
+            dir: Some(dir),
+            bypass,
+            graphql_ttl: Duration::from_secs(60),
+            max_age: Duration::from_secs(60 * 60),
+            max_bytes: 1024 * 1024,
+            last_sweep: Arc::default(),
+            counters: Arc::default(),
+        }
+    }
+
+    fn entry(key: &str, stored_at: DateTime<Utc>) -> CachedResponse {
+        CachedResponse {
+            key: key.to_string(),
+            body: "diff --git a/README b/README".to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            immutable: false,
+            stored_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_and_bypass() {
+        let dir = TempDir::new().unwrap();
+        let key = ResponseCache::key(&[
+            "tokens-1",
+            "https://api.github.com/repos/o/r/commits/1",
+            "diff",
+        ]);
+
+        let cache = cache(dir.path().to_path_buf(), false);
+        assert!(cache.get(&key).await.is_none());
+        cache.put(entry(&key, Utc::now())).await;
+        let cached = cache.get(&key).await.expect("entry should be cached");
+        assert_eq!(cached.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(cache.stats().stored, 1);
+
+        let bypassed = self::cache(dir.path().to_path_buf(), true);
+        assert!(bypassed.get(&key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_removes_old_entries_then_oldest() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = cache(dir.path().to_path_buf(), false);
+        // Written before any sweep is due, so nothing races the assertions below
+        *cache.last_sweep.lock().unwrap() = Some(Instant::now());
+
+        let keys: Vec<String> = (0..4)
+            .map(|i| ResponseCache::key(&["tokens-1", &format!("https://api/{i}")]))
+            .collect();
+        for key in &keys {
+            cache.put(entry(key, Utc::now())).await;
+        }
+        let age = |days: u64| SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+        for (key, days) in keys.iter().zip([30, 3, 2, 1]) {
+            let file = std::fs::File::options()
+                .write(true)
+                .open(cache.path(key).unwrap())
+                .unwrap();
+            file.set_modified(age(days)).unwrap();
+        }
+        let entry_len = std::fs::metadata(cache.path(&keys[1]).unwrap())
+            .unwrap()
+            .len();
+
+        // Only the expired entry goes while the rest fit
+        cache.max_age = Duration::from_secs(7 * 24 * 60 * 60);
+        assert_eq!(cache.sweep().await.unwrap(), 1);
+        assert!(cache.get(&keys[0]).await.is_none());
+
+        // Then the oldest until the rest fit
+        cache.max_bytes = entry_len * 2;
+        assert_eq!(cache.sweep().await.unwrap(), 1);
+        assert!(cache.get(&keys[1]).await.is_none());
+        assert!(cache.get(&keys[2]).await.is_some());
+        assert!(cache.get(&keys[3]).await.is_some());
+    }
+
+    #[test]
+    fn test_freshness() {
+        let cache = cache(PathBuf::from("unused"), false);
+        assert!(cache.is_fresh(&entry("a", Utc::now())));
+        assert!(!cache.is_fresh(&entry("a", Utc::now() - TimeDelta::minutes(5))));
+
+        let mut immutable = entry("a", Utc::now() - TimeDelta::days(30));
+        immutable.immutable = true;
+        assert!(cache.is_fresh(&immutable));
+    }
+
+    #[test]
+    fn test_key_separates_parts() {
+        assert_ne!(
+            ResponseCache::key(&["ab", "c"]),
+            ResponseCache::key(&["a", "bc"])
+        );
+    }
+}
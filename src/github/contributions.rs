@@ -0,0 +1,218 @@
+use chrono::{DateTime, Months, NaiveDate, TimeDelta, TimeZone, Utc};
+use eyre::{bail, eyre, Result};
+
+use super::Repository;
+
+/// A `from`/`to` range passed to `contributionsCollection`
+pub type Window = (DateTime<Utc>, DateTime<Utc>);
+
+/// Time range of a user's contributions to ingest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContributionPeriod {
+    /// GitHub's default of the last twelve months
+    #[default]
+    LastYear,
+    /// An explicit range, fetched a year at a time since GitHub won't return more
+    Range {
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    },
+    /// Every year the user has contributions in, from `contributionYears`
+    FullHistory,
+}
+
+impl ContributionPeriod {
+    /// Builds a period from optional bounds. A missing `to` means now and a missing
+    /// `from` means a year before `to`.
+    pub fn new(
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        full_history: bool,
+    ) -> Result<Self> {
+        if full_history {
+            if from.is_some() || to.is_some() {
+                bail!("Full history can't be combined with a from/to date range");
+            }
+            return Ok(Self::FullHistory);
+        }
+
+        let (from, to) = match (from, to) {
+            (None, None) => return Ok(Self::LastYear),
+            (Some(from), None) => (from, Utc::now()),
+            (None, Some(to)) => (to - Months::new(12), to),
+            (Some(from), Some(to)) => (from, to),
+        };
+        if from >= to {
+            bail!("Contribution range start {from} must be before its end {to}");
+        }
+        Ok(Self::Range { from, to })
+    }
+
+    /// Earliest instant in the period, or `None` for the full history
+    pub fn start(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Self::LastYear => Some(Utc::now() - Months::new(12)),
+            Self::Range { from, .. } => Some(*from),
+            Self::FullHistory => None,
+        }
+    }
+
+    /// Whether an RFC 3339 timestamp from GitHub falls within the period
+    pub fn contains(&self, timestamp: &str) -> bool {
+        let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) else {
+            return false;
+        };
+        let timestamp = timestamp.to_utc();
+        let end = match self {
+            Self::Range { to, .. } => Some(*to),
+            Self::LastYear | Self::FullHistory => None,
+        };
+        self.start().is_none_or(|start| timestamp >= start)
+            && end.is_none_or(|end| timestamp <= end)
+    }
+
+    /// Whether an RFC 3339 timestamp from GitHub is earlier than the whole period
+    pub fn is_before(&self, timestamp: &str) -> bool {
+        let (Some(start), Ok(timestamp)) = (self.start(), DateTime::parse_from_rfc3339(timestamp))
+        else {
+            return false;
+        };
+        timestamp.to_utc() < start
+    }
+
+    /// Bounds for a repository's commit history; `None` leaves that side open
+    pub fn commit_bounds(&self) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+        match self {
+            Self::Range { from, to } => (Some(*from), Some(*to)),
+            Self::LastYear | Self::FullHistory => (None, None),
+        }
+    }
+}
+
+/// Parses an RFC 3339 timestamp or a plain `YYYY-MM-DD` date (midnight UTC)
+pub fn parse_date(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Ok(date.to_utc());
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| eyre!("'{s}' is not an RFC 3339 timestamp or YYYY-MM-DD date"))?;
+    Ok(date.and_time(Default::default()).and_utc())
+}
+
+/// Start of the earliest year in `years`
+pub(super) fn start_of_first_year(years: &[i64]) -> Option<DateTime<Utc>> {
+    let year = i32::try_from(*years.iter().min()?).ok()?;
+    Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).single()
+}
+
+/// Splits `from..to` into consecutive windows of at most a year, the longest span
+/// `contributionsCollection` accepts.
+pub(super) fn yearly_windows(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Window> {
+    let mut windows = Vec::new();
+    let mut start = from;
+    while start < to {
+        let next = start
+            .checked_add_months(Months::new(12))
+            .map_or(to, |next| next.min(to));
+        // GitHub treats `to` as inclusive, so stop just short of the next window
+        let end = if next < to {
+            next - TimeDelta::seconds(1)
+        } else {
+            to
+        };
+        windows.push((start, end));
+        start = next;
+    }
+    windows
+}
+
+/// Adds `repos` to `merged`, summing the commit counts of repositories seen in an
+/// earlier window
+pub(super) fn merge_repositories(merged: &mut Vec<Repository>, repos: Vec<Repository>) {
+    for repo in repos {
+        match merged
+            .iter_mut()
+            .find(|existing| existing.owner == repo.owner && existing.name == repo.name)
+        {
+            Some(existing) => existing.commit_count += repo.commit_count,
+            None => merged.push(repo),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(owner: &str, name: &str, commit_count: i32) -> Repository {
+        Repository {
+            name: name.to_string(),
+            owner: owner.to_string(),
+            default_branch: "main".to_string(),
+            commit_count,
+        }
+    }
+
+    #[test]
+    fn test_yearly_windows() {
+        let from = parse_date("2020-01-01").unwrap();
+        let to = parse_date("2022-06-01").unwrap();
+        let windows = yearly_windows(from, to);
+
+        assert_eq!(windows.len(), 3);
+        assert_eq!(windows[0].0, from);
+        assert_eq!(windows[0].1, parse_date("2020-12-31T23:59:59Z").unwrap());
+        assert_eq!(windows[1].0, parse_date("2021-01-01").unwrap());
+        assert_eq!(windows[2], (parse_date("2022-01-01").unwrap(), to));
+    }
+
+    #[test]
+    fn test_period_validation() {
+        let from = parse_date("2021-01-01").unwrap();
+        let to = parse_date("2020-01-01").unwrap();
+
+        assert_eq!(
+            ContributionPeriod::new(None, None, false).unwrap(),
+            ContributionPeriod::LastYear
+        );
+        assert!(ContributionPeriod::new(Some(from), Some(to), false).is_err());
+        assert!(ContributionPeriod::new(Some(to), None, true).is_err());
+        assert_eq!(
+            ContributionPeriod::new(None, Some(from), false).unwrap(),
+            ContributionPeriod::Range { from: to, to: from }
+        );
+        assert!(parse_date("last tuesday").is_err());
+    }
+
+    #[test]
+    fn test_period_contains() {
+        let period = ContributionPeriod::Range {
+            from: parse_date("2020-01-01").unwrap(),
+            to: parse_date("2021-01-01").unwrap(),
+        };
+        assert!(period.contains("2020-06-01T12:00:00Z"));
+        assert!(!period.contains("2019-12-31T23:59:59Z"));
+        assert!(!period.contains("2021-01-01T00:00:01Z"));
+        assert!(!period.contains("not a timestamp"));
+        assert!(period.is_before("2019-12-31T23:59:59Z"));
+        assert!(!period.is_before("2021-06-01T00:00:00Z"));
+        assert!(ContributionPeriod::FullHistory.contains("2008-04-10T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_merge_repositories() {
+        let mut merged = vec![repo("rust-lang", "rust", 3)];
+        merge_repositories(
+            &mut merged,
+            vec![repo("rust-lang", "rust", 2), repo("tokio-rs", "tokio", 1)],
+        );
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].commit_count, 5);
+        assert_eq!(merged[1].name, "tokio");
+        assert_eq!(
+            start_of_first_year(&[2019, 2016, 2024]),
+            parse_date("2016-01-01").ok()
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Local stand-in for the GitHub API that serves recorded responses, so the client
+//! can be tested end-to-end without network access or a token.
+//!
+//! Fixtures live in `src/github/fixtures/<scenario>/`:
+//! - `graphql/<operation>/*.json` answer `POST /graphql` requests for that operation
+//!   whose variables match the fixture's `request.variables`
+//! - `rest/<path>.json` answer any other `GET` or `POST` to `<path>`, e.g.
+//!   `rest/repos/o/r/readme.json`
+//!
+//! A fixture's `responses` are served in order, repeating the last one, which is how
+//! pagination, rate limits and retries are scripted.
+//!
+//! [`FakeGitHub::start_forge`] serves the GitLab and Gitea REST APIs the same way,
+//! from `src/forge/fixtures/<forge>/<scenario>/rest/`.
+//!
+//! Setting `GITHUB_FIXTURES_RECORD=1` forwards every request to the real API with
+//! `GITHUB_TOKEN` (or `GITLAB_TOKEN`, `GITEA_TOKEN`) instead and writes the responses
+//! into the scenario's fixtures.
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use eyre::{eyre, Context, Result};
+use graphql_client::GraphQLQuery;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use super::GitHubClient;
+use crate::{config::Config, forge::ForgeKind};
+
+/// Response headers worth keeping in recorded fixtures
+const RECORDED_HEADERS: &[&str] = &[
+    "content-type",
+    "etag",
+    "last-modified",
+    "x-next-page",
+    "x-ratelimit-limit",
+    "x-ratelimit-remaining",
+    "x-ratelimit-reset",
+    "x-ratelimit-resource",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Fixture {
+    /// What the fixture answers; only `variables` is matched, for GraphQL
+    request: Value,
+    responses: Vec<FixtureResponse>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FixtureResponse {
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// JSON responses are stored as JSON, anything else (e.g. diffs) as a string
+    body: Value,
+}
+
+/// A request the fake received
+#[derive(Debug, Clone)]
+pub struct FakeRequest {
+    /// `POST /graphql <operation>`, or the method and path of a REST request
+    pub target: String,
+    /// Query string of the request, if any
+    pub query: Option<String>,
+    /// Bearer token the request was sent with
+    pub token: Option<String>,
+    /// ETag the request was conditional on
+    pub if_none_match: Option<String>,
+    /// Every header, for the other forges' authentication schemes
+    pub headers: HeaderMap,
+}
+
+struct FakeState {
+    dir: PathBuf,
+    /// Upstream API and token when recording
+    record: Option<(String, String)>,
+    client: Client,
+    /// How many times each fixture was served
+    served: Mutex<HashMap<PathBuf, usize>>,
+    requests: Mutex<Vec<FakeRequest>>,
+}
+
+pub struct FakeGitHub {
+    pub url: String,
+    state: Arc<FakeState>,
+    server: JoinHandle<()>,
+}
+
+impl Drop for FakeGitHub {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl FakeGitHub {
+    /// Starts serving the fixtures of `scenario` on a free local port
+    pub async fn start(scenario: &str) -> Result<Self> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/github/fixtures")
+            .join(scenario);
+        Self::serve(dir, "https://api.github.com", "GITHUB_TOKEN").await
+    }
+
+    /// Starts serving the GitLab or Gitea fixtures of `scenario`, with paths under
+    /// the forge's API prefix, e.g. `rest/api/v4/users.json`
+    pub async fn start_forge(forge: ForgeKind, scenario: &str) -> Result<Self> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/forge/fixtures")
+            .join(forge.as_str())
+            .join(scenario);
+        match forge {
+            ForgeKind::GitLab => Self::serve(dir, "https://gitlab.com", "GITLAB_TOKEN").await,
+            ForgeKind::Gitea => Self::serve(dir, "https://codeberg.org", "GITEA_TOKEN").await,
+            ForgeKind::GitHub | ForgeKind::Local => Err(eyre!("No fake for {forge:?}")),
+        }
+    }
+
+    async fn serve(dir: PathBuf, upstream: &str, token_var: &str) -> Result<Self> {
+        let record = match env::var("GITHUB_FIXTURES_RECORD") {
+            Ok(value) if !value.is_empty() && value != "0" => {
+                dotenv::dotenv().ok();
+                let token = env::var(token_var)
+                    .wrap_err_with(|| format!("{token_var} is needed to record fixtures"))?;
+                Some((upstream.to_string(), token))
+            }
+            _ => None,
+        };
+        let state = Arc::new(FakeState {
+            dir,
+            record,
+            client: Client::new(),
+            served: Mutex::default(),
+            requests: Mutex::default(),
+        });
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let app = Router::new().fallback(handle).with_state(state.clone());
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.ok();
+        });
+        Ok(Self { url, state, server })
+    }
+
+    /// Configuration pointing both GitHub APIs, GitLab and Gitea at the fake
+    pub fn config(&self) -> Config {
+        let mut config = Config::for_tests(&self.url);
+        config.gitlab_url = self.url.clone();
+        config.gitea_url = self.url.clone();
+        config
+    }
+
+    /// Requests received so far, in order
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.state
+            .requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Parses the first response of a recorded GraphQL fixture the way the client does,
+/// for testing conversions of the generated types without a server
+pub fn graphql_response<Q: GraphQLQuery>(
+    scenario: &str,
+    operation: &str,
+    name: &str,
+) -> Result<Q::ResponseData> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("src/github/fixtures")
+        .join(scenario)
+        .join("graphql")
+        .join(operation)
+        .join(format!("{name}.json"));
+    let fixture: Fixture = serde_json::from_str(&fs::read_to_string(&path)?)
+        .wrap_err_with(|| format!("Invalid fixture {}", path.display()))?;
+    let response = fixture
+        .responses
+        .first()
+        .ok_or_else(|| eyre!("{} has no responses", path.display()))?;
+    GitHubClient::parse_graphql_response::<Q>(operation, &response.body.to_string())
+}
+
+async fn handle(
+    State(state): State<Arc<FakeState>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match respond(&state, &method, &uri, &headers, &body).await {
+        Ok(response) => response,
+        // GitHub's error shape, so the client reports the message
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            axum::Json(json!({ "message": format!("{e:#}") })),
+        )
+            .into_response(),
+    }
+}
+
+async fn respond(
+    state: &FakeState,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Result<Response> {
+    let token = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(String::from);
+
+    let (target, request, path) = if method == Method::POST && uri.path() == "/graphql" {
+        let query: Value = serde_json::from_slice(body).wrap_err("GraphQL body isn't JSON")?;
+        let operation = query["operationName"]
+            .as_str()
+            .ok_or_else(|| eyre!("GraphQL request has no operationName"))?
+            .to_string();
+        let variables = query["variables"].clone();
+        let dir = state.dir.join("graphql").join(&operation);
+        let path = match find_graphql_fixture(&dir, &variables)? {
+            Some(path) => path,
+            None => dir.join(format!("{}.json", digest(&variables))),
+        };
+        (
+            format!("POST /graphql {operation}"),
+            json!({ "operation": operation, "variables": variables }),
+            path,
+        )
+    } else if method == Method::GET || method == Method::POST {
+        let path = state
+            .dir
+            .join("rest")
+            .join(format!("{}.json", uri.path().trim_start_matches('/')));
+        (
+            format!("{method} {}", uri.path()),
+            json!({ "path": uri.path() }),
+            path,
+        )
+    } else {
+        return Err(eyre!("The fake doesn't handle {method} {uri}"));
+    };
+    state
+        .requests
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(FakeRequest {
+            target: target.clone(),
+            query: uri.query().map(String::from),
+            token,
+            if_none_match: headers
+                .get("if-none-match")
+                .and_then(|value| value.to_str().ok())
+                .map(String::from),
+            headers: headers.clone(),
+        });
+
+    let response = match &state.record {
+        Some((upstream, token)) => {
+            let response = record(state, upstream, token, method, uri, headers, body).await?;
+            let fixture = Fixture {
+                request,
+                responses: vec![response.clone()],
+            };
+            fs::create_dir_all(path.parent().unwrap_or(&state.dir))?;
+            fs::write(&path, serde_json::to_string_pretty(&fixture)? + "\n")
+                .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+            response
+        }
+        None => {
+            let fixture: Fixture = serde_json::from_str(
+                &fs::read_to_string(&path)
+                    .wrap_err_with(|| format!("No fixture for {target} at {}", path.display()))?,
+            )
+            .wrap_err_with(|| format!("Invalid fixture {}", path.display()))?;
+            let mut served = state.served.lock().unwrap_or_else(|e| e.into_inner());
+            let count = served.entry(path).or_default();
+            let response = fixture
+                .responses
+                .get(*count)
+                .or(fixture.responses.last())
+                .cloned()
+                .ok_or_else(|| eyre!("Fixture for {target} has no responses"))?;
+            *count += 1;
+            response
+        }
+    };
+
+    let mut builder = Response::builder().status(response.status);
+    for (name, value) in &response.headers {
+        builder = builder.header(name, value);
+    }
+    let body = match response.body {
+        Value::String(text) => text,
+        body => body.to_string(),
+    };
+    Ok(builder.body(body.into())?)
+}
+
+/// Forwards a request to the real API and captures its response
+async fn record(
+    state: &FakeState,
+    upstream: &str,
+    token: &str,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Result<FixtureResponse> {
+    let url = format!("{upstream}{uri}");
+    let mut request = match *method {
+        Method::POST => state.client.post(&url).body(body.clone()),
+        _ => state.client.get(&url),
+    };
+    if let Some(accept) = headers.get("accept") {
+        request = request.header("accept", accept);
+    }
+    let response = request
+        .bearer_auth(token)
+        .header("content-type", "application/json")
+        .header("user-agent", "github-research-rs")
+        .send()
+        .await?;
+
+    let status = response.status().as_u16();
+    let headers: BTreeMap<String, String> = RECORDED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = response.headers().get(*name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    let text = response.text().await?;
+    let is_json = headers
+        .get("content-type")
+        .is_some_and(|content_type| content_type.contains("json"));
+    let body = match is_json {
+        true => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+        false => Value::String(text),
+    };
+    Ok(FixtureResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// The fixture in `dir` recorded for `variables`, compared regardless of key order
+fn find_graphql_fixture(dir: &Path, variables: &Value) -> Result<Option<PathBuf>> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(None);
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let fixture: Fixture = serde_json::from_str(&fs::read_to_string(&path)?)
+            .wrap_err_with(|| format!("Invalid fixture {}", path.display()))?;
+        if fixture.request["variables"] == *variables {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Short stable name for a recorded GraphQL fixture
+fn digest(variables: &Value) -> String {
+    hex::encode(&Sha256::digest(variables.to_string().as_bytes())[..6])
+}
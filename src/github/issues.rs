@@ -0,0 +1,432 @@
+use eyre::Result;
+use graphql_client::GraphQLQuery;
+use tracing::{debug, instrument};
+
+use super::{
+    queries::{
+        user_discussion_comments, user_discussions, user_issue_comments, user_issues,
+        UserDiscussionComments, UserDiscussions, UserIssueComments, UserIssues,
+    },
+    ContributionPeriod, GitHubClient,
+};
+use crate::{database::DocumentKind, forge::ForgeError};
+
+/// Largest page GitHub returns for user connections
+const PAGE_SIZE: i64 = 100;
+
+/// Collects the names from a `labels(first: ...) { nodes { name } }` selection, whose
+/// generated type differs for every query
+macro_rules! label_names {
+    ($labels:expr) => {
+        $labels
+            .and_then(|labels| labels.nodes)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|label| label.name)
+            .collect::<Vec<String>>()
+    };
+}
+
+/// An issue or discussion a user opened, or a comment they left on one
+#[derive(Debug, Clone)]
+pub struct IssueActivity {
+    pub id: String,
+    pub kind: DocumentKind,
+    /// Title of the issue or discussion
+    pub title: String,
+    pub body: String,
+    pub url: String,
+    pub created_at: String,
+    pub owner: String,
+    pub repo: String,
+    pub labels: Vec<String>,
+    /// Discussion category
+    pub category: Option<String>,
+}
+
+type IssueNode = user_issues::UserIssuesUserIssuesNodes;
+type IssueCommentNode = user_issue_comments::UserIssueCommentsUserIssueCommentsNodes;
+type DiscussionNode = user_discussions::UserDiscussionsUserRepositoryDiscussionsNodes;
+type DiscussionCommentNode =
+    user_discussion_comments::UserDiscussionCommentsUserRepositoryDiscussionCommentsNodes;
+
+impl From<IssueNode> for IssueActivity {
+    fn from(issue: IssueNode) -> Self {
+        Self {
+            id: issue.id,
+            kind: DocumentKind::Issue,
+            title: issue.title,
+            body: issue.body,
+            url: issue.url,
+            created_at: issue.created_at,
+            owner: issue.repository.owner.login,
+            repo: issue.repository.name,
+            labels: label_names!(issue.labels),
+            category: None,
+        }
+    }
+}
+
+impl From<IssueCommentNode> for IssueActivity {
+    fn from(comment: IssueCommentNode) -> Self {
+        Self {
+            id: comment.id,
+            kind: DocumentKind::IssueComment,
+            title: comment.issue.title,
+            body: comment.body,
+            url: comment.url,
+            created_at: comment.created_at,
+            owner: comment.issue.repository.owner.login,
+            repo: comment.issue.repository.name,
+            labels: label_names!(comment.issue.labels),
+            category: None,
+        }
+    }
+}
+
+impl From<DiscussionNode> for IssueActivity {
+    fn from(discussion: DiscussionNode) -> Self {
+        Self {
+            id: discussion.id,
+            kind: DocumentKind::Discussion,
+            title: discussion.title,
+            body: discussion.body,
+            url: discussion.url,
+            created_at: discussion.created_at,
+            owner: discussion.repository.owner.login,
+            repo: discussion.repository.name,
+            labels: label_names!(discussion.labels),
+            category: Some(discussion.category.name),
+        }
+    }
+}
+
+impl IssueActivity {
+    /// A comment on a discussion, unless the discussion is gone
+    fn from_discussion_comment(comment: DiscussionCommentNode) -> Option<Self> {
+        let discussion = comment.discussion?;
+        Some(Self {
+            id: comment.id,
+            kind: DocumentKind::DiscussionComment,
+            title: discussion.title,
+            body: comment.body,
+            url: comment.url,
+            created_at: comment.created_at,
+            owner: discussion.repository.owner.login,
+            repo: discussion.repository.name,
+            labels: label_names!(discussion.labels),
+            category: Some(discussion.category.name),
+        })
+    }
+}
+
+/// One page of a connection converted to our own type
+struct Page<T> {
+    items: Vec<T>,
+    /// Cursor of the next page, `None` once nothing more in the period is left
+    next: Option<String>,
+}
+
+impl GitHubClient {
+    /// Lists the issues and discussions `username` opened during `period`, and the
+    /// comments they left on others
+    #[instrument(skip(self))]
+    pub async fn get_user_issue_activity(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<IssueActivity>> {
+        let mut activity = self.get_user_issues(username, period).await?;
+        activity.extend(self.get_user_issue_comments(username, period).await?);
+        activity.extend(self.get_user_discussions(username, period).await?);
+        activity.extend(self.get_user_discussion_comments(username, period).await?);
+
+        debug!(
+            "Found {} issue and discussion activities by {username}",
+            activity.len()
+        );
+        Ok(activity)
+    }
+
+    /// Fetches every page of a connection, stopping early once `page` says the rest
+    /// is out of range
+    async fn paginate<Q: GraphQLQuery, T>(
+        &self,
+        variables: impl Fn(Option<String>) -> Q::Variables,
+        page: impl Fn(Q::ResponseData) -> Result<Page<T>>,
+    ) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        let mut cursor = None;
+        loop {
+            let data = self
+                .graphql_request::<Q>(None, variables(cursor.take()))
+                .await?;
+            let page = page(data)?;
+            items.extend(page.items);
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(items),
+            }
+        }
+    }
+
+    async fn get_user_issues(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<IssueActivity>> {
+        self.paginate::<UserIssues, _>(
+            |cursor| user_issues::Variables {
+                username: username.to_string(),
+                first: PAGE_SIZE,
+                cursor,
+            },
+            |data| {
+                let Some(user) = data.user else {
+                    return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+                };
+                let connection = user.issues;
+                let issues: Vec<_> = connection.nodes.into_iter().flatten().flatten().collect();
+                // Newest first, so an issue before the period means the rest are too
+                let done = !connection.page_info.has_next_page
+                    || issues
+                        .last()
+                        .is_some_and(|issue| period.is_before(&issue.created_at));
+
+                let items = issues
+                    .into_iter()
+                    .filter(|issue| period.contains(&issue.created_at))
+                    .map(IssueActivity::from)
+                    .collect();
+                let next = (!done).then_some(connection.page_info.end_cursor).flatten();
+                Ok(Page { items, next })
+            },
+        )
+        .await
+    }
+
+    async fn get_user_issue_comments(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<IssueActivity>> {
+        self.paginate::<UserIssueComments, _>(
+            |cursor| user_issue_comments::Variables {
+                username: username.to_string(),
+                first: PAGE_SIZE,
+                cursor,
+            },
+            |data| {
+                let Some(user) = data.user else {
+                    return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+                };
+                let connection = user.issue_comments;
+                let comments: Vec<_> = connection.nodes.into_iter().flatten().flatten().collect();
+                // Ordered by last update, and a comment can't be created after it was
+                // last updated, so one updated before the period ends the search
+                let done = !connection.page_info.has_next_page
+                    || comments
+                        .last()
+                        .is_some_and(|comment| period.is_before(&comment.updated_at));
+
+                let items = comments
+                    .into_iter()
+                    .filter(|comment| period.contains(&comment.created_at))
+                    .map(IssueActivity::from)
+                    .collect();
+                let next = (!done).then_some(connection.page_info.end_cursor).flatten();
+                Ok(Page { items, next })
+            },
+        )
+        .await
+    }
+
+    async fn get_user_discussions(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<IssueActivity>> {
+        self.paginate::<UserDiscussions, _>(
+            |cursor| user_discussions::Variables {
+                username: username.to_string(),
+                first: PAGE_SIZE,
+                cursor,
+            },
+            |data| {
+                let Some(user) = data.user else {
+                    return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+                };
+                let connection = user.repository_discussions;
+                let discussions: Vec<_> =
+                    connection.nodes.into_iter().flatten().flatten().collect();
+                // Newest first, like issues
+                let done = !connection.page_info.has_next_page
+                    || discussions
+                        .last()
+                        .is_some_and(|discussion| period.is_before(&discussion.created_at));
+
+                let items = discussions
+                    .into_iter()
+                    .filter(|discussion| period.contains(&discussion.created_at))
+                    .map(IssueActivity::from)
+                    .collect();
+                let next = (!done).then_some(connection.page_info.end_cursor).flatten();
+                Ok(Page { items, next })
+            },
+        )
+        .await
+    }
+
+    async fn get_user_discussion_comments(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<IssueActivity>> {
+        self.paginate::<UserDiscussionComments, _>(
+            |cursor| user_discussion_comments::Variables {
+                username: username.to_string(),
+                first: PAGE_SIZE,
+                cursor,
+            },
+            |data| {
+                let Some(user) = data.user else {
+                    return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+                };
+                let connection = user.repository_discussion_comments;
+
+                // This connection can't be ordered, so every page has to be read
+                let items = connection
+                    .nodes
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .filter(|comment| period.contains(&comment.created_at))
+                    .filter_map(IssueActivity::from_discussion_comment)
+                    .collect();
+                let next = connection
+                    .page_info
+                    .has_next_page
+                    .then_some(connection.page_info.end_cursor)
+                    .flatten();
+                Ok(Page { items, next })
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::fake::{graphql_response, FakeGitHub};
+
+    #[test]
+    fn test_issue_activity_from_nodes() -> Result<()> {
+        let issues: Vec<IssueActivity> =
+            graphql_response::<UserIssues>("hello_world", "UserIssues", "octocat")?
+                .user
+                .unwrap()
+                .issues
+                .nodes
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(IssueActivity::from)
+                .collect();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].kind, DocumentKind::Issue);
+        assert_eq!(issues[0].labels, ["documentation"]);
+        assert_eq!(issues[0].category, None);
+
+        let comments: Vec<IssueActivity> =
+            graphql_response::<UserIssueComments>("hello_world", "UserIssueComments", "octocat")?
+                .user
+                .unwrap()
+                .issue_comments
+                .nodes
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(IssueActivity::from)
+                .collect();
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].kind, DocumentKind::IssueComment);
+        // Comments carry the title of their issue
+        assert_eq!(comments[0].title, issues[0].title);
+        assert_eq!(comments[0].body, "The whole world, presumably.");
+        Ok(())
+    }
+
+    #[test]
+    fn test_discussion_activity_from_nodes() -> Result<()> {
+        let discussions: Vec<IssueActivity> =
+            graphql_response::<UserDiscussions>("hello_world", "UserDiscussions", "octocat")?
+                .user
+                .unwrap()
+                .repository_discussions
+                .nodes
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(IssueActivity::from)
+                .collect();
+        assert_eq!(discussions.len(), 1);
+        assert_eq!(discussions[0].kind, DocumentKind::Discussion);
+        assert_eq!(discussions[0].category.as_deref(), Some("Ideas"));
+
+        let comments: Vec<IssueActivity> = graphql_response::<UserDiscussionComments>(
+            "hello_world",
+            "UserDiscussionComments",
+            "octocat",
+        )?
+        .user
+        .unwrap()
+        .repository_discussion_comments
+        .nodes
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(IssueActivity::from_discussion_comment)
+        .collect();
+        // The comment on a deleted discussion is dropped
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].kind, DocumentKind::DiscussionComment);
+        assert_eq!(comments[0].title, "Translating the greeting");
+        assert_eq!(comments[0].category.as_deref(), Some("Ideas"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_issue_activity_pagination() -> Result<()> {
+        let github = FakeGitHub::start("user").await?;
+        let client = GitHubClient::new(github.config())?;
+        let period = ContributionPeriod::new(
+            Some("2025-01-01T00:00:00Z".parse()?),
+            Some("2025-07-01T00:00:00Z".parse()?),
+            false,
+        )?;
+
+        let activity = client.get_user_issue_activity("octocat", &period).await?;
+        let issues: Vec<&str> = activity
+            .iter()
+            .filter(|activity| activity.kind == DocumentKind::Issue)
+            .map(|activity| activity.title.as_str())
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                "Greeting is missing a newline",
+                "README doesn't say who the world is"
+            ]
+        );
+        assert_eq!(activity.len(), 5);
+        let issue_pages = github
+            .requests()
+            .iter()
+            .filter(|request| request.target == "POST /graphql UserIssues")
+            .count();
+        assert_eq!(issue_pages, 2);
+        Ok(())
+    }
+}
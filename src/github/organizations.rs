@@ -0,0 +1,149 @@
+use eyre::{bail, Result};
+use tracing::{debug, instrument};
+
+use super::{
+    queries::{default_branch, org_repositories, DefaultBranch, OrgRepositories},
+    GitHubClient, Repository,
+};
+use crate::forge::ForgeError;
+
+/// Largest page GitHub returns for an organization's repositories
+const PAGE_SIZE: i64 = 100;
+
+/// Which of an organization's repositories to ingest
+#[derive(Debug, Clone, Default)]
+pub struct RepositoryFilter {
+    pub include_archived: bool,
+    pub include_forks: bool,
+    /// Only repositories whose primary language is this, ignoring case
+    pub language: Option<String>,
+    /// Only repositories tagged with this topic
+    pub topic: Option<String>,
+}
+
+impl RepositoryFilter {
+    fn matches(&self, language: Option<&str>, topics: &[String]) -> bool {
+        let language_matches = self.language.as_ref().is_none_or(|wanted| {
+            language.is_some_and(|language| language.eq_ignore_ascii_case(wanted))
+        });
+        let topic_matches = self.topic.as_ref().is_none_or(|wanted| {
+            topics
+                .iter()
+                .any(|topic| topic.eq_ignore_ascii_case(wanted))
+        });
+        language_matches && topic_matches
+    }
+}
+
+impl GitHubClient {
+    /// Lists an organization's repositories that match `filter`
+    #[instrument(skip(self))]
+    pub async fn get_org_repositories(
+        &self,
+        org: &str,
+        filter: &RepositoryFilter,
+    ) -> Result<Vec<Repository>> {
+        let mut repos = Vec::new();
+        let mut cursor = None;
+        loop {
+            // Archived repositories and forks are filtered by GitHub, the rest here
+            let data = self
+                .graphql_request::<OrgRepositories>(
+                    Some(org),
+                    org_repositories::Variables {
+                        org: org.to_string(),
+                        first: PAGE_SIZE,
+                        cursor: cursor.take(),
+                        is_archived: (!filter.include_archived).then_some(false),
+                        is_fork: (!filter.include_forks).then_some(false),
+                    },
+                )
+                .await?;
+            let Some(organization) = data.organization else {
+                return Err(ForgeError::NotFound(format!("GitHub organization {org}")).into());
+            };
+            let connection = organization.repositories;
+
+            for repository in connection.nodes.into_iter().flatten().flatten() {
+                let language = repository.primary_language.map(|language| language.name);
+                let topics: Vec<String> = repository
+                    .repository_topics
+                    .nodes
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .map(|node| node.topic.name)
+                    .collect();
+                if !filter.matches(language.as_deref(), &topics) {
+                    continue;
+                }
+                let Some(default_branch) = repository.default_branch_ref else {
+                    debug!("Skipping empty repository {org}/{}", repository.name);
+                    continue;
+                };
+                repos.push(Repository {
+                    name: repository.name,
+                    owner: repository.owner.login,
+                    default_branch: default_branch.name,
+                    commit_count: 0,
+                });
+            }
+
+            if !connection.page_info.has_next_page {
+                break;
+            }
+            cursor = connection.page_info.end_cursor;
+        }
+
+        debug!("Found {} matching repositories in {org}", repos.len());
+        Ok(repos)
+    }
+
+    /// Looks up a single repository and its default branch
+    #[instrument(skip(self))]
+    pub async fn get_repository(&self, owner: &str, repo: &str) -> Result<Repository> {
+        let data = self
+            .graphql_request::<DefaultBranch>(
+                Some(owner),
+                default_branch::Variables {
+                    owner: owner.to_string(),
+                    name: repo.to_string(),
+                },
+            )
+            .await?;
+        let Some(repository) = data.repository else {
+            return Err(ForgeError::NotFound(format!("Repository {owner}/{repo}")).into());
+        };
+        let Some(default_branch) = repository.default_branch_ref else {
+            bail!("Repository {owner}/{repo} has no commits");
+        };
+
+        Ok(Repository {
+            name: repo.to_string(),
+            owner: owner.to_string(),
+            default_branch: default_branch.name,
+            commit_count: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repository_filter() {
+        let topics = vec!["web".to_string(), "async".to_string()];
+        assert!(RepositoryFilter::default().matches(None, &[]));
+
+        let filter = RepositoryFilter {
+            language: Some("rust".to_string()),
+            topic: Some("Async".to_string()),
+            ..RepositoryFilter::default()
+        };
+        assert!(filter.matches(Some("Rust"), &topics));
+        assert!(!filter.matches(Some("Go"), &topics));
+        assert!(!filter.matches(None, &topics));
+        assert!(!filter.matches(Some("Rust"), &[]));
+    }
+}
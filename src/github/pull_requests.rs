@@ -0,0 +1,284 @@
+use eyre::Result;
+use tracing::{debug, instrument};
+
+use super::{
+    queries::{user_pull_requests, user_reviews, UserPullRequests, UserReviews},
+    ContributionPeriod, GitHubClient,
+};
+use crate::forge::ForgeError;
+
+/// Largest page GitHub returns for contribution connections
+const PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Clone)]
+pub struct PullRequestInfo {
+    pub id: String,
+    pub title: String,
+    pub body: String,
+    pub url: String,
+    pub created_at: String,
+    pub additions: i64,
+    pub deletions: i64,
+    pub changed_files: i64,
+    pub merged: bool,
+    pub owner: String,
+    pub repo: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReviewInfo {
+    pub id: String,
+    pub body: String,
+    pub url: String,
+    pub created_at: String,
+    /// Title of the reviewed pull request
+    pub pull_request_title: String,
+    pub owner: String,
+    pub repo: String,
+    pub comments: Vec<ReviewCommentInfo>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReviewCommentInfo {
+    pub id: String,
+    pub body: String,
+    pub url: String,
+    pub created_at: String,
+    pub path: String,
+    pub diff_hunk: String,
+}
+
+type PullRequestNode =
+    user_pull_requests::UserPullRequestsUserContributionsCollectionPullRequestContributionsNodesPullRequest;
+type ReviewNode =
+    user_reviews::UserReviewsUserContributionsCollectionPullRequestReviewContributionsNodesPullRequestReview;
+type ReviewCommentNode =
+    user_reviews::UserReviewsUserContributionsCollectionPullRequestReviewContributionsNodesPullRequestReviewCommentsNodes;
+
+impl From<PullRequestNode> for PullRequestInfo {
+    fn from(pr: PullRequestNode) -> Self {
+        Self {
+            id: pr.id,
+            title: pr.title,
+            body: pr.body,
+            url: pr.url,
+            created_at: pr.created_at,
+            additions: pr.additions,
+            deletions: pr.deletions,
+            changed_files: pr.changed_files,
+            merged: pr.merged,
+            owner: pr.repository.owner.login,
+            repo: pr.repository.name,
+        }
+    }
+}
+
+impl From<ReviewNode> for ReviewInfo {
+    fn from(review: ReviewNode) -> Self {
+        Self {
+            id: review.id,
+            body: review.body,
+            url: review.url,
+            created_at: review.created_at,
+            pull_request_title: review.pull_request.title,
+            owner: review.repository.owner.login,
+            repo: review.repository.name,
+            comments: review
+                .comments
+                .nodes
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(ReviewCommentInfo::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<ReviewCommentNode> for ReviewCommentInfo {
+    fn from(comment: ReviewCommentNode) -> Self {
+        Self {
+            id: comment.id,
+            body: comment.body,
+            url: comment.url,
+            created_at: comment.created_at,
+            path: comment.path,
+            diff_hunk: comment.diff_hunk,
+        }
+    }
+}
+
+impl GitHubClient {
+    /// Lists the pull requests `username` opened during `period`
+    #[instrument(skip(self))]
+    pub async fn get_user_pull_requests(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<PullRequestInfo>> {
+        let Some(windows) = self.contribution_windows(username, period).await? else {
+            return Ok(Vec::new());
+        };
+
+        let mut pull_requests = Vec::new();
+        for window in windows {
+            let mut cursor = None;
+            loop {
+                let data = self
+                    .graphql_request::<UserPullRequests>(
+                        None,
+                        user_pull_requests::Variables {
+                            username: username.to_string(),
+                            from: window.map(|(from, _)| from.to_rfc3339()),
+                            to: window.map(|(_, to)| to.to_rfc3339()),
+                            first: PAGE_SIZE,
+                            cursor: cursor.take(),
+                        },
+                    )
+                    .await?;
+                let Some(user) = data.user else {
+                    return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+                };
+                let connection = user.contributions_collection.pull_request_contributions;
+
+                pull_requests.extend(
+                    connection
+                        .nodes
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .map(|contribution| PullRequestInfo::from(contribution.pull_request)),
+                );
+
+                if !connection.page_info.has_next_page {
+                    break;
+                }
+                cursor = connection.page_info.end_cursor;
+            }
+        }
+
+        debug!("Found {} pull requests by {username}", pull_requests.len());
+        Ok(pull_requests)
+    }
+
+    /// Lists the pull request reviews `username` submitted during `period`, with up
+    /// to 100 inline comments each
+    #[instrument(skip(self))]
+    pub async fn get_user_reviews(
+        &self,
+        username: &str,
+        period: &ContributionPeriod,
+    ) -> Result<Vec<ReviewInfo>> {
+        let Some(windows) = self.contribution_windows(username, period).await? else {
+            return Ok(Vec::new());
+        };
+
+        let mut reviews = Vec::new();
+        for window in windows {
+            let mut cursor = None;
+            loop {
+                let data = self
+                    .graphql_request::<UserReviews>(
+                        None,
+                        user_reviews::Variables {
+                            username: username.to_string(),
+                            from: window.map(|(from, _)| from.to_rfc3339()),
+                            to: window.map(|(_, to)| to.to_rfc3339()),
+                            first: PAGE_SIZE,
+                            cursor: cursor.take(),
+                        },
+                    )
+                    .await?;
+                let Some(user) = data.user else {
+                    return Err(ForgeError::NotFound(format!("GitHub user {username}")).into());
+                };
+                let connection = user
+                    .contributions_collection
+                    .pull_request_review_contributions;
+
+                reviews.extend(
+                    connection
+                        .nodes
+                        .into_iter()
+                        .flatten()
+                        .flatten()
+                        .map(|contribution| ReviewInfo::from(contribution.pull_request_review)),
+                );
+
+                if !connection.page_info.has_next_page {
+                    break;
+                }
+                cursor = connection.page_info.end_cursor;
+            }
+        }
+
+        debug!("Found {} reviews by {username}", reviews.len());
+        Ok(reviews)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::fake::graphql_response;
+
+    #[test]
+    fn test_pull_request_from_node() -> Result<()> {
+        let data =
+            graphql_response::<UserPullRequests>("hello_world", "UserPullRequests", "octocat")?;
+        let pull_requests: Vec<PullRequestInfo> = data
+            .user
+            .unwrap()
+            .contributions_collection
+            .pull_request_contributions
+            .nodes
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|contribution| PullRequestInfo::from(contribution.pull_request))
+            .collect();
+
+        assert_eq!(pull_requests.len(), 1);
+        let pr = &pull_requests[0];
+        assert_eq!(pr.title, "Add a greeting for the new year");
+        assert_eq!((pr.additions, pr.deletions, pr.changed_files), (12, 3, 2));
+        assert!(pr.merged);
+        assert_eq!(
+            (pr.owner.as_str(), pr.repo.as_str()),
+            ("octocat", "Hello-World")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_review_from_node() -> Result<()> {
+        let data = graphql_response::<UserReviews>("hello_world", "UserReviews", "octocat")?;
+        let reviews: Vec<ReviewInfo> = data
+            .user
+            .unwrap()
+            .contributions_collection
+            .pull_request_review_contributions
+            .nodes
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|contribution| ReviewInfo::from(contribution.pull_request_review))
+            .collect();
+
+        assert_eq!(reviews.len(), 2);
+        let review = &reviews[0];
+        assert_eq!(review.body, "Looks good once the typo is fixed.");
+        assert_eq!(review.pull_request_title, "Fix the README heading");
+        assert_eq!(
+            (review.owner.as_str(), review.repo.as_str()),
+            ("octocat", "Hello-World")
+        );
+        assert_eq!(review.comments.len(), 1);
+        assert_eq!(review.comments[0].path, "README");
+        assert!(review.comments[0].diff_hunk.starts_with("@@ -1 +1 @@"));
+
+        assert!(reviews[1].body.is_empty());
+        assert!(reviews[1].comments.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Typed GitHub GraphQL operations.
+//!
+//! Request and response types are generated at compile time from
+//! `schema.docs.graphql` and the documents in `queries/`, so a query that no longer
+//! matches the schema fails the build instead of silently returning empty fields.
+
+use graphql_client::GraphQLQuery;
+
+// Custom scalars used by the queries, all of which GitHub sends as strings
+type DateTime = String;
+type GitObjectID = String;
+type GitTimestamp = String;
+#[allow(clippy::upper_case_acronyms)]
+type URI = String;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/commits.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct Commits;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/contribution_years.graphql",
+    response_derives = "Debug"
+)]
+pub struct ContributionYears;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_contributed_repos.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct UserContributedRepos;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_id.graphql",
+    response_derives = "Debug"
+)]
+pub struct UserId;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_pull_requests.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct UserPullRequests;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_reviews.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct UserReviews;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_issues.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct UserIssues;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_issue_comments.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct UserIssueComments;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_discussions.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct UserDiscussions;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/user_discussion_comments.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct UserDiscussionComments;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/org_repositories.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct OrgRepositories;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "schema.docs.graphql",
+    query_path = "src/github/queries/default_branch.graphql",
+    response_derives = "Debug",
+    skip_serializing_none
+)]
+pub struct DefaultBranch;
@@ -0,0 +1,376 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use eyre::{bail, Result};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Gauge},
+    KeyValue,
+};
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use crate::forge::ForgeError;
+
+/// Rate-limited GitHub API, each with its own hourly budget per token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum Resource {
+    Rest,
+    GraphQl,
+}
+
+impl Resource {
+    /// Name GitHub uses in `X-RateLimit-Resource`
+    pub(super) const fn as_str(self) -> &'static str {
+        match self {
+            Self::Rest => "core",
+            Self::GraphQl => "graphql",
+        }
+    }
+}
+
+/// When the rate limit reported in `headers` resets
+pub(super) fn reset_at(headers: &HeaderMap) -> Option<DateTime<Utc>> {
+    let reset = headers
+        .get("x-ratelimit-reset")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    DateTime::from_timestamp(reset, 0)
+}
+
+/// The error for a response GitHub refused because its credential has no
+/// `resource` budget left, as opposed to a permission error
+pub(super) fn rate_limit_error(
+    resource: Resource,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Option<ForgeError> {
+    let refused = status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS;
+    let exhausted = headers
+        .get("x-ratelimit-remaining")
+        .is_some_and(|remaining| remaining.as_bytes() == b"0");
+    (refused && exhausted).then(|| ForgeError::RateLimited {
+        resource: resource.as_str().to_string(),
+        reset_at: reset_at(headers),
+    })
+}
+
+/// What GitHub last reported about a token's budget for one resource
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    remaining: u64,
+    reset_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    budgets: HashMap<Resource, Budget>,
+    /// Rejected with a `401`, so out of rotation for the rest of the process
+    revoked: bool,
+    requests: u64,
+}
+
+#[derive(Debug)]
+struct PooledToken {
+    token: String,
+    /// Identifies the token in logs and metrics without revealing it
+    label: String,
+    state: Mutex<TokenState>,
+}
+
+/// Usage of one pooled token
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenStats {
+    /// `token-<n>`, numbered in configuration order
+    pub label: String,
+    pub revoked: bool,
+    /// Requests sent with the token since the server started
+    pub requests: u64,
+    pub rate_limits: Vec<RateLimitStats>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RateLimitStats {
+    /// `core` for REST or `graphql`
+    pub resource: String,
+    pub remaining: u64,
+    #[schema(value_type = String, format = DateTime)]
+    pub reset_at: DateTime<Utc>,
+}
+
+/// Personal access tokens that requests are spread across.
+///
+/// Each request goes to the token with the most budget left for its API, learned
+/// from the `X-RateLimit-*` headers of earlier responses. Exhausted tokens sit out
+/// until their budget resets, and revoked ones for good.
+#[derive(Debug)]
+pub(super) struct TokenPool {
+    tokens: Vec<PooledToken>,
+    /// Identifies the set of tokens without revealing them
+    fingerprint: String,
+    requests: Counter<u64>,
+    remaining: Gauge<u64>,
+}
+
+impl TokenPool {
+    pub(super) fn new(tokens: &[String]) -> Self {
+        let meter = global::meter("github-research-rs");
+        let mut sorted = tokens.to_vec();
+        sorted.sort();
+        let digest = Sha256::digest(sorted.join("\n"));
+        Self {
+            fingerprint: format!("tokens-{}", &hex::encode(digest)[..16]),
+            tokens: tokens
+                .iter()
+                .enumerate()
+                .map(|(index, token)| PooledToken {
+                    token: token.clone(),
+                    label: format!("token-{}", index + 1),
+                    state: Mutex::default(),
+                })
+                .collect(),
+            requests: meter
+                .u64_counter("github.token.requests")
+                .with_description("GitHub API requests sent with each pooled token")
+                .build(),
+            remaining: meter
+                .u64_gauge("github.token.remaining")
+                .with_description("Rate limit budget GitHub last reported for each pooled token")
+                .build(),
+        }
+    }
+
+    /// Identifies the set of tokens, whichever order they're configured in
+    pub(super) fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Picks the token with the most budget left for `resource`, returning its index
+    /// and value. Tokens whose budget hasn't been seen yet are tried first.
+    pub(super) fn pick(&self, resource: Resource) -> Result<(usize, String)> {
+        if self.tokens.is_empty() {
+            bail!("No GitHub tokens are configured");
+        }
+        let now = Utc::now();
+
+        let mut best: Option<(usize, u64)> = None;
+        let mut next_reset: Option<DateTime<Utc>> = None;
+        for (index, pooled) in self.tokens.iter().enumerate() {
+            let state = pooled.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.revoked {
+                continue;
+            }
+            let available = match state.budgets.get(&resource) {
+                Some(budget) if budget.reset_at > now => {
+                    if budget.remaining == 0 {
+                        next_reset = next_reset
+                            .min(Some(budget.reset_at))
+                            .or(Some(budget.reset_at));
+                    }
+                    budget.remaining
+                }
+                _ => u64::MAX,
+            };
+            if available > 0 && best.is_none_or(|(_, most)| available > most) {
+                best = Some((index, available));
+            }
+        }
+
+        let Some((index, _)) = best else {
+            let Some(reset) = next_reset else {
+                return Err(ForgeError::Upstream {
+                    status: 401,
+                    message: "Every GitHub token has been revoked".to_string(),
+                }
+                .into());
+            };
+            let error = ForgeError::RateLimited {
+                resource: resource.as_str().to_string(),
+                reset_at: Some(reset),
+            };
+            return Err(eyre::Report::new(error).wrap_err(format!(
+                "Every GitHub token has used up its {} rate limit; the first resets at {reset}",
+                resource.as_str()
+            )));
+        };
+        let pooled = &self.tokens[index];
+        pooled
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .requests += 1;
+        self.requests.add(1, &self.attributes(index, resource));
+        Ok((index, pooled.token.clone()))
+    }
+
+    /// Updates a token's budget from a response, and takes it out of rotation if
+    /// GitHub rejected it. Returns `false` when the request should be retried with
+    /// another token: the token was rejected or had run out of budget.
+    pub(super) fn record(
+        &self,
+        index: usize,
+        resource: Resource,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> bool {
+        let pooled = &self.tokens[index];
+        let mut state = pooled.state.lock().unwrap_or_else(|e| e.into_inner());
+        if status == StatusCode::UNAUTHORIZED {
+            warn!(
+                "GitHub rejected {}; taking it out of rotation",
+                pooled.label
+            );
+            state.revoked = true;
+            return false;
+        }
+
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<i64>().ok())
+        };
+        let (Some(remaining), Some(reset)) =
+            (header("x-ratelimit-remaining"), header("x-ratelimit-reset"))
+        else {
+            return true;
+        };
+        let Some(reset_at) = DateTime::from_timestamp(reset, 0) else {
+            return true;
+        };
+        let remaining = u64::try_from(remaining).unwrap_or_default();
+        if remaining == 0 {
+            debug!(
+                "{} used up its {} rate limit until {reset_at}",
+                pooled.label,
+                resource.as_str()
+            );
+        }
+        state.budgets.insert(
+            resource,
+            Budget {
+                remaining,
+                reset_at,
+            },
+        );
+        self.remaining
+            .record(remaining, &self.attributes(index, resource));
+
+        let rate_limited =
+            status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS;
+        !(rate_limited && remaining == 0)
+    }
+
+    pub(super) fn stats(&self) -> Vec<TokenStats> {
+        self.tokens
+            .iter()
+            .map(|pooled| {
+                let state = pooled.state.lock().unwrap_or_else(|e| e.into_inner());
+                let mut rate_limits: Vec<RateLimitStats> = state
+                    .budgets
+                    .iter()
+                    .map(|(resource, budget)| RateLimitStats {
+                        resource: resource.as_str().to_string(),
+                        remaining: budget.remaining,
+                        reset_at: budget.reset_at,
+                    })
+                    .collect();
+                rate_limits.sort_by(|a, b| a.resource.cmp(&b.resource));
+                TokenStats {
+                    label: pooled.label.clone(),
+                    revoked: state.revoked,
+                    requests: state.requests,
+                    rate_limits,
+                }
+            })
+            .collect()
+    }
+
+    fn attributes(&self, index: usize, resource: Resource) -> [KeyValue; 2] {
+        [
+            KeyValue::new("token", self.tokens[index].label.clone()),
+            KeyValue::new("resource", resource.as_str()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit(remaining: u64) -> HeaderMap {
+        let reset = (Utc::now() + chrono::TimeDelta::hours(1)).timestamp();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", remaining.into());
+        headers.insert("x-ratelimit-reset", reset.into());
+        headers
+    }
+
+    #[test]
+    fn test_pool_rotation() {
+        let pool = TokenPool::new(&["a".to_string(), "b".to_string(), "c".to_string()]);
+        assert!(pool.record(0, Resource::Rest, StatusCode::OK, &rate_limit(10)));
+        assert!(pool.record(1, Resource::Rest, StatusCode::OK, &rate_limit(4000)));
+        // Rate limited, so the request has to be retried with another token
+        assert!(!pool.record(2, Resource::Rest, StatusCode::FORBIDDEN, &rate_limit(0)));
+
+        // The token with the most budget left, then whichever is untried for GraphQL
+        assert_eq!(pool.pick(Resource::Rest).unwrap().1, "b");
+        assert_eq!(pool.pick(Resource::GraphQl).unwrap().0, 0);
+
+        assert!(!pool.record(
+            1,
+            Resource::Rest,
+            StatusCode::UNAUTHORIZED,
+            &HeaderMap::new(),
+        ));
+        assert_eq!(pool.pick(Resource::Rest).unwrap().1, "a");
+
+        pool.record(0, Resource::Rest, StatusCode::OK, &rate_limit(0));
+        let error = pool.pick(Resource::Rest).unwrap_err().to_string();
+        assert!(error.contains("core rate limit"), "{error}");
+
+        let stats = pool.stats();
+        assert!(stats[1].revoked);
+        assert_eq!(stats[1].requests, 1);
+        assert_eq!(stats[2].rate_limits[0].remaining, 0);
+    }
+
+    #[test]
+    fn test_fingerprint_identifies_the_token_set() {
+        let tokens = |tokens: &[&str]| {
+            TokenPool::new(&tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>())
+                .fingerprint()
+                .to_string()
+        };
+        assert_eq!(tokens(&["a", "b"]), tokens(&["b", "a"]));
+        assert_ne!(tokens(&["a", "b"]), tokens(&["a"]));
+        assert!(!tokens(&["secret"]).contains("secret"));
+    }
+
+    #[test]
+    fn test_rate_limit_error() {
+        let error = rate_limit_error(Resource::Rest, StatusCode::FORBIDDEN, &rate_limit(0));
+        assert!(matches!(
+            error,
+            Some(ForgeError::RateLimited {
+                reset_at: Some(_),
+                ..
+            })
+        ));
+        assert!(rate_limit_error(
+            Resource::GraphQl,
+            StatusCode::TOO_MANY_REQUESTS,
+            &rate_limit(0)
+        )
+        .is_some());
+        // Forbidden for lack of permission, not budget
+        assert!(rate_limit_error(Resource::Rest, StatusCode::FORBIDDEN, &rate_limit(10)).is_none());
+        assert!(rate_limit_error(Resource::Rest, StatusCode::OK, &rate_limit(0)).is_none());
+    }
+}
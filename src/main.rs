@@ -1,192 +1,662 @@
 mod api;
 mod config;
+mod corpus;
 mod database;
+mod forge;
+mod git;
 mod github;
 mod ml;
+mod retention;
+mod telemetry;
 
+use api::types::{ProcessRepositoriesResponse, ProcessUserResponse};
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
-use color_eyre::eyre::{Result, WrapErr};
-use opentelemetry::global;
-use opentelemetry::trace::TracerProvider;
-use opentelemetry_otlp::{LogExporter, MetricExporter, Protocol, SpanExporter, WithExportConfig};
-use opentelemetry_sdk::logs::SdkLoggerProvider;
-use opentelemetry_sdk::metrics::SdkMeterProvider;
-use opentelemetry_sdk::trace::SdkTracerProvider;
-use opentelemetry_sdk::Resource;
-use std::sync::{Arc, LazyLock};
-use tracing::info;
-use tracing::subscriber::set_global_default;
-use tracing_opentelemetry::OpenTelemetryLayer;
-use tracing_subscriber::{prelude::*, Registry};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use corpus::{CorpusFormat, ExportOptions};
+use database::CommitFilter;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
-    command: Option<Commands>,
+    command: Commands,
+    #[command(flatten)]
+    config: config::ConfigArgs,
+    /// Print the summary of what a command did as JSON
+    #[arg(long, global = true)]
+    json: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Ingest a repository's branch history for every author
     Process {
         owner: String,
         repo: String,
-        #[arg(default_value = "main")]
-        branch: String,
+        /// Branch to walk; defaults to the repository's default branch
+        branch: Option<String>,
+        /// Only ingest commits made at or after this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        from: Option<DateTime<Utc>>,
+        /// Only ingest commits made at or before this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        to: Option<DateTime<Utc>>,
     },
+    /// Ingest every matching repository in an organization
+    Org {
+        org: String,
+        /// Also ingest archived repositories
+        #[arg(long)]
+        include_archived: bool,
+        /// Also ingest forks
+        #[arg(long)]
+        include_forks: bool,
+        /// Only ingest repositories with this primary language
+        #[arg(long)]
+        language: Option<String>,
+        /// Only ingest repositories tagged with this topic
+        #[arg(long)]
+        topic: Option<String>,
+        /// Only ingest commits made at or after this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        from: Option<DateTime<Utc>>,
+        /// Only ingest commits made at or before this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        to: Option<DateTime<Utc>>,
+    },
+    /// Ingest a branch of a local clone or bare repository without the GitHub API
+    Local {
+        /// Path to the repository
+        path: PathBuf,
+        /// Branch to walk; defaults to the branch HEAD points to
+        #[arg(long)]
+        branch: Option<String>,
+        /// Organization to store the commits under
+        #[arg(long, default_value = "local")]
+        org: String,
+        /// Repository name to store the commits under; defaults to the directory name
+        #[arg(long)]
+        repo: Option<String>,
+        /// Only ingest commits made at or after this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        from: Option<DateTime<Utc>>,
+        /// Only ingest commits made at or before this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        to: Option<DateTime<Utc>>,
+    },
+    /// Ingest the commits a user contributed
     User {
         username: String,
+        /// Forge the user is on
+        #[arg(long, value_enum, default_value_t)]
+        forge: forge::ForgeKind,
+        /// Start of the contribution range (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        from: Option<DateTime<Utc>>,
+        /// End of the contribution range (RFC 3339 or YYYY-MM-DD)
+        #[arg(long, value_parser = github::parse_date)]
+        to: Option<DateTime<Utc>>,
+        /// Walk every year the user has contributions in
+        #[arg(long, conflicts_with_all = ["from", "to"])]
+        full_history: bool,
     },
+    /// Run the API server until it's interrupted
     Serve,
+    /// Export processed commits to a JSONL or Parquet file
+    Export {
+        /// File to write
+        output: PathBuf,
+        /// Output format, inferred from the file extension when omitted
+        #[arg(long, value_enum)]
+        format: Option<CorpusFormat>,
+        /// Only export commits from this organization or user
+        #[arg(long)]
+        org: Option<String>,
+        /// Only export commits from repositories with this name
+        #[arg(long)]
+        repo: Option<String>,
+        /// Leave commit patches out of the export
+        #[arg(long)]
+        no_patches: bool,
+        /// Leave embeddings out of the export
+        #[arg(long)]
+        no_embeddings: bool,
+    },
+    /// Import commits from a JSONL or Parquet export
+    Import {
+        /// File to read
+        input: PathBuf,
+        /// Input format, inferred from the file extension when omitted
+        #[arg(long, value_enum)]
+        format: Option<CorpusFormat>,
+    },
+    /// Attribute GitHub commits stored before their authors were recorded, so
+    /// deleting a user finds them; run once after upgrading
+    BackfillAuthors,
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
 }
 
-static RESOURCE: LazyLock<Resource> = LazyLock::new(|| {
-    Resource::builder()
-        .with_service_name("basic-otlp-example-http")
-        .build()
-});
-
-fn init_logs() -> SdkLoggerProvider {
-    let exporter = LogExporter::builder()
-        .with_http()
-        .with_protocol(Protocol::HttpBinary)
-        .build()
-        .expect("Failed to create log exporter");
+/// How the process exits, so cron jobs and CI can tell failures apart. clap exits
+/// with 2 on invalid arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    Success = 0,
+    Failure = 1,
+    Config = 3,
+    NotFound = 4,
+    RateLimited = 5,
+    Upstream = 6,
+}
 
-    SdkLoggerProvider::builder()
-        .with_batch_exporter(exporter)
-        .with_resource(RESOURCE.clone())
-        .build()
+impl Exit {
+    /// Classifies a failed command by the forge error behind it, if any
+    fn of(report: &color_eyre::Report) -> Self {
+        match report.downcast_ref::<forge::ForgeError>() {
+            Some(forge::ForgeError::NotFound(_)) => Self::NotFound,
+            Some(forge::ForgeError::RateLimited { .. }) => Self::RateLimited,
+            Some(forge::ForgeError::Upstream { .. }) => Self::Upstream,
+            None => Self::Failure,
+        }
+    }
 }
 
-fn init_traces() -> SdkTracerProvider {
-    let exporter = SpanExporter::builder()
-        .with_http()
-        .with_protocol(Protocol::HttpBinary) //can be changed to `Protocol::HttpJson` to export in JSON format
-        .build()
-        .expect("Failed to create trace exporter");
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
 
-    SdkTracerProvider::builder()
-        .with_batch_exporter(exporter)
-        .with_resource(RESOURCE.clone())
-        .build()
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the effective configuration, from every layer, with secrets redacted
+    Show {
+        #[arg(long, value_enum, default_value_t)]
+        format: ConfigFormat,
+    },
 }
 
-fn init_metrics() -> SdkMeterProvider {
-    let exporter = MetricExporter::builder()
-        .with_http()
-        .with_protocol(Protocol::HttpBinary) //can be changed to `Protocol::HttpJson` to export in JSON format
-        .build()
-        .expect("Failed to create metric exporter");
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum ConfigFormat {
+    #[default]
+    Toml,
+    Yaml,
+}
 
-    SdkMeterProvider::builder()
-        .with_periodic_exporter(exporter)
-        .with_resource(RESOURCE.clone())
-        .build()
+fn resolve_format(path: &Path, format: Option<CorpusFormat>) -> Result<CorpusFormat> {
+    format
+        .or_else(|| CorpusFormat::from_path(path))
+        .ok_or_else(|| {
+            eyre!(
+                "Can't infer format from {}, pass --format jsonl or --format parquet",
+                path.display()
+            )
+        })
 }
 
-#[tracing::instrument]
-fn test_function() {
-    tracing::info!("inside the test function");
-    println!("test");
+async fn export_corpus(
+    db: &database::MongoDb,
+    output: &Path,
+    format: Option<CorpusFormat>,
+    options: ExportOptions,
+) -> Result<()> {
+    let format = resolve_format(output, format)?;
+    let file =
+        File::create(output).wrap_err_with(|| format!("Failed to create {}", output.display()))?;
+    let mut writer = BufWriter::new(file);
+    let count = corpus::export_commits(db, format, &options, |chunk| {
+        std::future::ready(
+            writer
+                .write_all(&chunk)
+                .wrap_err_with(|| format!("Failed to write {}", output.display())),
+        )
+    })
+    .await?;
+    writer
+        .flush()
+        .wrap_err_with(|| format!("Failed to write {}", output.display()))?;
+    info!("Exported {count} commits to {}", output.display());
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let logger_provider = init_logs();
+async fn import_corpus(
+    config: &config::Config,
+    db: &database::MongoDb,
+    input: &Path,
+    format: Option<CorpusFormat>,
+) -> Result<()> {
+    let format = resolve_format(input, format)?;
+    let file = File::open(input).wrap_err_with(|| format!("Failed to open {}", input.display()))?;
+    // Only needed for records exported without embeddings
+    let embedder = ml::MachineLearning::new(config)
+        .inspect_err(|e| {
+            warn!("Records without embeddings will be skipped, since they can't be embedded: {e}")
+        })
+        .ok();
+    let report = corpus::import_file(db, embedder.as_ref(), format, file).await?;
+    info!(
+        "Imported {} commits from {}, skipped {} without embeddings",
+        report.imported,
+        input.display(),
+        report.skipped
+    );
+    Ok(())
+}
 
-    //I think these are enabled by default but Ill leave this here just in case you want to enable
-    //it.
-    //https://github.com/open-telemetry/opentelemetry-rust/issues/761
-    //let filter_otel = EnvFilter::new("info")
-    //.add_directive("hyper=off".parse().unwrap())
-    //.add_directive("opentelemetry=off".parse().unwrap())
-    //.add_directive("tonic=off".parse().unwrap())
-    //.add_directive("h2=off".parse().unwrap())
-    //.add_directive("reqwest=off".parse().unwrap());
+/// Prints the configuration every layer makes up, followed by whatever is wrong
+/// with it, so an invalid configuration can be inspected too
+fn show_config(settings: config::Settings, format: ConfigFormat) -> Result<Exit> {
+    let (config, problems) = config::Config::check(settings.clone());
+    // Defaults under the layers, so invalid values show up as they were given
+    let settings = config::Settings::from(&config).merge(settings).redacted();
+    let text = match format {
+        ConfigFormat::Toml => toml::to_string(&settings)?,
+        ConfigFormat::Yaml => serde_yaml_ng::to_string(&settings)?,
+    };
+    print!("{text}");
+    if problems.is_empty() {
+        return Ok(Exit::Success);
+    }
+    eprintln!("Invalid configuration:");
+    for problem in &problems {
+        eprintln!("  - {problem}");
+    }
+    Ok(Exit::Config)
+}
 
-    let tracer_provider = init_traces();
+fn app_state(config: config::Config, db: database::MongoDb) -> Result<Arc<api::types::AppState>> {
+    let github_client = github::GitHubClient::new(config.clone())?;
+    let gitlab_client = forge::GitLabClient::new(&config)?;
+    let gitea_client = forge::GiteaClient::new(&config)?;
+    let machine_learning =
+        ml::MachineLearning::new(&config).wrap_err("Failed to initialize embedding generator")?;
 
-    let otel_layer = OpenTelemetryLayer::new(tracer_provider.tracer("bountybot")); // .with_filter(filter_otel);
+    Ok(Arc::new(api::types::AppState {
+        db,
+        config,
+        machine_learning,
+        github_client,
+        gitlab_client,
+        gitea_client,
+        webhooks: api::webhooks::WebhookQueue::new(),
+        rate_limiter: api::auth::RateLimiter::default(),
+        provider_checks: api::health::ProviderChecks::default(),
+        metrics: telemetry::Metrics::new(),
+    }))
+}
 
-    //u can comment this and end of following line out to disable the console printing.
-    let fmt_layer = tracing_subscriber::fmt::layer().pretty();
+/// Progress bar for `user`, drawn on stderr below the logs when it's a terminal
+struct UserProgress(ProgressBar);
+
+impl UserProgress {
+    fn new() -> Result<Self> {
+        let bar =
+            telemetry::progress_bar(ProgressBar::new(0).with_style(ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:40} {pos}/{len} commits {wide_msg}",
+            )?));
+        bar.enable_steady_tick(Duration::from_millis(200));
+        Ok(Self(bar))
+    }
+}
 
-    let subscriber = Registry::default().with(otel_layer).with(fmt_layer); //<-;
-    set_global_default(subscriber).expect("Failed to set subscriber.");
+impl api::process::IngestProgress for UserProgress {
+    fn started(&self, repositories: usize, expected: u64) {
+        self.0.set_length(expected);
+        self.0
+            .set_message(format!("in {repositories} repositories"));
+    }
 
-    //sets globals
-    global::set_tracer_provider(tracer_provider.clone());
+    fn repository(&self, repository: &str) {
+        self.0.set_message(repository.to_string());
+    }
 
-    let meter_provider = init_metrics();
-    global::set_meter_provider(meter_provider.clone());
+    fn commit_stored(&self) {
+        // The contribution counts commits are expected from can be low
+        if self
+            .0
+            .length()
+            .is_some_and(|length| self.0.position() >= length)
+        {
+            self.0.inc_length(1);
+        }
+        self.0.inc(1);
+    }
 
-    color_eyre::install().wrap_err("Failed to install color-eyre error handler")?;
+    fn activities(&self) {
+        self.0
+            .set_message("pull requests, reviews, issues and discussions");
+    }
+}
 
-    test_function();
+async fn ingest_user(
+    config: config::Config,
+    db: database::MongoDb,
+    forge: forge::ForgeKind,
+    username: &str,
+    period: github::ContributionPeriod,
+) -> Result<ProcessUserResponse> {
+    let app_state = app_state(config, db)?;
+    let forge = app_state.forge(forge)?;
+    let progress = UserProgress::new()?;
+    let response = api::process::ingest_user(&app_state, forge, username, &period, &progress).await;
+    progress.0.finish_and_clear();
+    response
+}
 
-    // Register a shutdown handler
-    // tokio::spawn(async move {
-    //     tokio::signal::ctrl_c().await.unwrap();
-    //     // Gracefully shut down the tracer provider
-    //     drop(provider);
-    // });
+/// Prints what a command did, as JSON with `--json`
+fn print_summary<T: Serialize>(
+    json: bool,
+    response: &T,
+    summary: impl FnOnce() -> String,
+) -> Result<()> {
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(response)?),
+        false => println!("{}", summary()),
+    }
+    Ok(())
+}
 
-    // Load environment variables from .env file
-    dotenv::dotenv().ok();
+fn repositories_summary(response: &ProcessRepositoriesResponse) -> String {
+    format!(
+        "Walked {} repositories: {} commits seen, {} newly stored, by {} authors; {} \
+        commits aren't linked to a GitHub account",
+        response.repositories.len(),
+        response.commits_seen,
+        response.commits_processed,
+        response.authors.len(),
+        response.unattributed_commits
+    )
+}
 
-    let config = config::Config::new()?;
-    let db = database::MongoDb::new(config.clone())
-        .await
-        .wrap_err("Failed to initialize MongoDB connection")?;
-    let github_client = github::GitHubClient::new(config.clone());
-    let machine_learning =
-        ml::MachineLearning::new().wrap_err("Failed to initialize embedding generator")?;
+/// Resolves on Ctrl+C or SIGTERM, so `serve` stops cleanly and telemetry is flushed
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down the API server");
+}
 
+async fn serve(config: config::Config, db: database::MongoDb) -> Result<()> {
     info!("Starting API server on {}:{}", config.host, config.port);
-    let app_state = Arc::new(api::types::AppState {
-        db,
-        config: config.clone(),
-        machine_learning,
-        github_client,
-    });
+    if config.api_admin_key.is_none() {
+        warn!("API_ADMIN_KEY is unset, so only API keys created earlier are accepted");
+    }
+    let app_state = app_state(config.clone(), db)?;
+    tokio::spawn(retention::run_sweeper(app_state.clone()));
+    tokio::spawn(api::webhooks::run_worker(app_state.clone()));
     let app = api::create_router(app_state);
 
     let listener = tokio::net::TcpListener::bind((config.host, config.port))
         .await
         .wrap_err_with(|| format!("Failed to bind server to {}:{}", config.host, config.port))?;
     axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .wrap_err("Failed to start API server")
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Err(e) = color_eyre::install() {
+        eprintln!("Failed to install color-eyre error handler: {e}");
+        return Exit::Failure.into();
+    }
+    // Load environment variables from .env file before anything reads them
+    dotenv::dotenv().ok();
+
+    let settings = match cli.config.layered() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return Exit::Config.into();
+        }
+    };
+    if let Commands::Config {
+        command: ConfigCommands::Show { format },
+    } = cli.command
+    {
+        return show_config(settings, format)
+            .unwrap_or_else(|e| {
+                eprintln!("Error: {e:?}");
+                Exit::Failure
+            })
+            .into();
+    }
+    let config = match config::Config::from_settings(settings) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return Exit::Config.into();
+        }
+    };
+
+    let telemetry = match telemetry::Telemetry::init(&config.telemetry) {
+        Ok(telemetry) => telemetry,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return Exit::Config.into();
+        }
+    };
+    let exit = match run(cli, config).await {
+        Ok(()) => Exit::Success,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            Exit::of(&e)
+        }
+    };
+    telemetry.shutdown();
+    exit.into()
+}
+
+async fn run(cli: Cli, config: config::Config) -> Result<()> {
+    let db = database::MongoDb::new(config.clone())
         .await
-        .wrap_err("Failed to start API server")?;
+        .wrap_err("Failed to initialize MongoDB connection")?;
 
-    tracer_provider.shutdown()?;
-    meter_provider.shutdown()?;
-    logger_provider.shutdown()?;
+    match cli.command {
+        Commands::Export {
+            output,
+            format,
+            org,
+            repo,
+            no_patches,
+            no_embeddings,
+        } => {
+            let options = ExportOptions {
+                filter: CommitFilter {
+                    org,
+                    repo,
+                    ..CommitFilter::default()
+                },
+                include_patches: !no_patches,
+                include_embeddings: !no_embeddings,
+            };
+            export_corpus(&db, &output, format, options).await?;
+        }
+        Commands::Import { input, format } => import_corpus(&config, &db, &input, format).await?,
+        Commands::Process {
+            owner,
+            repo,
+            branch,
+            from,
+            to,
+        } => {
+            let app_state = app_state(config, db)?;
+            let response = api::process::ingest_repository(
+                &app_state,
+                &owner,
+                &repo,
+                branch.as_deref(),
+                from,
+                to,
+            )
+            .await?;
+            print_summary(cli.json, &response, || repositories_summary(&response))?;
+        }
+        Commands::Org {
+            org,
+            include_archived,
+            include_forks,
+            language,
+            topic,
+            from,
+            to,
+        } => {
+            let app_state = app_state(config, db)?;
+            let filter = github::RepositoryFilter {
+                include_archived,
+                include_forks,
+                language,
+                topic,
+            };
+            let response = api::process::ingest_org(&app_state, &org, &filter, from, to).await?;
+            print_summary(cli.json, &response, || repositories_summary(&response))?;
+        }
+        Commands::Local {
+            path,
+            branch,
+            org,
+            repo,
+            from,
+            to,
+        } => {
+            let repo = match repo {
+                Some(repo) => repo,
+                None => local_repository_name(&path)?,
+            };
+            let app_state = app_state(config, db)?;
+            let response = api::process::ingest_local_repository(
+                &app_state,
+                &path,
+                &org,
+                &repo,
+                branch.as_deref(),
+                from,
+                to,
+            )
+            .await?;
+            print_summary(cli.json, &response, || repositories_summary(&response))?;
+        }
+        Commands::User {
+            username,
+            forge,
+            from,
+            to,
+            full_history,
+        } => {
+            let period = github::ContributionPeriod::new(from, to, full_history)?;
+            let response = ingest_user(config, db, forge, &username, period).await?;
+            print_summary(cli.json, &response, || {
+                format!(
+                    "Processed {}/{} commits from {} repositories and {} pull requests, \
+                    reviews, issues, discussions and comments for {username}",
+                    response.total_processed,
+                    response.total_expected,
+                    response.repositories.len(),
+                    response.activities_processed
+                )
+            })?;
+        }
+        Commands::Serve => serve(config, db).await?,
+        Commands::BackfillAuthors => {
+            let github = github::GitHubClient::new(config)?;
+            let attributed = retention::backfill_author_logins(&db, &github).await?;
+            let response = serde_json::json!({ "attributed": attributed });
+            print_summary(cli.json, &response, || {
+                format!("Attributed {attributed} commits to their GitHub accounts")
+            })?;
+        }
+        // Handled before connecting to MongoDB
+        Commands::Config { .. } => {}
+    }
 
     Ok(())
 }
 
+/// Name of a repository directory, without the `.git` suffix of bare repositories
+fn local_repository_name(path: &Path) -> Result<String> {
+    let path = path
+        .canonicalize()
+        .wrap_err_with(|| format!("Failed to resolve {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| eyre!("Can't derive a repository name from {}", path.display()))?;
+    Ok(name.strip_suffix(".git").unwrap_or(name).to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_exit_codes() {
+        let not_found = color_eyre::Report::new(forge::ForgeError::NotFound("user ghost".into()))
+            .wrap_err("Failed to get contributed repos for user ghost");
+        assert_eq!(Exit::of(&not_found), Exit::NotFound);
+        let rate_limited = color_eyre::Report::new(forge::ForgeError::RateLimited {
+            resource: "graphql".into(),
+            reset_at: None,
+        });
+        assert_eq!(Exit::of(&rate_limited), Exit::RateLimited);
+        assert_eq!(Exit::of(&eyre!("Failed to store commit")), Exit::Failure);
+    }
+
     #[tokio::test]
     async fn test_process_repository() -> Result<()> {
-        // Load test environment variables
-        dotenv::dotenv().ok();
-
-        // Initialize real components with test configuration
-        let config = config::Config::new()?;
-        let github_client = github::GitHubClient::new(config);
+        // Recorded from octocat/Hello-World; see `github::fake` to re-record
+        let fake = github::fake::FakeGitHub::start("hello_world").await?;
+        let github_client = github::GitHubClient::new(fake.config())?;
 
-        // Use a smaller test repository
         let owner = "octocat";
         let repo = "Hello-World";
         let branch = "master";
 
         // Get commits
         let commits = github_client
-            .get_commits(owner, repo, Some(branch), None)
+            .get_commits(
+                owner,
+                repo,
+                Some(branch),
+                None,
+                &github::ContributionPeriod::default(),
+            )
             .await
             .map_err(|e| {
                 eprintln!("Failed to get commits: {e:?}");
@@ -197,13 +667,14 @@ mod tests {
 
         // Test processing the first commit
         let commit = &commits[0];
-        let _patch = github_client
+        let patch = github_client
             .get_commit_patch(owner, repo, &commit.oid)
             .await
             .map_err(|e| {
                 eprintln!("Failed to get commit patch: {:?}", e);
                 e
             })?;
+        assert!(!patch.is_empty());
 
         Ok(())
     }
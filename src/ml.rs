@@ -1,15 +1,40 @@
 use eyre::{bail, eyre, Result, WrapErr};
-use reqwest::Client;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use reqwest::{header::RETRY_AFTER, Client, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::env;
+use serde_json::{json, Value};
 use tracing::instrument;
 
-use crate::database::CommitSummary;
+mod cassette;
+
+pub use cassette::Cassette;
+
+use crate::{config::Config, database::CommitSummary};
+
+/// Gemini model used for commit and README summaries
+pub const SUMMARY_MODEL: &str = "gemini-1.5-flash-8b";
+
+/// OpenAI model used for embeddings
+pub const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// List prices in USD per million input and output tokens, for the cost metric
+const SUMMARY_MODEL_PRICES: (f64, f64) = (0.0375, 0.15);
+const EMBEDDING_MODEL_PRICES: (f64, f64) = (0.02, 0.0);
 
 #[derive(Debug, Deserialize)]
 struct GeminiResponse {
     candidates: Vec<GeminiCandidate>,
+    #[serde(rename = "usageMetadata", default)]
+    usage: Option<GeminiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiUsage {
+    #[serde(default)]
+    prompt_token_count: u64,
+    #[serde(default)]
+    candidates_token_count: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -37,6 +62,13 @@ struct OpenAIEmbeddingRequest<'a> {
 #[derive(Debug, Deserialize)]
 struct OpenAIEmbeddingResponse {
     data: Vec<OpenAIEmbedding>,
+    #[serde(default)]
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIUsage {
+    prompt_tokens: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,46 +76,243 @@ struct OpenAIEmbedding {
     embedding: Vec<f32>,
 }
 
+/// A model API answering with an error, classified so the HTTP API can pass it on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelError {
+    /// `service` is rate limiting us; it can be retried after `retry_after` seconds,
+    /// if the response said
+    RateLimited {
+        service: String,
+        retry_after: Option<u64>,
+    },
+    /// `service` answered with an unexpected error status
+    Upstream {
+        service: String,
+        status: u16,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for ModelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RateLimited {
+                service,
+                retry_after: Some(seconds),
+            } => write!(f, "{service} is rate limited; retry after {seconds}s"),
+            Self::RateLimited { service, .. } => write!(f, "{service} is rate limited"),
+            Self::Upstream {
+                service,
+                status,
+                message,
+            } => write!(f, "{service} returned {status}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+/// Start of every Gemini API URL
+pub const GEMINI_URL_PREFIX: &str = "https://generativelanguage.googleapis.com/";
+
+/// Start of every OpenAI API URL
+pub const OPENAI_URL_PREFIX: &str = "https://api.openai.com/";
+
+/// Gemini endpoint for [`SUMMARY_MODEL`]; the API key is added as a query parameter
+fn gemini_url() -> String {
+    format!("{GEMINI_URL_PREFIX}v1beta/models/{SUMMARY_MODEL}:generateContent")
+}
+
 pub struct MachineLearning {
     client: Client,
     gemini_api_key: String,
     openai_api_key: String,
+    /// Replays (or records) responses instead of only calling the APIs
+    cassette: Option<Cassette>,
+    tokens: Counter<u64>,
+    cost: Counter<f64>,
 }
 
 impl MachineLearning {
-    #[instrument]
-    pub fn new() -> Result<Self> {
-        let gemini_api_key =
-            env::var("GEMINI_API_KEY").wrap_err("GEMINI_API_KEY environment variable not set")?;
-        let openai_api_key =
-            env::var("OPENAI_API_KEY").wrap_err("OPENAI_API_KEY environment variable not set")?;
+    /// Calls the APIs directly, or through the cassette in `ml_cassette_dir` if set
+    #[instrument(skip(config))]
+    pub fn new(config: &Config) -> Result<Self> {
+        let cassette = config.ml_cassette_dir.clone().map(Cassette::open);
+        Self::with_cassette(
+            config.gemini_api_key.clone(),
+            config.openai_api_key.clone(),
+            cassette,
+        )
+    }
 
+    /// API keys are only required when the APIs are actually called, i.e. not when
+    /// replaying a cassette
+    pub fn with_cassette(
+        gemini_api_key: Option<String>,
+        openai_api_key: Option<String>,
+        cassette: Option<Cassette>,
+    ) -> Result<Self> {
+        let replaying = cassette
+            .as_ref()
+            .is_some_and(|cassette| !cassette.is_recording());
+        let api_key = |key: Option<String>, name: &str| match key {
+            Some(key) => Ok(key),
+            None if replaying => Ok(String::new()),
+            None => Err(eyre!("{name} isn't set")),
+        };
+
+        let meter = global::meter("github-research-rs");
         Ok(Self {
             client: Client::new(),
-            gemini_api_key,
-            openai_api_key,
+            gemini_api_key: api_key(gemini_api_key, "gemini_api_key")?,
+            openai_api_key: api_key(openai_api_key, "openai_api_key")?,
+            cassette,
+            tokens: meter
+                .u64_counter("llm.tokens")
+                .with_description("Tokens sent to and generated by model APIs")
+                .build(),
+            cost: meter
+                .f64_counter("llm.cost")
+                .with_unit("USD")
+                .with_description("Estimated model API spend at list prices")
+                .build(),
         })
     }
 
+    /// Counts the tokens a call used and what they cost
+    fn record_usage(&self, model: &'static str, prices: (f64, f64), input: u64, output: u64) {
+        for (direction, count) in [("input", input), ("output", output)] {
+            if count > 0 {
+                let attributes = [
+                    KeyValue::new("model", model),
+                    KeyValue::new("direction", direction),
+                ];
+                self.tokens.add(count, &attributes);
+            }
+        }
+        let cost = (input as f64 * prices.0 + output as f64 * prices.1) / 1_000_000.0;
+        self.cost.add(cost, &[KeyValue::new("model", model)]);
+    }
+
+    fn record_gemini_usage(&self, response: &GeminiResponse) {
+        if let Some(usage) = &response.usage {
+            self.record_usage(
+                SUMMARY_MODEL,
+                SUMMARY_MODEL_PRICES,
+                usage.prompt_token_count,
+                usage.candidates_token_count,
+            );
+        }
+    }
+
+    /// Checks both APIs accept our keys by looking up the models we use, which is
+    /// free. Nothing is checked when replaying a cassette.
+    #[instrument(skip(self))]
+    pub async fn check(&self) -> Result<()> {
+        if self.cassette.as_ref().is_some_and(|c| !c.is_recording()) {
+            return Ok(());
+        }
+        let checks = [
+            (
+                "OpenAI",
+                self.client
+                    .get(format!("{OPENAI_URL_PREFIX}v1/models/{EMBEDDING_MODEL}"))
+                    .bearer_auth(&self.openai_api_key),
+            ),
+            (
+                "Gemini API",
+                self.client
+                    .get(format!("{GEMINI_URL_PREFIX}v1beta/models/{SUMMARY_MODEL}"))
+                    .query(&[("key", &self.gemini_api_key)]),
+            ),
+        ];
+        for (service, request) in checks {
+            let response = request
+                .send()
+                .await
+                .wrap_err_with(|| format!("Failed to reach {service}"))?;
+            let status = response.status();
+            if !status.is_success() {
+                bail!("{service} rejected the model lookup: {status}");
+            }
+        }
+        Ok(())
+    }
+
+    /// POSTs `body` to `url` and returns the text of a successful response, failing
+    /// with a [`ModelError`] otherwise. `authorize` adds the credentials, which are
+    /// kept out of `url` so they never end up in a cassette.
+    async fn post(
+        &self,
+        service: &str,
+        url: &str,
+        body: &Value,
+        authorize: impl FnOnce(RequestBuilder) -> RequestBuilder,
+    ) -> Result<String> {
+        let (status, text, retry_after) = match self.cassette.as_ref().filter(|c| !c.is_recording())
+        {
+            Some(cassette) => {
+                let (status, text) = cassette.replay(url, body)?;
+                (status, text, None)
+            }
+            None => {
+                let response = authorize(self.client.post(url))
+                    .json(body)
+                    .send()
+                    .await
+                    .wrap_err_with(|| format!("Failed to send request to {service}"))?;
+                let status = response.status();
+                let retry_after = response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok()?.parse().ok());
+                let text = response
+                    .text()
+                    .await
+                    .wrap_err_with(|| format!("Failed to get response text from {service}"))?;
+
+                // Errors such as rate limits are transient and shouldn't be replayed
+                if let Some(cassette) = self.cassette.as_ref().filter(|_| status.is_success()) {
+                    cassette.record(url, body, status.as_u16(), &text)?;
+                }
+                (status.as_u16(), text, retry_after)
+            }
+        };
+
+        if status == StatusCode::TOO_MANY_REQUESTS.as_u16() {
+            return Err(ModelError::RateLimited {
+                service: service.to_string(),
+                retry_after,
+            }
+            .into());
+        }
+        if !(200..300).contains(&status) {
+            return Err(ModelError::Upstream {
+                service: service.to_string(),
+                status,
+                message: text,
+            }
+            .into());
+        }
+        Ok(text)
+    }
+
     #[instrument(skip(self, text))]
     pub async fn get_embedding(&self, text: &str) -> Result<Vec<f32>> {
-        let request = OpenAIEmbeddingRequest {
-            model: "text-embedding-3-small",
+        let request = serde_json::to_value(OpenAIEmbeddingRequest {
+            model: EMBEDDING_MODEL,
             input: text,
             encoding_format: "float",
-        };
+        })?;
 
         let response_text = self
-            .client
-            .post("https://api.openai.com/v1/embeddings")
-            .header("Authorization", format!("Bearer {}", self.openai_api_key))
-            .json(&request)
-            .send()
-            .await
-            .wrap_err("Failed to send embedding request to OpenAI")?
-            .text()
-            .await
-            .wrap_err("Failed to get response text from OpenAI")?;
+            .post(
+                "OpenAI",
+                &format!("{OPENAI_URL_PREFIX}v1/embeddings"),
+                &request,
+                |request| request.bearer_auth(&self.openai_api_key),
+            )
+            .await?;
 
         let response = serde_json::from_str::<OpenAIEmbeddingResponse>(&response_text)
             .wrap_err_with(|| {
@@ -92,6 +321,14 @@ impl MachineLearning {
                     text, response_text
                 )
             })?;
+        if let Some(usage) = &response.usage {
+            self.record_usage(
+                EMBEDDING_MODEL,
+                EMBEDDING_MODEL_PRICES,
+                usage.prompt_tokens,
+                0,
+            );
+        }
 
         Ok(response.data[0].embedding.clone())
     }
@@ -147,22 +384,15 @@ impl MachineLearning {
         });
 
         let response = self
-            .client
-            .post(format!(
-                "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash-8b:generateContent?key={}",
-                self.gemini_api_key
-            ))
-            .json(&request)
-            .send()
-            .await
-            .wrap_err("Failed to send request to Gemini API")?
-            .text()
-            .await
-            .wrap_err("Failed to get response text from Gemini API")?;
+            .post("Gemini API", &gemini_url(), &request, |request| {
+                request.query(&[("key", &self.gemini_api_key)])
+            })
+            .await?;
 
         // First parse the Gemini response structure
         let gemini_response: GeminiResponse = serde_json::from_str(&response)
             .wrap_err_with(|| format!("Failed to parse Gemini response: {}", response))?;
+        self.record_gemini_usage(&gemini_response);
 
         // Get the first candidate's text
         let summary_text = gemini_response
@@ -224,22 +454,15 @@ impl MachineLearning {
         });
 
         let response = self
-            .client
-            .post(format!(
-                "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash-8b:generateContent?key={}",
-                self.gemini_api_key
-            ))
-            .json(&request)
-            .send()
-            .await
-            .wrap_err("Failed to send request to Gemini API")?
-            .text()
-            .await
-            .wrap_err("Failed to get response text from Gemini API")?;
+            .post("Gemini API", &gemini_url(), &request, |request| {
+                request.query(&[("key", &self.gemini_api_key)])
+            })
+            .await?;
 
         // Parse the Gemini response structure
         let gemini_response: GeminiResponse = serde_json::from_str(&response)
             .wrap_err_with(|| format!("Failed to parse Gemini response: {}", response))?;
+        self.record_gemini_usage(&gemini_response);
 
         // Get the first candidate's text
         let summary = gemini_response
@@ -282,12 +505,23 @@ impl MachineLearning {
 mod tests {
     use super::*;
     use dotenv::dotenv;
+    use std::env;
 
-    #[tokio::test]
-    async fn test_gemini_summarization() -> Result<()> {
+    /// Replays the responses recorded in `src/ml/cassettes`, with the API keys in the
+    /// environment to record them
+    fn machine_learning() -> Result<MachineLearning> {
         dotenv().ok();
+        let cassette = Cassette::open(concat!(env!("CARGO_MANIFEST_DIR"), "/src/ml/cassettes"));
+        MachineLearning::with_cassette(
+            env::var("GEMINI_API_KEY").ok(),
+            env::var("OPENAI_API_KEY").ok(),
+            Some(cassette),
+        )
+    }
 
-        let generator = MachineLearning::new()?;
+    #[tokio::test]
+    async fn test_gemini_summarization() -> Result<()> {
+        let generator = machine_learning()?;
         let sample_code = r#"
         fn add(a: i32, b: i32) -> i32 {
             a + b
@@ -319,9 +553,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_embedding_generation() -> Result<()> {
-        dotenv().ok();
-
-        let generator = MachineLearning::new()?;
+        let generator = machine_learning()?;
         let text = "Hello, world!";
 
         let embedding = generator.get_embedding(text).await?;
@@ -355,9 +587,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_readme_summarization() -> Result<()> {
-        dotenv().ok();
-
-        let generator = MachineLearning::new()?;
+        let generator = machine_learning()?;
         let sample_readme = r#"
         # Sample Project
 
@@ -377,4 +607,59 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_only_successful_responses_are_recorded() -> Result<()> {
+        use axum::{http::StatusCode, routing::post, Json, Router};
+
+        let app = Router::new()
+            .route("/ok", post(|| async { Json(json!({"ok": true})) }))
+            .route(
+                "/limited",
+                post(|| async {
+                    (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [("retry-after", "20")],
+                        "slow down",
+                    )
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = tempfile::tempdir()?;
+        let mut ml = machine_learning()?;
+        ml.cassette = Some(Cassette::recording(dir.path()));
+        let body = json!({"input": "hello"});
+
+        let error = ml
+            .post("Test", &format!("{url}/limited"), &body, |request| request)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ModelError>(),
+            Some(&ModelError::RateLimited {
+                service: "Test".to_string(),
+                retry_after: Some(20),
+            })
+        );
+        let text = ml
+            .post("Test", &format!("{url}/ok"), &body, |request| request)
+            .await?;
+        server.abort();
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        // Replaying needs no server, and the error was never stored
+        ml.cassette = Some(Cassette::open(dir.path()));
+        let replayed = ml
+            .post("Test", &format!("{url}/ok"), &body, |request| request)
+            .await?;
+        assert_eq!(replayed, text);
+        assert!(ml
+            .post("Test", &format!("{url}/limited"), &body, |request| request)
+            .await
+            .is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,181 @@
+use eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{env, fs, path::PathBuf};
+use tracing::debug;
+
+/// Recorded responses of the summary and embedding APIs, replayed instead of calling
+/// them so tests are deterministic and need no API keys.
+///
+/// Each exchange is a JSON file named after the hash of its URL and normalized
+/// request body, and keeps the request next to the response, so a prompt change
+/// shows up as a new cassette in the diff. Setting `ML_CASSETTE_RECORD=1` calls the
+/// real APIs and (re)writes the cassettes instead.
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    dir: PathBuf,
+    recording: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Exchange {
+    request: Value,
+    status: u16,
+    /// JSON responses are stored as JSON so they diff well
+    response: Value,
+}
+
+impl Cassette {
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        let recording = env::var("ML_CASSETTE_RECORD").is_ok_and(|value| value == "1");
+        Self {
+            dir: dir.into(),
+            recording,
+        }
+    }
+
+    /// Records into `dir` regardless of `ML_CASSETTE_RECORD`
+    #[cfg(test)]
+    pub(super) fn recording(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            recording: true,
+        }
+    }
+
+    /// The cassettes recorded in `src/ml/cassettes`, copied into a directory of their
+    /// own with every response from a URL starting with `url_prefix` replaced by an
+    /// error with `status`
+    #[cfg(test)]
+    pub fn recorded_failing(url_prefix: &str, status: u16) -> Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        let recorded = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/ml/cassettes");
+        for entry in fs::read_dir(recorded)? {
+            let entry = entry?;
+            let mut exchange: Exchange = serde_json::from_str(&fs::read_to_string(entry.path())?)?;
+            let url = exchange.request["url"].as_str().unwrap_or_default();
+            if url.starts_with(url_prefix) {
+                exchange.status = status;
+                exchange.response = serde_json::json!({"error": {"code": status}});
+            }
+            fs::write(
+                dir.path().join(entry.file_name()),
+                serde_json::to_string(&exchange)?,
+            )?;
+        }
+        Ok(dir)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// The recorded status and response to POSTing `body` to `url`
+    pub(super) fn replay(&self, url: &str, body: &Value) -> Result<(u16, String)> {
+        let (request, path) = self.locate(url, body);
+        let text = fs::read_to_string(&path).map_err(|_| {
+            eyre!(
+                "No recorded response for {url} at {}; record it with ML_CASSETTE_RECORD=1\n\
+                Request: {request}",
+                path.display()
+            )
+        })?;
+        let exchange: Exchange = serde_json::from_str(&text)
+            .wrap_err_with(|| format!("Invalid cassette {}", path.display()))?;
+        debug!("Replaying {}", path.display());
+        let text = match exchange.response {
+            Value::String(text) => text,
+            response => response.to_string(),
+        };
+        Ok((exchange.status, text))
+    }
+
+    /// Stores the response to POSTing `body` to `url`
+    pub(super) fn record(
+        &self,
+        url: &str,
+        body: &Value,
+        status: u16,
+        response: &str,
+    ) -> Result<()> {
+        let (request, path) = self.locate(url, body);
+        let exchange = Exchange {
+            request,
+            status,
+            response: serde_json::from_str(response)
+                .unwrap_or_else(|_| Value::String(response.to_string())),
+        };
+        fs::create_dir_all(&self.dir)
+            .wrap_err_with(|| format!("Failed to create {}", self.dir.display()))?;
+        fs::write(&path, serde_json::to_string_pretty(&exchange)? + "\n")
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        debug!("Recorded {}", path.display());
+        Ok(())
+    }
+
+    /// The normalized request and the cassette it's recorded in
+    fn locate(&self, url: &str, body: &Value) -> (Value, PathBuf) {
+        let request = normalize(&serde_json::json!({ "url": url, "body": body }));
+        let hash = Sha256::digest(request.to_string().as_bytes());
+        let path = self.dir.join(format!("{}.json", hex::encode(&hash[..8])));
+        (request, path)
+    }
+}
+
+/// Sorts object keys, so building a request differently doesn't change its hash
+fn normalize(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<(&String, &Value)> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.clone(), normalize(value)))
+                    .collect::<Map<_, _>>(),
+            )
+        }
+        Value::Array(items) => Value::Array(items.iter().map(normalize).collect()),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_requests_are_keyed_by_normalized_body() {
+        let cassette = Cassette::open("cassettes");
+        let (_, path) = cassette.locate(
+            "https://example.com",
+            &json!({"a": 1, "b": [{"c": 2, "d": 3}]}),
+        );
+        let (_, reordered) = cassette.locate(
+            "https://example.com",
+            &json!({"b": [{"d": 3, "c": 2}], "a": 1}),
+        );
+        let (_, changed) = cassette.locate("https://example.com", &json!({"a": 2, "b": []}));
+        assert_eq!(path, reordered);
+        assert_ne!(path, changed);
+    }
+
+    #[test]
+    fn test_replay_returns_recorded_status() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let body = json!({"input": "hello"});
+        Cassette::recording(dir.path()).record(
+            "https://example.com",
+            &body,
+            201,
+            r#"{"ok": true}"#,
+        )?;
+
+        let (status, text) = Cassette::open(dir.path()).replay("https://example.com", &body)?;
+        assert_eq!(status, 201);
+        assert_eq!(serde_json::from_str::<Value>(&text)?, json!({"ok": true}));
+        Ok(())
+    }
+}
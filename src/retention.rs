@@ -0,0 +1,447 @@
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::Arc};
+
+use chrono::Utc;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    api::types::AppState,
+    database::{repository_key, AuditRecord, CommitFilter, DeletionReport, MongoDb},
+    forge::ForgeKind,
+    github::GitHubClient,
+};
+
+/// Deletes commits and activities older than `max_age_days`, optionally scoped to an
+/// org or repository.
+///
+/// Parsed from `[org[/repo]=]days`, e.g. `365`, `acme=90` or `acme/secret=7`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub org: Option<String>,
+    pub repo: Option<String>,
+    pub max_age_days: u32,
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (scope, days) = s.trim().rsplit_once('=').unwrap_or(("", s.trim()));
+        let max_age_days = days
+            .parse()
+            .wrap_err_with(|| format!("Invalid retention age '{days}' in policy '{s}'"))?;
+
+        let (org, repo) = match scope.split_once('/') {
+            Some((org, repo)) if !org.is_empty() && !repo.is_empty() => {
+                (Some(org.to_string()), Some(repo.to_string()))
+            }
+            Some(_) => bail!("Invalid retention scope '{scope}', expected org/repo"),
+            None if scope.is_empty() => (None, None),
+            None => (Some(scope.to_string()), None),
+        };
+
+        Ok(Self {
+            org,
+            repo,
+            max_age_days,
+        })
+    }
+}
+
+/// Formats the policy the way it's parsed
+impl fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.org {
+            Some(_) => write!(f, "{}={}", self.target(), self.max_age_days),
+            None => write!(f, "{}", self.max_age_days),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    fn target(&self) -> String {
+        match (&self.org, &self.repo) {
+            (Some(org), Some(repo)) => format!("{org}/{repo}"),
+            (Some(org), None) => org.clone(),
+            _ => "*".to_string(),
+        }
+    }
+}
+
+async fn delete_and_audit(
+    db: &MongoDb,
+    action: &str,
+    target: String,
+    filter: CommitFilter,
+    requested_by: &str,
+) -> Result<DeletionReport> {
+    let report = db.delete_matching(&filter).await?;
+    db.insert_audit_record(AuditRecord {
+        action: action.to_string(),
+        target,
+        requested_by: requested_by.to_string(),
+        report: report.clone(),
+        created_at: Utc::now(),
+    })
+    .await?;
+    Ok(report)
+}
+
+/// Attributes GitHub commits stored before `author_login` was recorded to the
+/// accounts GitHub links their authors to, walking the default branch of every
+/// repository that has such commits once. A one-off step after upgrading, since
+/// deleting a user only finds commits attributed to them; returns how many commits
+/// were attributed.
+#[instrument(skip(db, github))]
+pub async fn backfill_author_logins(db: &MongoDb, github: &GitHubClient) -> Result<u64> {
+    let mut attributed = 0;
+    for (org, repo) in db.unattributed_repositories().await? {
+        let history = async {
+            let repository = github.get_repository(&org, &repo).await?;
+            github
+                .get_branch_history(&org, &repo, &repository.default_branch, None, None)
+                .await
+        };
+        let commits = match history.await {
+            Ok(commits) => commits,
+            Err(e) => {
+                warn!("Couldn't attribute commits in {org}/{repo}: {e:#}");
+                continue;
+            }
+        };
+
+        let mut shas_by_login: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for commit in commits {
+            if let Some(login) = commit.author.login {
+                shas_by_login.entry(login).or_default().push(commit.oid);
+            }
+        }
+        for (login, shas) in shas_by_login {
+            attributed += db.attribute_commits(&org, &repo, &shas, &login).await?;
+        }
+    }
+    info!("Attributed {attributed} previously unattributed commits");
+    Ok(attributed)
+}
+
+/// Deletes everything ingested for a GitHub login, including commits only known by
+/// an email the login authored with. Commits stored before logins were recorded
+/// are found once [`backfill_author_logins`] has attributed them.
+#[instrument(skip(db))]
+pub async fn delete_user(db: &MongoDb, login: &str, requested_by: &str) -> Result<DeletionReport> {
+    let filter = CommitFilter {
+        author_login: Some(login.to_string()),
+        author_emails: db.emails_for_login(login).await?,
+        ..CommitFilter::default()
+    };
+    delete_and_audit(db, "delete_user", login.to_string(), filter, requested_by).await
+}
+
+/// Deletes everything ingested from a repository on `forge`
+#[instrument(skip(db))]
+pub async fn delete_repository(
+    db: &MongoDb,
+    forge: ForgeKind,
+    org: &str,
+    repo: &str,
+    requested_by: &str,
+) -> Result<DeletionReport> {
+    let filter = CommitFilter {
+        forge: Some(forge),
+        org: Some(org.to_string()),
+        repo: Some(repo.to_string()),
+        ..CommitFilter::default()
+    };
+    let target = repository_key(forge, org, repo);
+    delete_and_audit(db, "delete_repository", target, filter, requested_by).await
+}
+
+/// Deletes commits and activities dated more than `policy.max_age_days` ago
+#[instrument(skip(db))]
+pub async fn delete_older_than(
+    db: &MongoDb,
+    policy: &RetentionPolicy,
+    requested_by: &str,
+) -> Result<DeletionReport> {
+    let cutoff = Utc::now() - chrono::Duration::days(i64::from(policy.max_age_days));
+    let filter = CommitFilter {
+        org: policy.org.clone(),
+        repo: policy.repo.clone(),
+        committed_before: Some(cutoff),
+        ..CommitFilter::default()
+    };
+    let target = format!(
+        "{} older than {} days",
+        policy.target(),
+        policy.max_age_days
+    );
+    delete_and_audit(db, "delete_older_than", target, filter, requested_by).await
+}
+
+/// Applies the configured retention policies every `retention_sweep_interval`
+pub async fn run_sweeper(state: Arc<AppState>) {
+    let policies = &state.config.retention_policies;
+    if policies.is_empty() {
+        info!("No retention policies configured, sweeper disabled");
+        return;
+    }
+
+    let mut interval = tokio::time::interval(state.config.retention_sweep_interval);
+    loop {
+        interval.tick().await;
+        for policy in policies {
+            match delete_older_than(&state.db, policy, "retention").await {
+                Ok(report) => info!("Retention policy {} applied: {report:?}", policy.target()),
+                Err(e) => error!("Retention policy {} failed: {e:?}", policy.target()),
+            }
+        }
+    }
+}
+
+/// Parses a comma-separated list of retention policies
+pub fn parse_policies(s: &str) -> Result<Vec<RetentionPolicy>> {
+    s.split(',')
+        .filter(|policy| !policy.trim().is_empty())
+        .map(RetentionPolicy::from_str)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            ActivityDocument, CommitContentDocument, CommitDocument, CommitSummary, DocumentKind,
+            ReadmeDocument,
+        },
+        github::fake::FakeGitHub,
+        ml::EMBEDDING_MODEL,
+    };
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_parse_policies() {
+        let policies = parse_policies("365, acme=90,acme/secret=7").unwrap();
+        assert_eq!(
+            policies,
+            vec![
+                RetentionPolicy {
+                    org: None,
+                    repo: None,
+                    max_age_days: 365
+                },
+                RetentionPolicy {
+                    org: Some("acme".to_string()),
+                    repo: None,
+                    max_age_days: 90
+                },
+                RetentionPolicy {
+                    org: Some("acme".to_string()),
+                    repo: Some("secret".to_string()),
+                    max_age_days: 7
+                },
+            ]
+        );
+        let formatted: Vec<String> = policies.iter().map(ToString::to_string).collect();
+        assert_eq!(formatted, ["365", "acme=90", "acme/secret=7"]);
+        assert!(parse_policies("").unwrap().is_empty());
+        assert!(parse_policies("acme=soon").is_err());
+        assert!(parse_policies("acme/=3").is_err());
+    }
+
+    fn readme(forge: ForgeKind, owner: &str, repo: &str) -> ReadmeDocument {
+        ReadmeDocument {
+            forge,
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+            content: "# Hello".to_string(),
+            cached_at: Utc::now(),
+            etag: None,
+            summary: None,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_delete_user_finds_old_shape_documents() -> Result<()> {
+        let db = MongoDb::for_tests().await?;
+        let github = FakeGitHub::start("hello_world").await?;
+        let client = GitHubClient::new(github.config())?;
+        let summary = doc! {
+            "languages": [], "frameworks_libraries": [], "patterns": [], "specialized_knowledge": []
+        };
+
+        // As stored before authors were recorded: only GitHub knows whose they are
+        for (sha, repo) in [
+            ("7fd1a60b01f91b314f59955a4e4d4e80d8edf11d", "Hello-World"),
+            ("553c2077f0edc3d5dc5d17262f6aa498e69d6f8e", "Hello-World"),
+        ] {
+            db.insert_raw_commit(doc! {
+                "sha": sha, "message": "m", "date": "2012-03-06T23:06:50Z",
+                "org": "octocat", "repo": repo, "patch": "", "summary": summary.clone(),
+                "embedding": [],
+            })
+            .await?;
+        }
+        // Attributed, which links the email to the login
+        db.insert_raw_commit(doc! {
+            "sha": "a1", "message": "m", "date": "2024-01-01T00:00:00Z",
+            "org": "octocat", "repo": "linguist", "forge": "github",
+            "author_login": "octocat", "author_email": "octocat@nowhere.com",
+            "summary": summary.clone(),
+        })
+        .await?;
+        // Ingested from a local clone, so only the email is known
+        db.insert_raw_commit(doc! {
+            "sha": "b2", "message": "m", "date": "2024-01-01T00:00:00Z",
+            "org": "octocat", "repo": "Spoon-Knife", "forge": "local",
+            "author_email": "octocat@nowhere.com", "summary": summary.clone(),
+        })
+        .await?;
+        let activity_summary = CommitSummary {
+            languages: vec!["Markdown".to_string()],
+            ..CommitSummary::default()
+        };
+        db.insert_activity(ActivityDocument {
+            id: "PR_1".to_string(),
+            kind: DocumentKind::PullRequest,
+            org: "octocat".to_string(),
+            repo: "octo-docs".to_string(),
+            author_login: Some("octocat".to_string()),
+            url: "https://github.com/octocat/octo-docs/pull/1".to_string(),
+            title: "Fix typo".to_string(),
+            body: String::new(),
+            date: "2024-01-01T00:00:00Z".to_string(),
+            diff_stats: None,
+            merged: Some(true),
+            path: None,
+            diff_hunk: None,
+            labels: Vec::new(),
+            category: None,
+            summary: activity_summary.clone(),
+            embedding: vec![0.5],
+        })
+        .await?;
+        // The pull request's embedding is cached, next to one of text nobody deletes
+        let activity_input = serde_json::to_string(&activity_summary)?;
+        db.cache_embedding(EMBEDDING_MODEL, &activity_input, vec![0.5])
+            .await?;
+        db.cache_embedding(EMBEDDING_MODEL, "unrelated", vec![0.25])
+            .await?;
+        for (forge, repo) in [
+            (ForgeKind::GitHub, "Hello-World"),
+            (ForgeKind::Local, "Spoon-Knife"),
+            (ForgeKind::GitHub, "octo-docs"),
+        ] {
+            db.cache_readme(&readme(forge, "octocat", repo)).await?;
+        }
+        // Upgrades the raw documents the way connecting to an older database does
+        db.migrate().await?;
+
+        // One by octocat, the other by cameronmcefee
+        assert_eq!(backfill_author_logins(&db, &client).await?, 2);
+        let report = delete_user(&db, "octocat", "test").await?;
+        assert_eq!(report.commits_deleted, 3);
+        assert_eq!(report.activities_deleted, 1);
+        assert_eq!(report.embeddings_deleted, 1);
+        assert!(db
+            .get_cached_embedding(EMBEDDING_MODEL, &activity_input)
+            .await?
+            .is_none());
+        assert!(db
+            .get_cached_embedding(EMBEDDING_MODEL, "unrelated")
+            .await?
+            .is_some());
+        // Hello-World still has a commit by someone else, so its README stays
+        assert_eq!(report.readmes_deleted, 2);
+
+        let left = db.get_commits(&CommitFilter::default()).await?;
+        let shas: Vec<&str> = left.iter().map(|commit| commit.sha.as_str()).collect();
+        assert_eq!(shas, ["553c2077f0edc3d5dc5d17262f6aa498e69d6f8e"]);
+        assert!(db
+            .get_cached_readme(ForgeKind::GitHub, "octocat", "Hello-World")
+            .await?
+            .is_some());
+
+        db.drop_for_tests().await
+    }
+
+    fn commit(forge: ForgeKind, sha: &str) -> CommitDocument {
+        CommitDocument {
+            sha: sha.to_string(),
+            message: "Add greeting".to_string(),
+            date: "2024-01-01T00:00:00Z".to_string(),
+            org: "octocat".to_string(),
+            repo: "Hello-World".to_string(),
+            forge,
+            author_login: None,
+            author_name: None,
+            author_email: Some("octocat@nowhere.com".to_string()),
+            patch: String::new(),
+            summary: CommitSummary::default(),
+            embedding: vec![0.5],
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_MONGO_URI"]
+    async fn test_same_repository_on_two_forges() -> Result<()> {
+        let db = MongoDb::for_tests().await?;
+        // A mirror: the same commit in octocat/Hello-World on GitHub and GitLab
+        let sha = "7fd1a60b01f91b314f59955a4e4d4e80d8edf11d";
+        db.insert_commit_content(CommitContentDocument {
+            sha: sha.to_string(),
+            patch: String::new(),
+            summary: CommitSummary::default(),
+            embedding: vec![0.5],
+            repositories: Vec::new(),
+        })
+        .await?;
+        for forge in [ForgeKind::GitHub, ForgeKind::GitLab] {
+            assert!(
+                !db.commit_exists(forge, sha, "octocat", "Hello-World")
+                    .await?
+            );
+            db.insert_commit(commit(forge, sha)).await?;
+            let mut readme = readme(forge, "octocat", "Hello-World");
+            readme.content = format!("# Hello from {}", forge.as_str());
+            db.cache_readme(&readme).await?;
+        }
+        assert!(
+            db.commit_exists(ForgeKind::GitLab, sha, "octocat", "Hello-World")
+                .await?
+        );
+        assert!(
+            !db.commit_exists(ForgeKind::Gitea, sha, "octocat", "Hello-World")
+                .await?
+        );
+        assert_eq!(db.get_commits(&CommitFilter::default()).await?.len(), 2);
+        let content = db.get_commit_content(sha).await?.unwrap();
+        assert_eq!(
+            content.repositories,
+            ["octocat/Hello-World", "gitlab:octocat/Hello-World"]
+        );
+
+        let report =
+            delete_repository(&db, ForgeKind::GitLab, "octocat", "Hello-World", "test").await?;
+        assert_eq!(report.commits_deleted, 1);
+        assert_eq!(report.readmes_deleted, 1);
+        // The GitHub copy keeps the shared content alive
+        assert_eq!(report.contents_deleted, 0);
+
+        let left = db.get_commits(&CommitFilter::default()).await?;
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].forge, ForgeKind::GitHub);
+        let readme = db
+            .get_cached_readme(ForgeKind::GitHub, "octocat", "Hello-World")
+            .await?
+            .unwrap();
+        assert_eq!(readme.content, "# Hello from github");
+        assert!(db
+            .get_cached_readme(ForgeKind::GitLab, "octocat", "Hello-World")
+            .await?
+            .is_none());
+
+        db.drop_for_tests().await
+    }
+}
@@ -0,0 +1,526 @@
+//! Tracing, logging and metrics setup, the metrics shared by the API, and their
+//! Prometheus exposition.
+//!
+//! Instruments are recorded once through the OpenTelemetry meter provider, which
+//! keeps them for [`PrometheusReader`] to render when `/metrics` is scraped and, when
+//! enabled, also pushes them over OTLP.
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use indicatif::{MultiProgress, ProgressBar};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram},
+    trace::TracerProvider,
+    KeyValue,
+};
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::{LogExporter, MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    logs::SdkLoggerProvider,
+    metrics::{
+        data::{self, ResourceMetrics},
+        reader::MetricReader,
+        InstrumentKind, ManualReader, MetricResult, Pipeline, SdkMeterProvider, Temporality,
+    },
+    trace::{Sampler, SdkTracerProvider},
+    Resource,
+};
+use std::{
+    fmt::Write,
+    io,
+    sync::{Arc, LazyLock, OnceLock, Weak},
+};
+use tracing::warn;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{filter::filter_fn, fmt, prelude::*, EnvFilter, Registry};
+
+use crate::config::{LogFormat, OtlpProtocol, TelemetryConfig};
+
+/// Crates whose events aren't exported as logs, since exporting them would log
+/// the exporter's own requests
+const LOG_EXPORT_EXCLUDED: &[&str] = &["opentelemetry", "hyper", "h2", "tonic", "reqwest"];
+
+/// Draws `bar` on stderr, below the log lines written while it's shown
+pub fn progress_bar(bar: ProgressBar) -> ProgressBar {
+    PROGRESS.add(bar)
+}
+
+/// Writes log lines to stderr around the progress bars. The fmt layer writes each
+/// event at once, so a line is never split by a redraw.
+struct LogWriter;
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        PROGRESS.suspend(|| io::stderr().write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// The reader `/metrics` renders, registered on the global meter provider
+static PROMETHEUS: OnceLock<PrometheusReader> = OnceLock::new();
+
+/// Progress bars on stderr, hidden while a log line is written so the two don't
+/// garble each other
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// Request latency buckets, in seconds; ingestion requests can take minutes
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// The providers set up by [`Telemetry::init`], flushed by [`Telemetry::shutdown`]
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: SdkMeterProvider,
+    logger_provider: Option<SdkLoggerProvider>,
+}
+
+impl Telemetry {
+    /// Installs the global subscriber and providers. Exporters connect lazily, so
+    /// this succeeds whether or not the collector is up.
+    pub fn init(config: &TelemetryConfig) -> Result<Self> {
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone())
+            .with_attribute(KeyValue::new(
+                "service.version",
+                config.service_version.clone(),
+            ))
+            .build();
+
+        let tracer_provider = match config.traces {
+            true => Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(span_exporter(config)?)
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        config.sampling_ratio,
+                    ))))
+                    .with_resource(resource.clone())
+                    .build(),
+            ),
+            false => None,
+        };
+        let logger_provider = match config.logs {
+            true => Some(
+                SdkLoggerProvider::builder()
+                    .with_batch_exporter(log_exporter(config)?)
+                    .with_resource(resource.clone())
+                    .build(),
+            ),
+            false => None,
+        };
+        // Always built, so `/metrics` works without a collector
+        let mut meter_provider = SdkMeterProvider::builder()
+            .with_reader(PrometheusReader::global())
+            .with_resource(resource);
+        if config.metrics {
+            meter_provider = meter_provider.with_periodic_exporter(metric_exporter(config)?);
+        }
+        let meter_provider = meter_provider.build();
+
+        let filter = EnvFilter::try_new(&config.log_filter)
+            .wrap_err_with(|| format!("Invalid log filter '{}'", config.log_filter))?;
+        // Logs go to stderr, leaving stdout to command output such as summaries
+        let fmt_layer = fmt::layer().with_writer(|| LogWriter);
+        let fmt_layer = match config.log_format {
+            LogFormat::Pretty => fmt_layer.pretty().boxed(),
+            LogFormat::Compact => fmt_layer.compact().boxed(),
+            LogFormat::Json => fmt_layer.json().boxed(),
+        };
+        let trace_layer = tracer_provider
+            .as_ref()
+            .map(|provider| OpenTelemetryLayer::new(provider.tracer(config.service_name.clone())));
+        let log_layer = logger_provider.as_ref().map(|provider| {
+            OpenTelemetryTracingBridge::new(provider).with_filter(filter_fn(|metadata| {
+                !LOG_EXPORT_EXCLUDED
+                    .iter()
+                    .any(|excluded| metadata.target().starts_with(excluded))
+            }))
+        });
+        let subscriber = Registry::default()
+            .with(filter)
+            .with(fmt_layer)
+            .with(trace_layer)
+            .with(log_layer);
+        tracing::subscriber::set_global_default(subscriber)
+            .wrap_err("Failed to set the global tracing subscriber")?;
+
+        if let Some(provider) = &tracer_provider {
+            global::set_tracer_provider(provider.clone());
+        }
+        global::set_meter_provider(meter_provider.clone());
+
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+            logger_provider,
+        })
+    }
+
+    /// Flushes whatever hasn't been exported yet. Failures are only logged, since an
+    /// unreachable collector shouldn't fail the command that just ran.
+    pub fn shutdown(self) {
+        if let Some(provider) = self.tracer_provider {
+            if let Err(e) = provider.shutdown() {
+                warn!("Failed to flush traces: {e}");
+            }
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            warn!("Failed to flush metrics: {e}");
+        }
+        if let Some(provider) = self.logger_provider {
+            if let Err(e) = provider.shutdown() {
+                warn!("Failed to flush logs: {e}");
+            }
+        }
+    }
+}
+
+/// Where `signal` is sent: the gRPC endpoint is shared, while each HTTP signal has
+/// its own path
+fn signal_endpoint(config: &TelemetryConfig, signal: &str) -> String {
+    match config.otlp_protocol {
+        OtlpProtocol::Grpc => config.otlp_endpoint.clone(),
+        OtlpProtocol::HttpProtobuf | OtlpProtocol::HttpJson => {
+            format!("{}/v1/{signal}", config.otlp_endpoint)
+        }
+    }
+}
+
+fn http_protocol(protocol: OtlpProtocol) -> Protocol {
+    match protocol {
+        OtlpProtocol::HttpJson => Protocol::HttpJson,
+        OtlpProtocol::HttpProtobuf | OtlpProtocol::Grpc => Protocol::HttpBinary,
+    }
+}
+
+fn span_exporter(config: &TelemetryConfig) -> Result<SpanExporter> {
+    let endpoint = signal_endpoint(config, "traces");
+    let exporter = match config.otlp_protocol {
+        OtlpProtocol::Grpc => SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build(),
+        protocol => SpanExporter::builder()
+            .with_http()
+            .with_protocol(http_protocol(protocol))
+            .with_endpoint(endpoint)
+            .build(),
+    };
+    exporter.wrap_err("Failed to create the OTLP span exporter")
+}
+
+fn metric_exporter(config: &TelemetryConfig) -> Result<MetricExporter> {
+    let endpoint = signal_endpoint(config, "metrics");
+    let exporter = match config.otlp_protocol {
+        OtlpProtocol::Grpc => MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build(),
+        protocol => MetricExporter::builder()
+            .with_http()
+            .with_protocol(http_protocol(protocol))
+            .with_endpoint(endpoint)
+            .build(),
+    };
+    exporter.wrap_err("Failed to create the OTLP metric exporter")
+}
+
+fn log_exporter(config: &TelemetryConfig) -> Result<LogExporter> {
+    let endpoint = signal_endpoint(config, "logs");
+    let exporter = match config.otlp_protocol {
+        OtlpProtocol::Grpc => LogExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build(),
+        protocol => LogExporter::builder()
+            .with_http()
+            .with_protocol(http_protocol(protocol))
+            .with_endpoint(endpoint)
+            .build(),
+    };
+    exporter.wrap_err("Failed to create the OTLP log exporter")
+}
+
+/// Instruments recorded by the API and ingestion
+pub struct Metrics {
+    pub request_duration: Histogram<f64>,
+    pub commits_processed: Counter<u64>,
+    /// Embedding lookups, by whether the cache had them; the hit ratio is
+    /// `result="hit"` over all lookups
+    pub embedding_cache: Counter<u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let meter = global::meter("github-research-rs");
+        Self {
+            request_duration: meter
+                .f64_histogram("http.server.request.duration")
+                .with_unit("s")
+                .with_description("Time taken to answer API requests")
+                .with_boundaries(LATENCY_BUCKETS.to_vec())
+                .build(),
+            commits_processed: meter
+                .u64_counter("commits.processed")
+                .with_description("Commits summarized, embedded and stored")
+                .build(),
+            embedding_cache: meter
+                .u64_counter("embedding.cache.lookups")
+                .with_description("Embedding cache lookups, by result (hit or miss)")
+                .build(),
+        }
+    }
+}
+
+/// Keeps the meter provider's measurements for Prometheus to pull, alongside the
+/// periodic OTLP export
+#[derive(Debug, Clone)]
+pub struct PrometheusReader {
+    reader: Arc<ManualReader>,
+}
+
+impl PrometheusReader {
+    /// The reader `/metrics` renders; register it on the meter provider with
+    /// `with_reader`
+    pub fn global() -> Self {
+        PROMETHEUS
+            .get_or_init(|| Self {
+                reader: Arc::new(ManualReader::builder().build()),
+            })
+            .clone()
+    }
+
+    /// Renders the global reader's metrics, which are empty until it's registered
+    pub fn render_global() -> Result<String> {
+        match PROMETHEUS.get() {
+            Some(reader) => reader.render(),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Current metrics in the Prometheus text exposition format
+    pub fn render(&self) -> Result<String> {
+        let mut metrics = ResourceMetrics {
+            resource: Resource::builder_empty().build(),
+            scope_metrics: Vec::new(),
+        };
+        self.reader
+            .collect(&mut metrics)
+            .map_err(|e| eyre!("Failed to collect metrics: {e}"))?;
+
+        let mut out = String::new();
+        for metric in metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| &scope.metrics)
+        {
+            write_metric(&mut out, metric);
+        }
+        Ok(out)
+    }
+}
+
+impl MetricReader for PrometheusReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.reader.register_pipeline(pipeline);
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        self.reader.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.reader.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.reader.shutdown()
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.reader.temporality(kind)
+    }
+}
+
+fn write_metric(out: &mut String, metric: &data::Metric) {
+    let mut name = sanitize(&metric.name);
+    if metric.unit == "s" && !name.ends_with("_seconds") {
+        name.push_str("_seconds");
+    }
+    let data = metric.data.as_any();
+
+    macro_rules! sums {
+        ($($t:ty),*) => {$(
+            if let Some(sum) = data.downcast_ref::<data::Sum<$t>>() {
+                let (kind, name) = match sum.is_monotonic {
+                    true => ("counter", format!("{name}_total")),
+                    false => ("gauge", name),
+                };
+                header(out, &name, &metric.description, kind);
+                for point in &sum.data_points {
+                    sample(out, &name, &point.attributes, None, point.value);
+                }
+                return;
+            }
+        )*};
+    }
+    macro_rules! gauges {
+        ($($t:ty),*) => {$(
+            if let Some(gauge) = data.downcast_ref::<data::Gauge<$t>>() {
+                header(out, &name, &metric.description, "gauge");
+                for point in &gauge.data_points {
+                    sample(out, &name, &point.attributes, None, point.value);
+                }
+                return;
+            }
+        )*};
+    }
+    macro_rules! histograms {
+        ($($t:ty),*) => {$(
+            if let Some(histogram) = data.downcast_ref::<data::Histogram<$t>>() {
+                header(out, &name, &metric.description, "histogram");
+                for point in &histogram.data_points {
+                    let bucket = format!("{name}_bucket");
+                    let mut cumulative = 0;
+                    for (index, count) in point.bucket_counts.iter().enumerate() {
+                        cumulative += count;
+                        let le = match point.bounds.get(index) {
+                            Some(bound) => bound.to_string(),
+                            None => "+Inf".to_string(),
+                        };
+                        sample(out, &bucket, &point.attributes, Some(&le), cumulative);
+                    }
+                    sample(out, &format!("{name}_sum"), &point.attributes, None, point.sum);
+                    sample(out, &format!("{name}_count"), &point.attributes, None, point.count);
+                }
+                return;
+            }
+        )*};
+    }
+    sums!(u64, i64, f64);
+    gauges!(u64, i64, f64);
+    histograms!(u64, f64);
+}
+
+fn header(out: &mut String, name: &str, description: &str, kind: &str) {
+    if !description.is_empty() {
+        let _ = writeln!(out, "# HELP {name} {}", description.replace('\n', " "));
+    }
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(
+    out: &mut String,
+    name: &str,
+    attributes: &[KeyValue],
+    le: Option<&str>,
+    value: impl std::fmt::Display,
+) {
+    let mut labels: Vec<String> = attributes
+        .iter()
+        .map(|attribute| {
+            let key = sanitize(attribute.key.as_str());
+            format!("{key}=\"{}\"", escape(&attribute.value.to_string()))
+        })
+        .collect();
+    if let Some(le) = le {
+        labels.push(format!("le=\"{le}\""));
+    }
+    match labels.is_empty() {
+        true => {
+            let _ = writeln!(out, "{name} {value}");
+        }
+        false => {
+            let _ = writeln!(out, "{name}{{{}}} {value}", labels.join(","));
+        }
+    }
+}
+
+/// Replaces characters Prometheus doesn't allow in names, such as dots
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    #[test]
+    fn test_prometheus_exposition() {
+        let reader = PrometheusReader {
+            reader: Arc::new(ManualReader::builder().build()),
+        };
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        let meter = provider.meter("test");
+
+        let counter = meter.u64_counter("commits.processed").build();
+        counter.add(2, &[KeyValue::new("forge", "github")]);
+        counter.add(1, &[KeyValue::new("forge", "git\"lab")]);
+        meter
+            .u64_gauge("github.token.remaining")
+            .with_description("Budget left")
+            .build()
+            .record(4999, &[]);
+        let histogram = meter
+            .f64_histogram("http.server.request.duration")
+            .with_unit("s")
+            .with_boundaries(vec![0.1, 1.0])
+            .build();
+        histogram.record(0.05, &[]);
+        histogram.record(0.5, &[]);
+
+        let text = reader.render().unwrap();
+        for expected in [
+            "# TYPE commits_processed_total counter",
+            r#"commits_processed_total{forge="github"} 2"#,
+            r#"commits_processed_total{forge="git\"lab"} 1"#,
+            "# HELP github_token_remaining Budget left",
+            "github_token_remaining 4999",
+            "# TYPE http_server_request_duration_seconds histogram",
+            r#"http_server_request_duration_seconds_bucket{le="0.1"} 1"#,
+            r#"http_server_request_duration_seconds_bucket{le="1"} 2"#,
+            r#"http_server_request_duration_seconds_bucket{le="+Inf"} 2"#,
+            "http_server_request_duration_seconds_count 2",
+        ] {
+            assert!(text.contains(expected), "{expected} missing from\n{text}");
+        }
+    }
+
+    #[test]
+    fn test_log_writer_around_progress_bar() -> Result<()> {
+        use std::io::Write as _;
+
+        let bar = progress_bar(ProgressBar::hidden());
+        bar.set_length(2);
+        bar.inc(1);
+        let line = b"INFO a log line while the bar is shown\n";
+        assert_eq!(LogWriter.write(line)?, line.len());
+        LogWriter.flush()?;
+        assert_eq!(bar.position(), 1);
+        bar.finish_and_clear();
+        Ok(())
+    }
+}